@@ -0,0 +1,368 @@
+use crate::dns_message::{CombinedClass, CombinedType, DnsMessage, DnsQuestion};
+use crate::random;
+use crate::resolver::{ResolverConfig, ResolverError, Result};
+use crate::udp_packet::{self, DomainName, UdpPacket, UdpPacketError};
+use std::collections::HashMap;
+use std::net;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+const MAX_ID_ATTEMPTS: usize = 64;  // Random ids tried for a query before giving up, when most are in use.
+
+/// A query sent by an AsyncUdpTransport which is waiting for its response.
+struct PendingQuery {
+    server: net::SocketAddr,
+    query: DnsMessage,          // The query as sent, i.e. with its random id and name case
+    randomise_case: bool,
+    sender: Option<oneshot::Sender<DnsMessage>>     // Taken once the response has arrived
+}
+
+/// The queries waiting for their responses by id, which are None once the task receiving
+/// responses has stopped.
+type PendingQueries = Arc<Mutex<Option<HashMap<u16, PendingQuery>>>>;
+
+fn io_error(description: &str) -> impl FnOnce(std::io::Error) -> UdpPacketError {
+    let description = String::from(description);
+    move |error| UdpPacketError::NetworkIo { description, source: error }
+}
+
+/// Removes a query from the pending queries once its exchange completes, times out or is
+/// cancelled by dropping the future, which frees its id for later queries.
+struct PendingGuard<'a> {
+    pending: &'a PendingQueries,
+    id: u16
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+/// An asynchronous UDP transport which multiplexes any number of outstanding queries over a
+/// single socket, telling their responses apart by id. Responses are read by a background task,
+/// which is stopped when the transport is dropped.
+pub struct AsyncUdpTransport {
+    socket: Arc<tokio::net::UdpSocket>,
+    pending: PendingQueries,
+    receiver: tokio::task::JoinHandle<()>
+}
+
+impl Drop for AsyncUdpTransport {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl AsyncUdpTransport {
+    /// Binds a socket to a random port, for talking to servers of the same address family as
+    /// `server`. Must be called from within a tokio runtime.
+    pub fn bind(server: net::SocketAddr) -> udp_packet::Result<Self> {
+        let socket = random::bind_random_port(server).map_err(io_error("failed to bind a UdpSocket"))?;
+        socket.set_nonblocking(true).map_err(io_error("failed to make the UdpSocket non-blocking"))?;
+        let socket = Arc::new(
+            tokio::net::UdpSocket::from_std(socket).map_err(io_error("failed to register the UdpSocket"))?
+        );
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let receiver = tokio::spawn(receive(Arc::clone(&socket), Arc::clone(&pending)));
+        Ok(Self { socket, pending, receiver })
+    }
+
+    pub fn local_addr(&self) -> udp_packet::Result<net::SocketAddr> {
+        self.socket.local_addr().map_err(io_error("failed to read the address of the UdpSocket"))
+    }
+
+    /// The asynchronous counterpart of DnsMessage::exchange, or of
+    /// DnsMessage::exchange_with_case_randomisation if `randomise_case` is set. Dropping the
+    /// returned future cancels the query.
+    pub async fn exchange(
+        &self,
+        query: &DnsMessage,
+        server: net::SocketAddr,
+        timeout: Duration,
+        randomise_case: bool
+    ) -> udp_packet::Result<DnsMessage> {
+        let mut sent = query.clone();
+        if randomise_case {
+            for question in sent.questions.iter_mut() {
+                question.name = question.name.randomise_case();
+            }
+        }
+        let (sender, receiver) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            let Some(pending) = pending.as_mut() else {
+                return Err(stopped_error());
+            };
+            // The lock is held while looking, so the number of attempts is bounded even if every id
+            // is in use.
+            let id = (0..MAX_ID_ATTEMPTS).map(|_| random::random_u16())
+            .find(|id| !pending.contains_key(id))
+            .ok_or_else(|| UdpPacketError::NetworkIo {
+                description: String::from("no free query id, too many queries are in flight"),
+                source: std::io::Error::from(std::io::ErrorKind::WouldBlock)
+            })?;
+            sent.header.id = id;
+            pending.insert(id, PendingQuery { server, query: sent.clone(), randomise_case, sender: Some(sender) });
+            id
+        };
+        let _guard = PendingGuard { pending: &self.pending, id };
+
+        let mut udp_packet = UdpPacket::new();
+        sent.write_to_udp_packet(&mut udp_packet)?;
+        self.socket.send_to(&udp_packet.buffer[..udp_packet.position], server).await
+        .map_err(io_error("failed to send a UdpPacket"))?;
+        log::trace!("sent {} bytes over UDP to {}:\n{}", udp_packet.position, server, udp_packet::hex_dump(&udp_packet.buffer[..udp_packet.position]));
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(mut response)) => {
+                response.header.id = query.header.id;
+                if randomise_case {
+                    response.questions = query.questions.clone();
+                }
+                Ok(response)
+            },
+            Ok(Err(_)) => Err(stopped_error()),
+            Err(_) => Err(UdpPacketError::NetworkIo {
+                description: String::from("timed out while waiting for a response"),
+                source: std::io::Error::from(std::io::ErrorKind::TimedOut)
+            })
+        }
+    }
+}
+
+fn stopped_error() -> UdpPacketError {
+    UdpPacketError::NetworkIo {
+        description: String::from("the task receiving responses has stopped"),
+        source: std::io::Error::from(std::io::ErrorKind::BrokenPipe)
+    }
+}
+
+/// Reads responses from `socket` and hands them to the pending queries they belong to. Packets which
+/// do not come from the server a query was sent to, or are not a response to it, are dropped.
+///
+/// The task stops at the first error of the socket other than those caused by ICMP errors for
+/// earlier datagrams, since retrying would spin on a broken socket. The pending queries, and any
+/// later ones, then fail at once.
+async fn receive(socket: Arc<tokio::net::UdpSocket>, pending: PendingQueries) {
+    loop {
+        let mut udp_packet = UdpPacket::new();
+        let source = match socket.recv_from(&mut udp_packet.buffer).await {
+            Ok((length, source)) => {
+                log::trace!("received {} bytes over UDP from {}:\n{}", length, source, udp_packet::hex_dump(&udp_packet.buffer[..length]));
+                source
+            },
+            // Some platforms report ICMP port unreachable messages here, e.g. Windows as ConnectionReset.
+            Err(error) if matches!(error.kind(), std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::Interrupted) => {
+                log::debug!("ignoring an error of the UDP socket: {}", error);
+                continue;
+            },
+            Err(error) => {
+                log::error!("stopped receiving responses over UDP: {}", error);
+                // Dropping the senders makes the pending queries fail rather than time out.
+                pending.lock().unwrap().take();
+                return;
+            }
+        };
+        let response = match DnsMessage::read_from_udp_packet(&mut udp_packet) {
+            Ok(response) => response,
+            Err(error) => {
+                log::debug!("ignoring a malformed packet from {}: {}", source, error);
+                continue;
+            }
+        };
+        let mut pending = pending.lock().unwrap();
+        if let Some(query) = pending.as_mut().and_then(|pending| pending.get_mut(&response.header.id)) {
+            let valid = query.server == source
+            && response.is_response_to(&query.query)
+            && (!query.randomise_case || response.echoes_question_case(&query.query));
+            if let Some(sender) = query.sender.take_if(|_| valid) {
+                // The receiver is gone if the query has just been cancelled, which is harmless.
+                let _ = sender.send(response);
+            }
+        }
+    }
+}
+
+/// The asynchronous counterpart of resolver::Resolver, using one AsyncUdpTransport per address
+/// family for all of its queries. The hosts file and search list are not consulted.
+pub struct AsyncResolver {
+    config: ResolverConfig,
+    ipv4_transport: Option<AsyncUdpTransport>,
+    ipv6_transport: Option<AsyncUdpTransport>,
+    next_server: AtomicUsize    // The index of the server to start at, used when rotating
+}
+
+impl AsyncResolver {
+    /// Creates a resolver, binding a socket for every address family among the configured name
+    /// servers. Must be called from within a tokio runtime.
+    pub fn new(config: ResolverConfig) -> Result<Self> {
+        let bind = |is_ipv4: bool| -> Result<Option<AsyncUdpTransport>> {
+            match config.servers.iter().find(|server| server.is_ipv4() == is_ipv4) {
+                Some(server) => Ok(Some(AsyncUdpTransport::bind(*server)?)),
+                None => Ok(None)
+            }
+        };
+        let ipv4_transport = bind(true)?;
+        let ipv6_transport = bind(false)?;
+        Ok(Self { config, ipv4_transport, ipv6_transport, next_server: AtomicUsize::new(0) })
+    }
+
+    pub fn config(&self) -> &ResolverConfig {
+        &self.config
+    }
+
+    /// Asks the configured name servers for the records of the given name, type and class.
+    pub async fn lookup(&self, name: &DomainName, question_type: CombinedType, question_class: CombinedClass) -> Result<DnsMessage> {
+        let query = DnsMessage {
+            questions: vec![DnsQuestion {
+                name: name.clone(),
+                question_type,
+                question_class
+            }],
+            ..Default::default()
+        };
+        self.query(&query).await
+    }
+
+    /// Sends `query` to the configured name servers, like resolver::Resolver::query.
+    pub async fn query(&self, query: &DnsMessage) -> Result<DnsMessage> {
+        let servers = &self.config.servers;
+        if servers.is_empty() {
+            return Err(ResolverError::NoServers);
+        }
+        let first_server = match self.config.rotate {
+            true => self.next_server.fetch_add(1, Ordering::Relaxed) % servers.len(),
+            false => 0
+        };
+        let mut last_error = None;
+        for _ in 0..self.config.attempts {
+            for index in 0..servers.len() {
+                let server = servers[(first_server + index) % servers.len()];
+                let transport = match server.is_ipv4() {
+                    true => self.ipv4_transport.as_ref(),
+                    false => self.ipv6_transport.as_ref()
+                };
+                // There is a transport for the family of every configured name server.
+                let transport = transport.unwrap();
+                match transport.exchange(query, server, self.config.timeout, self.config.case_randomisation).await {
+                    Ok(response) => return Ok(response),
+                    Err(error) if error.is_timeout() => (),
+                    Err(error) => last_error = Some(error)
+                }
+            }
+        }
+        match last_error {
+            Some(error) => Err(error.into()),
+            None => Err(ResolverError::Timeout {
+                servers: servers.to_vec(),
+                attempts: self.config.attempts,
+                timeout: self.config.timeout
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::async_resolver::*;
+    use crate::dns_message::*;
+    use std::str::FromStr;
+
+    const NUM_QUERIES: u8 = 16;
+
+    /// Starts a server which waits for NUM_QUERIES queries before answering all of them in reverse
+    /// order, with the address 192.0.2.<n> for the name host<n>.example.com.
+    fn start_reversing_server() -> net::SocketAddr {
+        let socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut queries = Vec::new();
+            for _ in 0..NUM_QUERIES {
+                let mut udp_packet = UdpPacket::new();
+                let (_, client) = udp_packet.recv_from(&socket).unwrap();
+                queries.push((DnsMessage::read_from_udp_packet(&mut udp_packet).unwrap(), client));
+            }
+            for (query, client) in queries.into_iter().rev() {
+                let name = query.questions[0].name.clone();
+                let host = name.labels()[0][4..].iter().fold(0, |host, digit| host * 10 + (digit - b'0'));
+                let mut response = query.reply(ResponseCode::NOERROR);
+                response.answers.push(DnsRecord {
+                    name,
+                    record_type: RecordType::A,
+                    record_class: RecordClass::IN,
+                    ttl: 300,
+                    length: 4,
+                    data: RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, host) }
+                });
+                response.update_counts();
+                let mut udp_packet = UdpPacket::new();
+                response.write_to_udp_packet(&mut udp_packet).unwrap();
+                udp_packet.send_to(&socket, client).unwrap();
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn multiplexing_test() {
+        let resolver = Arc::new(AsyncResolver::new(ResolverConfig {
+            servers: vec![start_reversing_server()],
+            case_randomisation: true,
+            ..Default::default()
+        }).unwrap());
+        let tasks: Vec<_> = (0..NUM_QUERIES).map(|host| {
+            let resolver = Arc::clone(&resolver);
+            tokio::spawn(async move {
+                let name = DomainName::from_str(&format!("host{}.example.com", host)).unwrap();
+                let response = resolver.lookup(&name, CombinedType::default(), CombinedClass::default()).await;
+                (host, response.expect("Failed to resolve."))
+            })
+        }).collect();
+        for task in tasks {
+            let (host, response) = task.await.unwrap();
+            assert_eq!(response.answers[0].data, RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, host) });
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_and_cancellation_test() {
+        let silent = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let resolver = AsyncResolver::new(ResolverConfig {
+            servers: vec![silent.local_addr().unwrap()],
+            timeout: Duration::from_millis(50),
+            attempts: 1,
+            ..Default::default()
+        }).unwrap();
+        let name = DomainName::from_str(TEST_DOMAIN).unwrap();
+        let result = resolver.lookup(&name, CombinedType::default(), CombinedClass::default()).await;
+        assert!(matches!(result, Err(ResolverError::Timeout { .. })));
+
+        let transport = resolver.ipv4_transport.as_ref().unwrap();
+        let query = DnsMessage::default();
+        let exchange = transport.exchange(&query, silent.local_addr().unwrap(), Duration::from_secs(60), false);
+        assert!(tokio::time::timeout(Duration::from_millis(50), exchange).await.is_err());
+        assert!(transport.pending.lock().unwrap().as_ref().unwrap().is_empty());
+
+        // A query fails rather than waiting for an id when every id is in use.
+        transport.pending.lock().unwrap().as_mut().unwrap().extend((0..=u16::MAX).map(|id| (id, PendingQuery {
+            server: silent.local_addr().unwrap(),
+            query: query.clone(),
+            randomise_case: false,
+            sender: None
+        })));
+        let exchange = transport.exchange(&query, silent.local_addr().unwrap(), Duration::from_secs(60), false);
+        let result = tokio::time::timeout(Duration::from_millis(50), exchange).await.expect("The query should fail at once.");
+        assert!(result.unwrap_err().to_string().contains("no free query id"));
+
+        // Once the task receiving responses has stopped, which it does on socket errors, queries
+        // fail at once instead of timing out.
+        transport.pending.lock().unwrap().take();
+        let exchange = transport.exchange(&query, silent.local_addr().unwrap(), Duration::from_secs(60), false);
+        let result = tokio::time::timeout(Duration::from_millis(50), exchange).await.expect("The query should fail at once.");
+        assert!(result.unwrap_err().to_string().contains("has stopped"));
+    }
+}
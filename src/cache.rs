@@ -0,0 +1,502 @@
+use crate::dns_message::{
+    CombinedClass, CombinedType, DnsMessage, DnsQuestion, DnsRecord, RecordClass, RecordData, RecordType, ResponseCode
+};
+use crate::udp_packet::DomainName;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_CAPACITY: usize = 10000;     // The default maximum number of RRsets held by a Cache.
+const DEFAULT_MAX_TTL: u32 = 86400;         // One day, i.e. the default upper bound on cached TTLs.
+const DEFAULT_MAX_NEGATIVE_TTL: u32 = 10800; // Three hours, as recommended by RFC 2308.
+const MAX_CNAME_CHAIN_LENGTH: usize = 8;    // The maximum number of CNAME:s followed by Cache::lookup.
+
+/// A source of the current time, which allows the expiry of cached records to be tested
+/// without actually waiting for their TTL:s to run out.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The clock used by default, i.e. the monotonic system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when told to, intended for tests.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<Instant>
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self { now: Mutex::new(Instant::now()) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+impl<C: Clock> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        self.as_ref().now()
+    }
+}
+
+/// Limits applied by a Cache to the entries it holds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheConfig {
+    pub capacity: usize,        // The maximum number of entries, the least recently used one is evicted beyond it
+    pub min_ttl: u32,           // Entries with a lower TTL are cached for min_ttl seconds
+    pub max_ttl: u32,           // RRsets with a higher TTL are cached for max_ttl seconds
+    pub max_negative_ttl: u32   // Negative answers with a higher TTL are cached for max_negative_ttl seconds
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CAPACITY,
+            min_ttl: 0,
+            max_ttl: DEFAULT_MAX_TTL,
+            max_negative_ttl: DEFAULT_MAX_NEGATIVE_TTL
+        }
+    }
+}
+
+/// Entries are identified by their owner name, type and class. The type is None for NXDOMAIN
+/// entries, since these apply to every type of the name.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: DomainName,
+    record_type: Option<RecordType>,
+    record_class: RecordClass
+}
+
+impl CacheKey {
+    fn new(name: &DomainName, record_type: Option<RecordType>, record_class: RecordClass) -> Self {
+        Self { name: name.clone(), record_type, record_class }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum CacheData {
+    Records(Vec<DnsRecord>),
+    Negative {
+        response_code: ResponseCode,    // NAMEERROR for NXDOMAIN and NOERROR for NODATA
+        soa: DnsRecord                  // The SOA record proving the non-existence
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    data: CacheData,
+    expires: Instant,
+    last_used: u64      // The value of Cache.tick when the entry was last inserted or read
+}
+
+/// A cached answer to a question, including any CNAME:s which were followed to reach it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheAnswer {
+    pub response_code: ResponseCode,    // NAMEERROR if the (final) name does not exist
+    pub answers: Vec<DnsRecord>,        // The CNAME chain followed by the answering RRset, if any
+    pub authorities: Vec<DnsRecord>     // The SOA record of a negative answer
+}
+
+/// A cache of RRsets and negative answers (RFC 2308) which expire according to their TTL:s,
+/// bounded in size by evicting the least recently used entry.
+#[derive(Debug)]
+pub struct Cache<C = SystemClock> {
+    config: CacheConfig,
+    clock: C,
+    entries: HashMap<CacheKey, CacheEntry>,
+    recency: BTreeMap<u64, CacheKey>,   // Maps CacheEntry.last_used to keys, i.e. orders the entries by recency
+    tick: u64
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new(CacheConfig::default())
+    }
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self::with_clock(config, SystemClock)
+    }
+}
+
+impl<C: Clock> Cache<C> {
+    pub fn with_clock(config: CacheConfig, clock: C) -> Self {
+        Self {
+            config,
+            clock,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    /// Caches `records`, grouped into RRsets which replace any previously cached RRset with the
+    /// same name, type and class. The TTL of an RRset is the lowest TTL among its records, clamped
+    /// to the bounds of the CacheConfig.
+    pub fn insert(&mut self, records: &[DnsRecord]) {
+        let mut rrsets: Vec<(CacheKey, Vec<DnsRecord>)> = Vec::new();
+        for record in records {
+            let key = CacheKey::new(&record.name, Some(record.record_type), record.record_class);
+            match rrsets.iter_mut().find(|(rrset_key, _)| *rrset_key == key) {
+                Some((_, rrset)) => rrset.push(record.clone()),
+                None => rrsets.push((key, vec![record.clone()]))
+            }
+        }
+        for (key, rrset) in rrsets {
+            let ttl = rrset.iter().map(|record| record.ttl).min().unwrap_or_default();
+            let ttl = ttl.clamp(self.config.min_ttl, self.config.max_ttl.max(self.config.min_ttl));
+            self.insert_entry(key, CacheData::Records(rrset), ttl);
+        }
+    }
+
+    /// Caches the answer section of a NOERROR response, as well as the negative answer given by
+    /// NXDOMAIN and NODATA responses whose authority section contains an SOA record. The negative
+    /// TTL is the lower of the SOA record's TTL and its MINIMUM field (RFC 2308, section 5).
+    /// Truncated responses and responses with other response codes are ignored.
+    pub fn insert_message(&mut self, message: &DnsMessage) {
+        let response_code = message.header.response_code;
+        if message.header.truncated || !matches!(response_code, ResponseCode::NOERROR | ResponseCode::NAMEERROR) {
+            return;
+        }
+        self.insert(&message.answers);
+
+        let (name, record_type, record_class) = match message.questions.as_slice() {
+            [DnsQuestion {
+                name,
+                question_type: CombinedType::RecordType(record_type),
+                question_class: CombinedClass::RecordClass(record_class)
+            }] => (name, *record_type, *record_class),
+            _ => return
+        };
+        // The name which does not exist, or lacks records, is the end of the answer's CNAME chain.
+        let mut name = name.clone();
+        for _ in 0..MAX_CNAME_CHAIN_LENGTH {
+            let canonical_name = message.answers.iter().find_map(|record| match &record.data {
+                RecordData::CNAME { canonical_name } if record.name == name => Some(canonical_name),
+                _ => None
+            });
+            match canonical_name {
+                Some(canonical_name) if record_type != RecordType::CNAME => name = canonical_name.clone(),
+                _ => break
+            }
+        }
+        let answered = message.answers.iter()
+        .any(|record| record.record_type == record_type && record.name == name);
+        if response_code == ResponseCode::NOERROR && answered {
+            return;
+        }
+        let (soa, minimum) = match message.authorities.iter().find_map(|record| match record.data {
+            RecordData::SOA { minimum, .. } => Some((record, minimum)),
+            _ => None
+        }) {
+            Some(soa) => soa,
+            None => return
+        };
+        let ttl = soa.ttl.min(minimum)
+        .clamp(self.config.min_ttl, self.config.max_negative_ttl.max(self.config.min_ttl));
+        let key = match response_code {
+            ResponseCode::NAMEERROR => CacheKey::new(&name, None, record_class),
+            _ => CacheKey::new(&name, Some(record_type), record_class)
+        };
+        self.insert_entry(key, CacheData::Negative { response_code, soa: soa.clone() }, ttl);
+    }
+
+    fn insert_entry(&mut self, key: CacheKey, data: CacheData, ttl: u32) {
+        if ttl == 0 || self.config.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        while self.entries.len() >= self.config.capacity {
+            self.evict_least_recently_used();
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, CacheEntry {
+            data,
+            expires: self.clock.now() + Duration::from_secs(ttl as u64),
+            last_used: self.tick
+        });
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        if let Some((_, key)) = self.recency.pop_first() {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Returns the data of an unexpired entry, with the TTL of each record set to the number of
+    /// seconds left until the entry expires.
+    fn get_entry(&mut self, key: CacheKey) -> Option<CacheData> {
+        let now = self.clock.now();
+        let entry = self.entries.get_mut(&key)?;
+        if entry.expires <= now {
+            self.remove(&key);
+            return None;
+        }
+        self.tick += 1;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(self.tick, key);
+        entry.last_used = self.tick;
+
+        // Rounded up, so that an entry read right after being inserted keeps its original TTL.
+        let remaining = entry.expires.duration_since(now);
+        let remaining = (remaining.as_secs() + (remaining.subsec_nanos() > 0) as u64) as u32;
+        let mut data = entry.data.clone();
+        match &mut data {
+            CacheData::Records(records) => records.iter_mut().for_each(|record| record.ttl = remaining),
+            CacheData::Negative { soa, .. } => soa.ttl = remaining
+        }
+        Some(data)
+    }
+
+    /// Returns the cached RRset with the given name, type and class, with the TTL of each record
+    /// set to the number of seconds left until the RRset expires.
+    pub fn get(&mut self, name: &DomainName, record_type: RecordType, record_class: RecordClass) -> Option<Vec<DnsRecord>> {
+        match self.get_entry(CacheKey::new(name, Some(record_type), record_class))? {
+            CacheData::Records(records) => Some(records),
+            CacheData::Negative { .. } => None
+        }
+    }
+
+    /// Answers a question using the cache, following cached CNAME:s until either an RRset of the
+    /// requested type or a negative answer is found.
+    pub fn lookup(&mut self, name: &DomainName, record_type: RecordType, record_class: RecordClass) -> Option<CacheAnswer> {
+        let mut answers = Vec::new();
+        let mut name = name.clone();
+        for _ in 0..MAX_CNAME_CHAIN_LENGTH {
+            match self.get_entry(CacheKey::new(&name, Some(record_type), record_class)) {
+                Some(CacheData::Records(rrset)) => {
+                    answers.extend(rrset);
+                    return Some(CacheAnswer { response_code: ResponseCode::NOERROR, answers, authorities: Vec::new() });
+                },
+                Some(CacheData::Negative { response_code, soa }) => {
+                    return Some(CacheAnswer { response_code, answers, authorities: vec![soa] });
+                },
+                None => ()
+            }
+            if let Some(cname) = self.get(&name, RecordType::CNAME, record_class) {
+                if let RecordData::CNAME { canonical_name } = &cname[0].data {
+                    name = canonical_name.clone();
+                    answers.extend(cname);
+                    continue;
+                }
+            }
+            return match self.get_entry(CacheKey::new(&name, None, record_class))? {
+                CacheData::Negative { response_code, soa } => Some(CacheAnswer { response_code, answers, authorities: vec![soa] }),
+                CacheData::Records(_) => None
+            };
+        }
+        None
+    }
+
+    /// Synthesises a response to `query` from the cache, if it contains a single question whose
+    /// (positive or negative) answer is cached. The response has the same id as the query.
+    pub fn answer(&mut self, query: &DnsMessage) -> Option<DnsMessage> {
+        let (name, record_type, record_class) = match query.questions.as_slice() {
+            [DnsQuestion {
+                name,
+                question_type: CombinedType::RecordType(record_type),
+                question_class: CombinedClass::RecordClass(record_class)
+            }] => (name, *record_type, *record_class),
+            _ => return None
+        };
+        let answer = self.lookup(name, record_type, record_class)?;
+        let mut response = query.reply(answer.response_code);
+        response.answers = answer.answers;
+        response.authorities = answer.authorities;
+        response.update_counts();
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::*;
+    use crate::dns_message::TEST_DOMAIN;
+    use std::net;
+    use std::str::FromStr;
+
+    fn record(name: &str, ttl: u32, data: RecordData) -> DnsRecord {
+        let record_type = match data {
+            RecordData::CNAME { .. } => RecordType::CNAME,
+            _ => RecordType::A
+        };
+        DnsRecord {
+            name: DomainName::from_str(name).unwrap(),
+            record_type,
+            record_class: RecordClass::IN,
+            ttl,
+            length: 0,
+            data
+        }
+    }
+
+    fn a_record(name: &str, ttl: u32, last_octet: u8) -> DnsRecord {
+        record(name, ttl, RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, last_octet) })
+    }
+
+    fn get(cache: &mut Cache<Arc<ManualClock>>, name: &str) -> Option<Vec<DnsRecord>> {
+        cache.get(&DomainName::from_str(name).unwrap(), RecordType::A, RecordClass::IN)
+    }
+
+    #[test]
+    fn ttl_expiry_test() {
+        let clock = Arc::new(ManualClock::new());
+        let mut cache = Cache::with_clock(CacheConfig::default(), Arc::clone(&clock));
+        cache.insert(&[a_record(TEST_DOMAIN, 300, 1), a_record(TEST_DOMAIN, 100, 2)]);
+        assert_eq!(get(&mut cache, TEST_DOMAIN).unwrap().len(), 2);
+
+        clock.advance(Duration::from_secs(40));
+        let records = get(&mut cache, "EXAMPLE.com").unwrap();
+        assert!(records.iter().all(|record| record.ttl == 60));
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(get(&mut cache, TEST_DOMAIN), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn ttl_clamping_test() {
+        let clock = Arc::new(ManualClock::new());
+        let config = CacheConfig { min_ttl: 60, max_ttl: 3600, ..Default::default() };
+        let mut cache = Cache::with_clock(config, Arc::clone(&clock));
+        cache.insert(&[a_record("low.example.com", 0, 1), a_record("high.example.com", 604800, 2)]);
+        assert_eq!(get(&mut cache, "low.example.com").unwrap()[0].ttl, 60);
+        assert_eq!(get(&mut cache, "high.example.com").unwrap()[0].ttl, 3600);
+    }
+
+    #[test]
+    fn lru_eviction_test() {
+        let clock = Arc::new(ManualClock::new());
+        let config = CacheConfig { capacity: 2, ..Default::default() };
+        let mut cache = Cache::with_clock(config, Arc::clone(&clock));
+        cache.insert(&[a_record("a.example.com", 300, 1)]);
+        cache.insert(&[a_record("b.example.com", 300, 2)]);
+        get(&mut cache, "a.example.com").unwrap();
+        cache.insert(&[a_record("c.example.com", 300, 3)]);
+        assert_eq!(cache.len(), 2);
+        assert!(get(&mut cache, "a.example.com").is_some());
+        assert!(get(&mut cache, "b.example.com").is_none());
+        assert!(get(&mut cache, "c.example.com").is_some());
+    }
+
+    #[test]
+    fn cname_lookup_test() {
+        let mut cache = Cache::default();
+        let canonical_name = DomainName::from_str(TEST_DOMAIN).unwrap();
+        cache.insert(&[
+            record("www.example.com", 300, RecordData::CNAME { canonical_name }),
+            a_record(TEST_DOMAIN, 300, 1)
+        ]);
+        let name = DomainName::from_str("www.example.com").unwrap();
+        let answer = cache.lookup(&name, RecordType::A, RecordClass::IN).unwrap();
+        assert_eq!(answer.response_code, ResponseCode::NOERROR);
+        assert_eq!(answer.answers.len(), 2);
+        assert_eq!(answer.answers[0].record_type, RecordType::CNAME);
+        assert_eq!(answer.answers[1].record_type, RecordType::A);
+    }
+
+    fn negative_response(name: &str, response_code: ResponseCode, soa_ttl: u32, minimum: u32) -> DnsMessage {
+        let mut query = DnsMessage::default();
+        query.questions[0].name = DomainName::from_str(name).unwrap();
+        let mut response = query.reply(response_code);
+        response.authorities.push(DnsRecord {
+            name: DomainName::from_str(TEST_DOMAIN).unwrap(),
+            record_type: RecordType::SOA,
+            record_class: RecordClass::IN,
+            ttl: soa_ttl,
+            length: 0,
+            data: RecordData::SOA {
+                domain_name: DomainName::from_str("ns.example.com").unwrap(),
+                mailbox_address: DomainName::from_str("hostmaster.example.com").unwrap(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum
+            }
+        });
+        response.update_counts();
+        response
+    }
+
+    #[test]
+    fn nxdomain_caching_test() {
+        let clock = Arc::new(ManualClock::new());
+        let mut cache = Cache::with_clock(CacheConfig::default(), Arc::clone(&clock));
+        let response = negative_response("missing.example.com", ResponseCode::NAMEERROR, 3600, 300);
+        cache.insert_message(&response);
+
+        let mut query = DnsMessage::default();
+        query.header.id = 42;
+        query.questions[0].name = DomainName::from_str("missing.example.com").unwrap();
+        query.questions[0].question_type = CombinedType::RecordType(RecordType::AAAA);
+        let answer = cache.answer(&query).unwrap();
+        assert_eq!(answer.header.id, 42);
+        assert_eq!(answer.header.response_code, ResponseCode::NAMEERROR);
+        assert_eq!(answer.header.authority_count, 1);
+        assert_eq!(answer.authorities[0].record_type, RecordType::SOA);
+        assert_eq!(answer.authorities[0].ttl, 300);
+
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(cache.answer(&query), None);
+    }
+
+    #[test]
+    fn nodata_caching_test() {
+        let clock = Arc::new(ManualClock::new());
+        let mut cache = Cache::with_clock(CacheConfig::default(), Arc::clone(&clock));
+        cache.insert_message(&negative_response(TEST_DOMAIN, ResponseCode::NOERROR, 60, 300));
+
+        let name = DomainName::from_str(TEST_DOMAIN).unwrap();
+        let answer = cache.lookup(&name, RecordType::A, RecordClass::IN).unwrap();
+        assert_eq!(answer.response_code, ResponseCode::NOERROR);
+        assert!(answer.answers.is_empty());
+        assert_eq!(answer.authorities[0].ttl, 60);
+        // NODATA only applies to the type which was asked for.
+        assert_eq!(cache.lookup(&name, RecordType::MX, RecordClass::IN), None);
+    }
+}
@@ -0,0 +1,1050 @@
+use dns_resolver::dns_message::{CombinedClass, CombinedType, DnsHeader, DnsMessage, DnsQuestion, DnsRecord, RecordClass, RecordType, AUTHENTIC_DATA};
+use dns_resolver::dnstap::{DnstapSink, DnstapTransport, MessageType, SocketProtocol};
+use dns_resolver::format::{self, Formatter};
+use dns_resolver::iterative::{self, IterativeResolver, Trace, TraceStep};
+use dns_resolver::pcap::{self, PcapError, PcapReader, Summary};
+use dns_resolver::resolver::{self, Resolver, ResolverConfig, SearchResult};
+use dns_resolver::system_config::{self, Hosts};
+use dns_resolver::transport::{TcpTransport, Transport, UdpTransport};
+use dns_resolver::udp_packet::{DomainName, UDP_PACKET_MAX_SIZE_BYTES};
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, BufRead};
+use std::net::{self, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const USAGE: &str = "\
+Usage: dns_resolver [@server] [options] [name [type] [class] [query options] ...]
+       dns_resolver serve [<listen address>[:<port>]] [<upstream address>[:<port>] ...]
+       dns_resolver serve-tls <certificate chain> <private key> [<listen address>[:<port>]] [<upstream address>[:<port>] ...]
+       dns_resolver serve-https <certificate chain> <private key> [<listen address>[:<port>]] [<upstream address>[:<port>] ...]
+       dns_resolver pcap [-s] [-p <port>] [+short] [+format=<f>] <capture>
+
+Options:
+  @server       Query the given name server instead of those of resolv.conf
+  -p <port>     Send queries to the given port (default 53)
+  -t <type>     Set the type of the query (default A)
+  -c <class>    Set the class of the query (default IN)
+  -x <address>  Look up the PTR record of an address
+  -f <file>     Also send the queries of a file, one name [type] [class] per line, or of stdin if -
+  -4, -6        Only use IPv4 or IPv6 name servers
+  -h            Print this help
+
+Query options, which can be negated with +no, e.g. +notcp:
+  +tcp          Use TCP instead of UDP
+  +recurse      Set the RD (recursion desired) bit (default)
+  +adflag       Set the AD (authentic data) bit, asking whether the name server validated the response
+  +dnssec       Request DNSSEC records by setting the DO bit of an EDNS0 OPT record
+  +short        Only print the data of the answers
+  +trace        Resolve iteratively from the root (or @server), printing every delegation
+  +timeout=<s>  Wait the given number of seconds for a response (default 5)
+  +tries=<n>    Try every name server the given number of times (default 3)
+  +parallel=<n> Send up to the given number of queries at once, still printing them in order (default 8)
+  +format=<f>   Print the responses as dig (default), table, json, yaml or zone, or one line per query as
+                jsonl (JSON Lines) or csv. json and jsonl require the json feature
+
+-t and -c apply to the preceding name, or to all names if they precede the first one.
+
+serve answers queries over UDP and TCP on 127.0.0.1:53 by default, by forwarding them to the upstreams
+and caching the responses. The name servers of resolv.conf are used if no upstream is given. serve-tls
+(tls feature) does the same over DNS over TLS, on port 853 by default, and serve-https (https feature)
+over DNS over HTTPS at /dns-query, on port 443 by default. Both read the certificate chain and private
+key from PEM files.
+
+pcap prints the DNS messages sent over UDP or TCP in a pcap or pcapng capture, or in stdin if -:
+  -s            Print statistics of the messages instead of the messages
+  -p <port>     Extract the messages sent to or from the given port (default 53)
+  +short        Only print the data of the answers
+  +format=<f>   Print the messages as dig (default), table, json, yaml or zone
+
+Environment:
+  DNS_RESOLVER_LOG       Log up to the given level: off, error, warn (default), info, debug, or trace,
+                         which includes hex dumps of every packet sent and received
+  DNS_RESOLVER_LOG_FILE  Append the log to the given file instead of writing it to stderr
+  DNS_RESOLVER_DNSTAP    Write the queries and responses as dnstap to the given file, or to the unix socket
+                         of a dnstap collector if prefixed with unix:, e.g. unix:/run/dnstap.sock. The
+                         serve modes log client and forwarder messages, queries stub resolver or, with
+                         +trace, resolver messages";
+
+const DEFAULT_PORT: u16 = 53;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TRIES: usize = 3;
+const DEFAULT_PARALLELISM: usize = 8;
+pub const DNSTAP_VARIABLE: &str = "DNS_RESOLVER_DNSTAP";
+const CSV_HEADER: &str = "query,type,class,status,time_ms,name,ttl,record_type,data";
+
+/// Error handling type for the command line interface.
+#[derive(Debug, PartialEq)]
+pub enum CliError {
+    /// An option which takes a value was the last argument.
+    MissingValue {
+        option: String          // The option lacking a value.
+    },
+
+    /// The value of an option could not be parsed.
+    InvalidValue {
+        option: String,         // The option.
+        value: String           // The offending value.
+    },
+
+    /// An argument starting with - or + is not a known option.
+    UnknownOption {
+        option: String          // The unknown option.
+    },
+
+    /// The server could not be resolved to an address of the requested family.
+    InvalidServer {
+        server: String          // The server as given on the command line.
+    },
+
+    /// The file given by -f could not be read.
+    UnreadableBatch {
+        path: String,           // The file, or - for stdin.
+        description: String     // The underlying IO error.
+    },
+
+    /// A line of the file given by -f is not of the form name [type] [class].
+    InvalidLine {
+        line: usize,            // The line number, starting at 1.
+        text: String            // The offending line.
+    },
+
+    /// No capture was given to pcap.
+    MissingCapture,
+
+    /// Some of the queries failed, after their errors have been printed.
+    QueriesFailed {
+        failed: usize,          // The number of failed queries.
+        total: usize            // The number of queries.
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingValue {
+                option
+            } => write!(f, "option {} requires a value", option),
+            Self::InvalidValue {
+                option,
+                value
+            } => write!(f, "invalid value '{}' for option {}", value, option),
+            Self::UnknownOption {
+                option
+            } => write!(f, "unknown option {}, see -h for the available options", option),
+            Self::InvalidServer {
+                server
+            } => write!(f, "no usable address for server {}", server),
+            Self::UnreadableBatch {
+                path,
+                description
+            } => write!(f, "could not read queries from {}: {}", path, description),
+            Self::InvalidLine {
+                line,
+                text
+            } => write!(f, "invalid query on line {}, expected name [type] [class]: '{}'", line, text),
+            Self::MissingCapture => write!(f, "pcap requires a capture file, or - for stdin"),
+            Self::QueriesFailed {
+                failed,
+                total
+            } => write!(f, "{} of {} queries failed", failed, total)
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+/// Specialised result type for the command line interface.
+pub type Result<T> = std::result::Result<T, CliError>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6
+}
+
+/// How the responses are printed, see the +format option.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Dig,                                    // The formats up to JsonLines are those of format::formatter
+    Table,
+    #[cfg(feature = "json")]
+    Json,
+    Yaml,
+    Zone,
+    #[cfg(feature = "json")]
+    JsonLines,                              // One JSON object per query, see the json module
+    Csv                                     // One row per answer, or per query without answers
+}
+
+/// A single query given on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub name: String,                       // Possibly relative, see resolver::Resolver::search
+    pub question_type: CombinedType,
+    pub question_class: CombinedClass,
+    explicit_type: bool,                    // Whether a type has been given for this query
+    explicit_class: bool                    // Whether a class has been given for this query
+}
+
+impl Query {
+    pub fn new(name: &str, question_type: CombinedType, question_class: CombinedClass) -> Self {
+        Self { name: String::from(name), question_type, question_class, explicit_type: false, explicit_class: false }
+    }
+}
+
+/// Options which apply to all queries of the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub server: Option<String>,                     // An address or host name, from @server
+    pub port: u16,
+    pub address_family: Option<AddressFamily>,      // From -4 or -6
+    pub tcp: bool,
+    pub recurse: bool,
+    pub authentic_data: bool,
+    pub dnssec: bool,                               // Send an OPT record with the DO bit
+    pub short: bool,
+    pub trace: bool,                                // Resolve iteratively, see iterative::IterativeResolver::trace
+    pub timeout: Duration,
+    pub tries: usize,
+    pub batch: Option<String>,                      // The file given by -f, - for stdin
+    pub parallel: usize,                            // The maximum number of queries in flight
+    pub format: OutputFormat
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            server: None,
+            port: DEFAULT_PORT,
+            address_family: None,
+            tcp: false,
+            recurse: true,
+            authentic_data: false,
+            dnssec: false,
+            short: false,
+            trace: false,
+            timeout: DEFAULT_TIMEOUT,
+            tries: DEFAULT_TRIES,
+            batch: None,
+            parallel: DEFAULT_PARALLELISM,
+            format: OutputFormat::default()
+        }
+    }
+}
+
+/// A parsed command line.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Command {
+    pub options: Options,
+    pub queries: Vec<Query>,    // Like in dig, the NS records of the root if no name is given
+    pub help: bool              // Set by -h, in which case nothing is queried
+}
+
+/// A parsed pcap command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PcapCommand {
+    pub path: String,           // The capture, - for stdin
+    pub port: u16,              // See pcap::PcapReader::set_port
+    pub summary: bool,          // Set by -s
+    pub short: bool,            // Set by +short
+    pub format: OutputFormat,   // One of the formats of message_formatter
+    pub help: bool              // Set by -h, in which case nothing is read
+}
+
+impl Default for PcapCommand {
+    fn default() -> Self {
+        Self { path: String::new(), port: pcap::DNS_PORT, summary: false, short: false, format: OutputFormat::default(), help: false }
+    }
+}
+
+fn parse_type(s: &str) -> Option<CombinedType> {
+    match s.parse::<u16>() {
+        Ok(value) => CombinedType::try_from(value).ok(),
+        Err(_) => CombinedType::from_str(&s.to_ascii_uppercase()).ok()
+    }
+}
+
+fn parse_class(s: &str) -> Option<CombinedClass> {
+    match s.parse::<u16>() {
+        Ok(value) => CombinedClass::try_from(value).ok(),
+        Err(_) => CombinedClass::from_str(&s.to_ascii_uppercase()).ok()
+    }
+}
+
+/// Parses the arguments following the program name, in the style of dig.
+pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<Command> {
+    let mut command = Command::default();
+    let mut default_type = CombinedType::default();
+    let mut default_class = CombinedClass::default();
+    let mut args = args.iter().map(AsRef::as_ref);
+    while let Some(arg) = args.next() {
+        if let Some(server) = arg.strip_prefix('@') {
+            command.options.server = Some(String::from(server));
+        } else if let Some(option) = arg.strip_prefix('+') {
+            parse_query_option(option, &mut command.options)?;
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            let (option, attached) = arg.split_at(arg.char_indices().nth(2).map_or(arg.len(), |(index, _)| index));
+            let mut value = || match attached.is_empty() {
+                true => args.next().ok_or_else(|| CliError::MissingValue { option: String::from(option) }),
+                false => Ok(attached)
+            };
+            let invalid = |value: &str| CliError::InvalidValue { option: String::from(option), value: String::from(value) };
+            match option {
+                "-p" => {
+                    let value = value()?;
+                    command.options.port = value.parse().map_err(|_| invalid(value))?;
+                },
+                "-t" => {
+                    let value = value()?;
+                    let question_type = parse_type(value).ok_or_else(|| invalid(value))?;
+                    match command.queries.last_mut() {
+                        Some(query) => {
+                            query.question_type = question_type;
+                            query.explicit_type = true;
+                        },
+                        None => default_type = question_type
+                    }
+                },
+                "-c" => {
+                    let value = value()?;
+                    let question_class = parse_class(value).ok_or_else(|| invalid(value))?;
+                    match command.queries.last_mut() {
+                        Some(query) => {
+                            query.question_class = question_class;
+                            query.explicit_class = true;
+                        },
+                        None => default_class = question_class
+                    }
+                },
+                "-x" => {
+                    let value = value()?;
+                    let address = net::IpAddr::from_str(value).map_err(|_| invalid(value))?;
+                    let mut query = Query::new(
+                        &DomainName::reverse(address).to_string(),
+                        CombinedType::RecordType(RecordType::PTR),
+                        CombinedClass::RecordClass(RecordClass::IN)
+                    );
+                    query.explicit_type = true;
+                    command.queries.push(query);
+                },
+                "-f" => command.options.batch = Some(String::from(value()?)),
+                "-4" if attached.is_empty() => command.options.address_family = Some(AddressFamily::Ipv4),
+                "-6" if attached.is_empty() => command.options.address_family = Some(AddressFamily::Ipv6),
+                "-h" if attached.is_empty() => command.help = true,
+                _ => return Err(CliError::UnknownOption { option: String::from(arg) })
+            }
+        } else {
+            // Like in dig, a type or class following a name applies to it, and one preceding the
+            // first name applies to all names.
+            let query = command.queries.last_mut();
+            let explicit_type = query.as_ref().is_none_or(|query| query.explicit_type);
+            let explicit_class = query.as_ref().is_none_or(|query| query.explicit_class);
+            match (query, parse_type(arg), parse_class(arg)) {
+                (Some(query), Some(question_type), _) if !explicit_type => {
+                    query.question_type = question_type;
+                    query.explicit_type = true;
+                },
+                (Some(query), None, Some(question_class)) if !explicit_class => {
+                    query.question_class = question_class;
+                    query.explicit_class = true;
+                },
+                (None, Some(question_type), _) => default_type = question_type,
+                (None, None, Some(question_class)) => default_class = question_class,
+                _ => command.queries.push(Query::new(arg, default_type, default_class))
+            }
+        }
+    }
+    if command.queries.is_empty() && command.options.batch.is_none() {
+        command.queries.push(Query::new(".", CombinedType::RecordType(RecordType::NS), default_class));
+    }
+    Ok(command)
+}
+
+/// Parses a query option without its leading +, e.g. notcp or timeout=2.
+fn parse_query_option(option: &str, options: &mut Options) -> Result<()> {
+    let (name, value) = match option.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (option, None)
+    };
+    let (name, enabled) = match name.strip_prefix("no") {
+        Some(name) => (name, false),
+        None => (name, true)
+    };
+    let invalid = || CliError::InvalidValue { option: format!("+{}", name), value: String::from(value.unwrap_or_default()) };
+    let positive = || match value.map(str::parse::<u64>) {
+        Some(Ok(value)) if value > 0 && enabled => Ok(value),
+        _ => Err(invalid())
+    };
+    match name {
+        "tcp" | "vc" if value.is_none() => options.tcp = enabled,
+        "recurse" | "rec" if value.is_none() => options.recurse = enabled,
+        "adflag" if value.is_none() => options.authentic_data = enabled,
+        "dnssec" if value.is_none() => options.dnssec = enabled,
+        "short" if value.is_none() => options.short = enabled,
+        "trace" if value.is_none() => options.trace = enabled,
+        "timeout" => options.timeout = Duration::from_secs(positive()?),
+        "tries" => options.tries = positive()? as usize,
+        "parallel" => options.parallel = positive()? as usize,
+        "format" if enabled => options.format = value.and_then(parse_format).ok_or_else(invalid)?,
+        _ => return Err(CliError::UnknownOption { option: format!("+{}", option) })
+    }
+    Ok(())
+}
+
+/// Parses the value of +format.
+fn parse_format(value: &str) -> Option<OutputFormat> {
+    match value {
+        "dig" | "text" => Some(OutputFormat::Dig),
+        "table" => Some(OutputFormat::Table),
+        #[cfg(feature = "json")]
+        "json" => Some(OutputFormat::Json),
+        "yaml" => Some(OutputFormat::Yaml),
+        "zone" => Some(OutputFormat::Zone),
+        #[cfg(feature = "json")]
+        "jsonl" => Some(OutputFormat::JsonLines),
+        "csv" => Some(OutputFormat::Csv),
+        _ => None
+    }
+}
+
+/// Parses the arguments following pcap, see USAGE.
+pub fn parse_pcap<S: AsRef<str>>(args: &[S]) -> Result<PcapCommand> {
+    let mut command = PcapCommand::default();
+    let mut path = None;
+    let mut args = args.iter().map(AsRef::as_ref);
+    while let Some(arg) = args.next() {
+        let invalid = |option: &str, value: &str| CliError::InvalidValue { option: String::from(option), value: String::from(value) };
+        match arg {
+            "-s" => command.summary = true,
+            "-h" => command.help = true,
+            "-p" => {
+                let value = args.next().ok_or_else(|| CliError::MissingValue { option: String::from(arg) })?;
+                command.port = value.parse().map_err(|_| invalid(arg, value))?;
+            },
+            "+short" => command.short = true,
+            _ if arg.starts_with("+format=") => {
+                // Formats printing one line per query have no meaning for captured messages.
+                let value = &arg["+format=".len()..];
+                command.format = parse_format(value)
+                .filter(|format| message_formatter(*format, false).is_some())
+                .ok_or_else(|| invalid("+format", value))?;
+            },
+            _ if arg.len() > 1 && (arg.starts_with('-') || arg.starts_with('+')) => return Err(CliError::UnknownOption { option: String::from(arg) }),
+            _ if path.is_none() => path = Some(String::from(arg)),
+            _ => return Err(invalid("pcap", arg))
+        }
+    }
+    match path {
+        Some(path) => command.path = path,
+        None if command.help => (),
+        None => return Err(CliError::MissingCapture)
+    }
+    Ok(command)
+}
+
+/// Parses the queries of a batch file, one `name [type] [class]` per line, where the type and class
+/// may be given in either order. Empty lines and comments starting with # or ; are skipped.
+pub fn parse_batch(text: &str) -> Result<Vec<Query>> {
+    let mut queries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+            continue;
+        }
+        let invalid = || CliError::InvalidLine { line: index + 1, text: String::from(line) };
+        let mut words = trimmed.split_whitespace();
+        let mut query = Query::new(words.next().ok_or_else(invalid)?, CombinedType::default(), CombinedClass::default());
+        for word in words {
+            match (parse_type(word), parse_class(word)) {
+                (Some(question_type), _) if !query.explicit_type => {
+                    query.question_type = question_type;
+                    query.explicit_type = true;
+                },
+                (None, Some(question_class)) if !query.explicit_class => {
+                    query.question_class = question_class;
+                    query.explicit_class = true;
+                },
+                _ => return Err(invalid())
+            }
+        }
+        queries.push(query);
+    }
+    Ok(queries)
+}
+
+/// Reads the queries of the file given by -f, or of stdin if it is -.
+fn read_batch(path: &str) -> Result<Vec<Query>> {
+    let text = match path {
+        "-" => io::stdin().lock().lines().collect::<io::Result<Vec<String>>>().map(|lines| lines.join("\n")),
+        _ => std::fs::read_to_string(path)
+    };
+    parse_batch(&text.map_err(|error| CliError::UnreadableBatch { path: String::from(path), description: error.to_string() })?)
+}
+
+/// Builds the configuration of the resolver from resolv.conf and the options.
+fn resolver_config(options: &Options) -> std::result::Result<ResolverConfig, Box<dyn std::error::Error>> {
+    let mut config = system_config_or_default(system_config::RESOLV_CONF_PATH, options)?;
+    config.timeout = options.timeout;
+    config.attempts = options.tries;
+    let servers: Vec<net::IpAddr> = match &options.server {
+        Some(server) => server_addresses(server)?,
+        None => config.servers.iter().map(net::SocketAddr::ip).collect()
+    };
+    let server = options.server.as_deref().unwrap_or(system_config::RESOLV_CONF_PATH);
+    config.servers = filter_family(servers, server, options)?.into_iter()
+    .map(|address| net::SocketAddr::new(address, options.port))
+    .collect();
+    Ok(config)
+}
+
+/// Reads the resolver configuration at `path`. The file only supplies defaults when @server is
+/// given, so failing to read it is not an error then.
+fn system_config_or_default(path: &str, options: &Options) -> system_config::Result<ResolverConfig> {
+    match system_config::read_resolv_conf(path) {
+        Err(_) if options.server.is_some() => Ok(ResolverConfig::default()),
+        result => result
+    }
+}
+
+/// Resolves the server given by @server, which is either an address or a host name.
+fn server_addresses(server: &str) -> Result<Vec<net::IpAddr>> {
+    let server = server.trim_start_matches('[').trim_end_matches(']');
+    match net::IpAddr::from_str(server) {
+        Ok(address) => Ok(vec![address]),
+        Err(_) => Ok((server, 0).to_socket_addrs()
+        .map_err(|_| CliError::InvalidServer { server: String::from(server) })?
+        .map(|address| address.ip())
+        .collect())
+    }
+}
+
+/// Keeps the addresses of the family requested by -4 or -6, failing if none remain.
+fn filter_family(mut addresses: Vec<net::IpAddr>, server: &str, options: &Options) -> Result<Vec<net::IpAddr>> {
+    addresses.retain(|address| match options.address_family {
+        Some(AddressFamily::Ipv4) => address.is_ipv4(),
+        Some(AddressFamily::Ipv6) => address.is_ipv6(),
+        None => true
+    });
+    match addresses.is_empty() {
+        true => Err(CliError::InvalidServer { server: String::from(server) }),
+        false => Ok(addresses)
+    }
+}
+
+/// Sends every query of `command`, followed by those of the file given by -f, printing the responses
+/// and returning the results of those which succeeded. Failures are printed as they happen and
+/// reported at the end.
+pub fn run(command: &Command) -> std::result::Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let mut queries = command.queries.clone();
+    if let Some(path) = &command.options.batch {
+        queries.extend(read_batch(path)?);
+    }
+    if command.options.trace {
+        return run_traces(&queries, &command.options);
+    }
+    let config = resolver_config(&command.options)?;
+    let transport = transport(&command.options, MessageType::STUBQUERY)?;
+    run_queries(&Resolver::with_transport(config, Hosts::default(), transport), &queries, &command.options)
+}
+
+/// Opens the dnstap destination given by DNSTAP_VARIABLE, if any.
+pub fn dnstap_sink() -> std::result::Result<Option<Arc<DnstapSink>>, Box<dyn std::error::Error>> {
+    match env::var(DNSTAP_VARIABLE) {
+        Ok(destination) => Ok(Some(Arc::new(DnstapSink::open(&destination)?))),
+        Err(_) => Ok(None)
+    }
+}
+
+/// Returns the transport selected by +tcp, logging its queries as `message_type` if dnstap is
+/// enabled.
+fn transport(options: &Options, message_type: MessageType) -> std::result::Result<Box<dyn Transport>, Box<dyn std::error::Error>> {
+    let (transport, socket_protocol): (Box<dyn Transport>, SocketProtocol) = match options.tcp {
+        true => (Box::new(TcpTransport), SocketProtocol::TCP),
+        false => (Box::new(UdpTransport::default()), SocketProtocol::UDP)
+    };
+    match dnstap_sink()? {
+        Some(sink) => Ok(Box::new(DnstapTransport::new(transport, sink, message_type, socket_protocol))),
+        None => Ok(transport)
+    }
+}
+
+fn run_queries<T: Transport + Sync>(resolver: &Resolver<T>, queries: &[Query], options: &Options) -> std::result::Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let mut results = Vec::new();
+    if options.format == OutputFormat::Csv {
+        println!("{}", CSV_HEADER);
+    }
+    search_in_order(resolver, queries, options, |query, outcome, elapsed| {
+        if let Ok(result) = &outcome {
+            log::debug!("response for {}: {:?}", query.name, result.response);
+        }
+        print_outcome(query, &outcome, options, elapsed);
+        results.extend(outcome);
+    });
+    match results.len() == queries.len() {
+        true => Ok(results),
+        false => Err(CliError::QueriesFailed { failed: queries.len() - results.len(), total: queries.len() }.into())
+    }
+}
+
+/// Sends up to options.parallel queries at once, passing the outcomes to `emit` in the order of
+/// `queries` as soon as all preceding ones are known.
+fn search_in_order<T, F>(resolver: &Resolver<T>, queries: &[Query], options: &Options, mut emit: F)
+where T: Transport + Sync, F: FnMut(&Query, resolver::Result<SearchResult>, Duration) {
+    let next = AtomicUsize::new(0);     // The index of the next query to send
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..options.parallel.min(queries.len()) {
+            let (sender, next) = (sender.clone(), &next);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(query) = queries.get(index) else {
+                    break;
+                };
+                let start = Instant::now();
+                let outcome = resolver.search_query(&query.name, &query_template(query, options));
+                if sender.send((index, outcome, start.elapsed())).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+        let mut pending = BTreeMap::new();  // Outcomes waiting for those of preceding queries
+        let mut next_emitted = 0;
+        for (index, outcome, elapsed) in receiver {
+            pending.insert(index, (outcome, elapsed));
+            while let Some((outcome, elapsed)) = pending.remove(&next_emitted) {
+                emit(&queries[next_emitted], outcome, elapsed);
+                next_emitted += 1;
+            }
+        }
+    });
+}
+
+/// Builds the query sent for `query`, whose name is filled in by the resolver.
+fn query_template(query: &Query, options: &Options) -> DnsMessage {
+    // Larger UDP responses could not be received, those exceeding the size are truncated instead.
+    let additional = match options.dnssec {
+        true => vec![DnsRecord::opt(UDP_PACKET_MAX_SIZE_BYTES as u16, true)],
+        false => Vec::new()
+    };
+    let mut query = DnsMessage {
+        header: DnsHeader {
+            recursion_desired: options.recurse,
+            z: if options.authentic_data { AUTHENTIC_DATA } else { 0 },
+            ..Default::default()
+        },
+        questions: vec![DnsQuestion {
+            name: DomainName::root(),   // Replaced by every candidate of the search list
+            question_type: query.question_type,
+            question_class: query.question_class
+        }],
+        additional,
+        ..Default::default()
+    };
+    query.update_counts();
+    query
+}
+
+fn print_outcome(query: &Query, outcome: &resolver::Result<SearchResult>, options: &Options, elapsed: Duration) {
+    match (options.format, outcome) {
+        #[cfg(feature = "json")]
+        (OutputFormat::JsonLines, _) => println!("{}", json_line(query, outcome, elapsed)),
+        (OutputFormat::Csv, _) => {
+            for row in csv_rows(query, outcome, elapsed) {
+                println!("{}", row);
+            }
+        },
+        (_, Ok(result)) => print_result(&query.name, result, options, elapsed),
+        (_, Err(error)) => eprintln!(";; {}: {}", query.name, error)
+    }
+}
+
+/// Returns the formatter of the responses, unless the format prints one line per query.
+fn message_formatter(format: OutputFormat, short: bool) -> Option<Box<dyn Formatter>> {
+    let name = match format {
+        _ if short => "short",
+        OutputFormat::Dig => "dig",
+        OutputFormat::Table => "table",
+        #[cfg(feature = "json")]
+        OutputFormat::Json => "json",
+        OutputFormat::Yaml => "yaml",
+        OutputFormat::Zone => "zone",
+        #[cfg(feature = "json")]
+        OutputFormat::JsonLines => return None,
+        OutputFormat::Csv => return None
+    };
+    format::formatter(name)
+}
+
+/// Returns whether the messages printed by message_formatter are preceded and followed by
+/// comments, like in dig. Other formats are meant to be parsed, and print nothing else.
+fn commented(format: OutputFormat, short: bool) -> bool {
+    !short && matches!(format, OutputFormat::Dig | OutputFormat::Table | OutputFormat::Zone)
+}
+
+/// Renders the outcome of a query as a JSON object on a single line, with the response in the
+/// format of the json module.
+#[cfg(feature = "json")]
+fn json_line(query: &Query, outcome: &resolver::Result<SearchResult>, elapsed: Duration) -> String {
+    let mut line = serde_json::json!({
+        "query": query.name,
+        "type": query.question_type.to_string(),
+        "class": query.question_class.to_string(),
+        "time_ms": elapsed.as_millis() as u64
+    });
+    match outcome {
+        Ok(result) => {
+            line["name"] = serde_json::Value::String(result.name.to_string());
+            line["response"] = result.response.to_json_value();
+        },
+        Err(error) => line["error"] = serde_json::Value::String(error.to_string())
+    }
+    line.to_string()
+}
+
+/// Renders the outcome of a query as rows matching CSV_HEADER, one per answer, or a single one
+/// with empty record columns if there are none. The status is the response code or the error.
+fn csv_rows(query: &Query, outcome: &resolver::Result<SearchResult>, elapsed: Duration) -> Vec<String> {
+    let status = match outcome {
+        Ok(result) => result.response.header.response_code.to_string(),
+        Err(error) => error.to_string()
+    };
+    let columns = [
+        query.name.clone(), query.question_type.to_string(), query.question_class.to_string(), status,
+        elapsed.as_millis().to_string()
+    ];
+    let answers = match outcome {
+        Ok(result) => result.response.answers.iter().map(|answer| [
+            answer.name.to_string(), answer.ttl.to_string(), answer.record_type.to_string(), format::presentation_data(&answer.data)
+        ]).collect(),
+        Err(_) => Vec::new()
+    };
+    let row = |record: &[String]| columns.iter().chain(record).map(|field| csv_field(field)).collect::<Vec<String>>().join(",");
+    match answers.is_empty() {
+        true => vec![row(&[String::new(), String::new(), String::new(), String::new()])],
+        false => answers.iter().map(|record| row(record)).collect()
+    }
+}
+
+/// Quotes a CSV field if needed, as described by RFC 4180.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => String::from(field)
+    }
+}
+
+/// Resolves every query iteratively, like dig +trace, starting at the root name servers or at the
+/// server given by @server.
+fn run_traces(queries: &[Query], options: &Options) -> std::result::Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let root_hints = match &options.server {
+        Some(server) => filter_family(server_addresses(server)?, server, options)?,
+        None => filter_family(iterative::ROOT_HINTS.iter().copied().map(net::IpAddr::V4).collect(), ".", options)?
+    };
+    let transport = transport(options, MessageType::RESOLVERQUERY)?;
+    trace_queries(IterativeResolver::with_transport(root_hints, options.port, transport), queries, options)
+}
+
+fn trace_queries<T: Transport>(mut resolver: IterativeResolver<T>, queries: &[Query], options: &Options) -> std::result::Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    resolver.set_timeout(options.timeout);
+    let mut results = Vec::new();
+    for query in queries.iter() {
+        // The resolution starts at the root, so there is no search list to complete relative names.
+        let invalid = |value: String| CliError::InvalidValue { option: String::from("+trace"), value };
+        let name = DomainName::from_str(&query.name).map_err(|_| invalid(query.name.clone()))?;
+        let CombinedType::RecordType(record_type) = query.question_type else {
+            return Err(invalid(query.question_type.to_string()).into());
+        };
+        let CombinedClass::RecordClass(record_class) = query.question_class else {
+            return Err(invalid(query.question_class.to_string()).into());
+        };
+        let start = Instant::now();
+        let trace = resolver.trace(&name, record_type, record_class);
+        print_trace(&trace);
+        match trace.result {
+            Ok(response) => {
+                let result = SearchResult { name, response };
+                print_result(&query.name, &result, options, start.elapsed());
+                results.push(result);
+            },
+            Err(error) => eprintln!(";; {}: {}", query.name, error)
+        }
+    }
+    match results.len() == queries.len() {
+        true => Ok(results),
+        false => Err(CliError::QueriesFailed { failed: queries.len() - results.len(), total: queries.len() }.into())
+    }
+}
+
+/// Prints the steps of a trace, indenting the resolutions of glueless name servers, followed by
+/// any glue which does not match the addresses served by the child zone.
+fn print_trace(trace: &Trace) {
+    for step in trace.steps.iter() {
+        let indent = "  ".repeat(step.depth);
+        println!("{};; {} {} {} sent to {} for {} in {} msec",
+            indent, step.question.name, step.question.question_class, step.question.question_type,
+            step.server, step.zone, step.response_time.as_millis());
+        for line in describe_step(step) {
+            println!("{};;   {}", indent, line);
+        }
+    }
+    for mismatch in trace.glue_mismatches.iter() {
+        let join = |addresses: &[net::IpAddr]| match addresses.is_empty() {
+            true => String::from("none"),
+            false => addresses.iter().map(net::IpAddr::to_string).collect::<Vec<String>>().join(", ")
+        };
+        println!(";; GLUE MISMATCH: {} of {} has glue {}, but the zone serves {}",
+            mismatch.name_server, mismatch.zone, join(&mismatch.glue), join(&mismatch.authoritative));
+    }
+    if !trace.steps.is_empty() {
+        println!();
+    }
+}
+
+fn describe_step(step: &TraceStep) -> Vec<String> {
+    let response = match &step.response {
+        Ok(response) => response,
+        Err(error) if error.is_timeout() => return vec![String::from("timed out")],
+        Err(error) => return vec![format!("failed: {}", error)]
+    };
+    if step.is_lame() {
+        return vec![format!("LAME DELEGATION: {} is not authoritative for {} ({})",
+            step.server, step.zone, response.header.response_code)];
+    }
+    match step.referral() {
+        Some((zone, name_servers)) => {
+            let glue = step.glue();
+            let mut lines = vec![format!("referral to {}", zone)];
+            for name_server in name_servers {
+                let addresses: Vec<String> = glue.iter()
+                .filter(|(name, _)| *name == name_server)
+                .map(|(_, address)| address.to_string())
+                .collect();
+                lines.push(match addresses.is_empty() {
+                    true => format!("{} NS {} (no glue)", zone, name_server),
+                    false => format!("{} NS {} ({})", zone, name_server, addresses.join(", "))
+                });
+            }
+            lines
+        },
+        None => vec![format!("{} with {} answers", response.header.response_code, response.answers.len())]
+    }
+}
+
+/// Prints the DNS messages of the capture given to pcap, or their statistics with -s. Payloads which
+/// are not DNS messages are reported on stderr.
+pub fn run_pcap(command: &PcapCommand) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    match command.path.as_str() {
+        "-" => print_capture(PcapReader::new(io::stdin().lock())?, command),
+        path => print_capture(PcapReader::open(path)?, command)
+    }
+}
+
+fn print_capture<R: io::Read>(mut reader: PcapReader<R>, command: &PcapCommand) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    reader.set_port(command.port);
+    let formatter = message_formatter(command.format, command.short).expect("The format is checked by parse_pcap.");
+    let mut summary = Summary::default();
+    for result in reader {
+        summary.add_result(&result);
+        match result {
+            Ok(captured) if !command.summary => match commented(command.format, command.short) {
+                true => {
+                    println!(";; {} {} {} -> {}", timestamp(captured.timestamp), captured.protocol, captured.source, captured.destination);
+                    println!("{}", captured.message.formatted(formatter.as_ref()));
+                },
+                false => print!("{}", captured.message.formatted(formatter.as_ref()))
+            },
+            Ok(_) => (),
+            Err(error @ PcapError::InvalidMessage { .. }) => eprintln!(";; {}", error),
+            Err(error) => return Err(error.into())
+        }
+    }
+    if command.summary {
+        print!("{}", summary);
+    }
+    Ok(())
+}
+
+/// Formats a time as seconds since the Unix epoch, with microseconds like tcpdump.
+fn timestamp(time: SystemTime) -> String {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:06}", time.as_secs(), time.subsec_micros())
+}
+
+/// Prints a response using message_formatter. Formats with comments are surrounded by the name
+/// which was answered and the query time, like in dig.
+fn print_result(name: &str, result: &SearchResult, options: &Options, elapsed: Duration) {
+    let Some(formatter) = message_formatter(options.format, options.short) else {
+        return;
+    };
+    match commented(options.format, options.short) {
+        true => {
+            println!(";; Resolved {} as {}", name, result.name);
+            println!("{}", result.response.formatted(formatter.as_ref()));
+            println!(";; Query time: {} msec", elapsed.as_millis());
+            println!();
+        },
+        false => print!("{}", result.response.formatted(formatter.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::*;
+    use dns_resolver::dns_message::DNSSEC_OK;
+
+    fn a() -> CombinedType {
+        CombinedType::RecordType(RecordType::A)
+    }
+
+    fn mx() -> CombinedType {
+        CombinedType::RecordType(RecordType::MX)
+    }
+
+    #[test]
+    fn parse_test() {
+        let command = parse(&["@192.0.2.53", "-p", "5353", "example.com", "MX", "+tcp", "+norecurse", "+adflag", "example.org", "-x", "192.0.2.1"]).unwrap();
+        assert_eq!(command.options.server.as_deref(), Some("192.0.2.53"));
+        assert_eq!(command.options.port, 5353);
+        assert!(command.options.tcp);
+        assert!(!command.options.recurse);
+        assert!(command.options.authentic_data);
+        assert_eq!(query_template(&command.queries[0], &command.options).header.z, AUTHENTIC_DATA);
+        assert!(query_template(&command.queries[0], &command.options).additional.is_empty());
+        let names: Vec<(&str, CombinedType)> = command.queries.iter().map(|query| (query.name.as_str(), query.question_type)).collect();
+        assert_eq!(names, vec![
+            ("example.com", mx()),
+            ("example.org", a()),
+            ("1.2.0.192.in-addr.arpa.", CombinedType::RecordType(RecordType::PTR))
+        ]);
+
+        let command = parse(&["example.com", "+dnssec"]).unwrap();
+        assert!(command.options.dnssec);
+        let template = query_template(&command.queries[0], &command.options);
+        assert_eq!(template.header.additional_count, 1);
+        assert_eq!(template.additional[0].record_type, RecordType::OPT);
+        assert_eq!(template.additional[0].ttl & DNSSEC_OK, DNSSEC_OK);
+    }
+
+    #[test]
+    fn type_and_class_test() {
+        // A type or class preceding the first name applies to all names, one following a name to it only.
+        let command = parse(&["-t", "mx", "example.com", "example.org", "-t", "AAAA", "example.net", "ANY", "IN"]).unwrap();
+        let types: Vec<CombinedType> = command.queries.iter().map(|query| query.question_type).collect();
+        assert_eq!(types, vec![mx(), CombinedType::RecordType(RecordType::AAAA), CombinedType::QuestionType(dns_resolver::dns_message::QuestionType::ANY)]);
+        assert_eq!(command.queries[2].question_class, CombinedClass::RecordClass(RecordClass::IN));
+        let command = parse(&["example.com", "-t15", "+trace"]).unwrap();
+        assert_eq!(command.queries[0].question_type, mx());
+        assert!(command.options.trace);
+
+        // Like dig, the root name servers are asked for if no name is given.
+        let command = parse::<&str>(&["+short", "+timeout=2", "+tries=1", "-6"]).unwrap();
+        assert_eq!(command.queries, vec![Query::new(".", CombinedType::RecordType(RecordType::NS), CombinedClass::default())]);
+        assert!(command.options.short);
+        assert!(!command.options.trace);
+        assert_eq!(command.options.timeout, Duration::from_secs(2));
+        assert_eq!(command.options.tries, 1);
+        assert_eq!(command.options.address_family, Some(AddressFamily::Ipv6));
+    }
+
+    #[test]
+    fn parse_error_test() {
+        assert_eq!(parse(&["-p"]), Err(CliError::MissingValue { option: String::from("-p") }));
+        assert_eq!(parse(&["-p", "port"]), Err(CliError::InvalidValue { option: String::from("-p"), value: String::from("port") }));
+        assert_eq!(parse(&["-x", "example.com"]), Err(CliError::InvalidValue { option: String::from("-x"), value: String::from("example.com") }));
+        assert_eq!(parse(&["-t", "XYZ"]), Err(CliError::InvalidValue { option: String::from("-t"), value: String::from("XYZ") }));
+        assert_eq!(parse(&["+timeout=0"]), Err(CliError::InvalidValue { option: String::from("+timeout"), value: String::from("0") }));
+        assert_eq!(parse(&["+bogus"]), Err(CliError::UnknownOption { option: String::from("+bogus") }));
+        assert_eq!(parse(&["-q"]), Err(CliError::UnknownOption { option: String::from("-q") }));
+        assert_eq!(parse(&["+format=xml"]), Err(CliError::InvalidValue { option: String::from("+format"), value: String::from("xml") }));
+    }
+
+    #[test]
+    fn parse_pcap_test() {
+        let command = parse_pcap(&["-s", "-p", "5353", "capture.pcapng", "+format=yaml"]).unwrap();
+        assert_eq!(command, PcapCommand {
+            path: String::from("capture.pcapng"),
+            port: 5353,
+            summary: true,
+            short: false,
+            format: OutputFormat::Yaml,
+            help: false
+        });
+        assert!(parse_pcap(&["+short", "-"]).unwrap().short);
+        assert_eq!(parse_pcap(&["+format=text", "-"]).unwrap().format, OutputFormat::Dig);
+        assert_eq!(parse_pcap::<&str>(&[]), Err(CliError::MissingCapture));
+        assert!(parse_pcap(&["-h"]).unwrap().help);
+        assert_eq!(parse_pcap(&["+format=csv", "-"]), Err(CliError::InvalidValue { option: String::from("+format"), value: String::from("csv") }));
+        assert_eq!(parse_pcap(&["-x", "-"]), Err(CliError::UnknownOption { option: String::from("-x") }));
+    }
+
+    #[test]
+    fn batch_test() {
+        let command = parse(&["-f", "-", "+parallel=32", "+format=csv"]).unwrap();
+        assert!(command.queries.is_empty());
+        assert_eq!(command.options.batch.as_deref(), Some("-"));
+        assert_eq!(command.options.parallel, 32);
+        assert_eq!(command.options.format, OutputFormat::Csv);
+        assert_eq!(parse(&["+format=yaml"]).unwrap().options.format, OutputFormat::Yaml);
+        assert_eq!(parse(&["+format=text"]).unwrap().options.format, OutputFormat::Dig);
+
+        let queries = parse_batch("example.com\n\n# A comment\nexample.org mx\n  example.net IN AAAA  \n").unwrap();
+        let types: Vec<(&str, CombinedType)> = queries.iter().map(|query| (query.name.as_str(), query.question_type)).collect();
+        assert_eq!(types, vec![("example.com", a()), ("example.org", mx()), ("example.net", CombinedType::RecordType(RecordType::AAAA))]);
+        assert_eq!(parse_batch("example.com\nexample.org MX A\n"), Err(CliError::InvalidLine { line: 2, text: String::from("example.org MX A") }));
+    }
+
+    #[test]
+    fn search_in_order_test() {
+        use dns_resolver::dns_message::{DnsRecord, RecordData};
+        use dns_resolver::transport::{MockAction, MockTransport};
+        let question = |name: &str| DnsQuestion { name: DomainName::from_str(name).unwrap(), ..Default::default() };
+        let answer = |name: &str, address: [u8; 4]| DnsMessage {
+            answers: vec![DnsRecord {
+                name: DomainName::from_str(name).unwrap(),
+                record_type: RecordType::A,
+                record_class: RecordClass::IN,
+                ttl: 60,
+                length: 4,
+                data: RecordData::A { ipv4_address: net::Ipv4Addr::from(address) }
+            }],
+            ..Default::default()
+        };
+        // The first response is delayed, so the second query is answered before the first.
+        let transport = MockTransport::new();
+        transport.script(question("slow.example."), vec![MockAction::Delay(Duration::from_millis(200), Box::new(MockAction::Respond(answer("slow.example.", [192, 0, 2, 1]))))]);
+        transport.script(question("fast.example."), vec![MockAction::Respond(answer("fast.example.", [192, 0, 2, 2]))]);
+        let config = ResolverConfig { servers: vec![net::SocketAddr::from(([192, 0, 2, 53], 53))], ..Default::default() };
+        let resolver = Resolver::with_transport(config, Hosts::default(), transport);
+        let queries = vec![
+            Query::new("slow.example.", a(), CombinedClass::default()),
+            Query::new("fast.example.", a(), CombinedClass::default()),
+            Query::new("refused.example.", a(), CombinedClass::default())
+        ];
+        let mut rows = Vec::new();
+        search_in_order(&resolver, &queries, &Options { parallel: 2, ..Default::default() }, |query, outcome, _| {
+            rows.extend(csv_rows(query, &outcome, Duration::ZERO));
+        });
+        assert_eq!(rows, vec![
+            "slow.example.,A,IN,NOERROR,0,slow.example.,60,A,192.0.2.1",
+            "fast.example.,A,IN,NOERROR,0,fast.example.,60,A,192.0.2.2",
+            "refused.example.,A,IN,REFUSED,0,,,,"
+        ]);
+        assert_eq!(csv_field("a \"quoted\", field"), "\"a \"\"quoted\"\", field\"");
+    }
+
+    #[test]
+    fn system_config_test() {
+        // A directory cannot be read as a file.
+        let path = env!("CARGO_MANIFEST_DIR");
+        assert!(system_config_or_default(path, &Options::default()).is_err());
+        let options = Options { server: Some(String::from("192.0.2.53")), ..Default::default() };
+        assert_eq!(system_config_or_default(path, &options).unwrap().servers, ResolverConfig::default().servers);
+    }
+}
@@ -1,11 +1,13 @@
 use crate::build_enum;
 use crate::conversions::*;
+use crate::random;
 use crate::tabulation::Table;
 use crate::udp_packet;
 use std::convert::{TryFrom, TryInto};
 use std::fmt::Display;
 use std::str::FromStr;
 use std::net;
+use std::time::{Duration, Instant};
 
 // Flag bitfield format (DnsHeader):
 // 0b 1000 0000 0000 0000 (0x8000) response
@@ -47,9 +49,17 @@ build_enum!(
     NS = 2,         // Name server domain name
     CNAME = 5,      // Canonical name of an alias
     SOA = 6,        // Name server zone information
+    PTR = 12,       // A domain name pointer, e.g. from a reverse lookup
     HINFO = 13,     // Host information (CPU and OS) for name server
     MX = 15,        // The domain name of a MailExchange address
-    AAAA = 28       // An Ipv6 address (u128)
+    AAAA = 28,      // An Ipv6 address (u128)
+    DNAME = 39,     // Redirection of a whole subtree to another name (RFC 6672)
+    OPT = 41,       // EDNS0 pseudo-record, only in the additional section (RFC 6891)
+    DS = 43,        // Delegation signer, the digest of a DNSKEY of a child zone (RFC 4034)
+    RRSIG = 46,     // Signature over a record set (RFC 4034)
+    NSEC = 47,      // Proof of non-existence, the next owner name of a zone (RFC 4034)
+    DNSKEY = 48,    // Public key of a zone (RFC 4034)
+    NSEC3 = 50      // Proof of non-existence with hashed owner names (RFC 5155)
 );
 
 build_enum!(
@@ -128,6 +138,9 @@ impl TryInto<u16> for CombinedType {
     }
 }
 
+#[cfg(feature = "serde")]
+crate::serde_as_str!(CombinedType);
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CombinedClass {
     QuestionClass(QuestionClass),
@@ -188,7 +201,11 @@ impl TryInto<u16> for CombinedClass {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[cfg(feature = "serde")]
+crate::serde_as_str!(CombinedClass);
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RecordData {
     A {
         ipv4_address: net::Ipv4Addr,
@@ -199,6 +216,9 @@ pub enum RecordData {
     CNAME {
         canonical_name: udp_packet::DomainName,
     },
+    DNAME {
+        target: udp_packet::DomainName,
+    },
     HINFO {
         cpu: udp_packet::CharacterString,
         os: udp_packet::CharacterString,
@@ -210,6 +230,9 @@ pub enum RecordData {
     NS {
         domain_name: udp_packet::DomainName,
     },
+    PTR {
+        domain_name: udp_packet::DomainName,
+    },
     SOA {
         domain_name: udp_packet::DomainName,
         mailbox_address: udp_packet::DomainName,
@@ -219,6 +242,14 @@ pub enum RecordData {
         expire: u32,
         minimum: u32,
     },
+    OPT {
+        udp_payload_size: u16,  // The CLASS field of the record
+        options: Vec<u8>,
+    },
+    // The uninterpreted RDATA of the DNSSEC record types, which are only passed on.
+    Opaque {
+        data: Vec<u8>,
+    },
     Unknown
 }
 
@@ -234,6 +265,9 @@ impl Display for RecordData {
             Self::CNAME {
                 canonical_name,
             } => canonical_name.fmt(f),
+            Self::DNAME {
+                target,
+            } => target.fmt(f),
             Self::HINFO { 
                 cpu, 
                 os 
@@ -245,6 +279,9 @@ impl Display for RecordData {
             Self::NS {
                 domain_name,
             } => domain_name.fmt(f),
+            Self::PTR {
+                domain_name,
+            } => domain_name.fmt(f),
             Self::SOA {
                 domain_name,
                 mailbox_address,
@@ -254,6 +291,14 @@ impl Display for RecordData {
                 expire,
                 minimum,
             } => write!(f, "{} {} {} {} {} {} {}", domain_name, mailbox_address, serial, refresh, retry, expire, minimum),
+            Self::OPT {
+                udp_payload_size,
+                ..
+            } => write!(f, "udp: {}", udp_payload_size),
+            // The generic presentation format of RFC 3597, section 5.
+            Self::Opaque {
+                data,
+            } => write!(f, "\\# {} {}", data.len(), data.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
             Self::Unknown => write!(f, "Unknown/unimplemented")
         }
     }
@@ -272,6 +317,9 @@ impl RecordData {
             Self::CNAME {
                 canonical_name,
             } => canonical_name.bytes.to_vec(),
+            Self::DNAME {
+                target,
+            } => target.bytes.to_vec(),
             Self::HINFO { 
                 cpu, 
                 os 
@@ -291,6 +339,9 @@ impl RecordData {
             Self::NS {
                 domain_name,
             } => domain_name.bytes.to_vec(),
+            Self::PTR {
+                domain_name,
+            } => domain_name.bytes.to_vec(),
             Self::SOA {
                 domain_name,
                 mailbox_address,
@@ -308,15 +359,25 @@ impl RecordData {
                 .collect::<Vec<[u8; 4]>>()
                 .concat()
                 ].concat(),
+            Self::OPT {
+                options,
+                ..
+            } => options.to_vec(),
+            Self::Opaque {
+                data,
+            } => data.to_vec(),
             Self::Unknown => "Unknown/unimplemented".as_bytes().to_vec()
         }
     }
 
-    pub fn read_from_udp_packet(udp_packet: &mut udp_packet::UdpPacket, record_type: RecordType) -> udp_packet::Result<Self> {
+    /// Reads RDATA of `length` bytes. The UDP payload size of OPT records is not part of the RDATA,
+    /// see DnsRecord::read_from_udp_packet.
+    pub fn read_from_udp_packet<const N: usize>(udp_packet: &mut udp_packet::UdpPacket<N>, record_type: RecordType, length: u16) -> udp_packet::Result<Self> {
         match record_type {
             RecordType::A => Ok(Self::A { ipv4_address: net::Ipv4Addr::from(udp_packet.read_u32()?) }),
             RecordType::AAAA => Ok(Self::AAAA { ipv6_address: net::Ipv6Addr::from(udp_packet.read_u128()?) }),
             RecordType::CNAME => Ok(Self::CNAME { canonical_name: udp_packet.read_domain_name()? }),
+            RecordType::DNAME => Ok(Self::DNAME { target: udp_packet.read_domain_name()? }),
             RecordType::HINFO => Ok(Self::HINFO { 
                 cpu: udp_packet.read_character_string()?, 
                 os: udp_packet.read_character_string()? 
@@ -326,6 +387,7 @@ impl RecordData {
                 exchange_address: udp_packet.read_domain_name()?
             }),
             RecordType::NS => Ok(Self::NS { domain_name: udp_packet.read_domain_name()? }),
+            RecordType::PTR => Ok(Self::PTR { domain_name: udp_packet.read_domain_name()? }),
             RecordType::SOA => Ok(Self::SOA { 
                 domain_name: udp_packet.read_domain_name()?, 
                 mailbox_address: udp_packet.read_domain_name()?, 
@@ -334,12 +396,23 @@ impl RecordData {
                 retry: udp_packet.read_u32()?, 
                 expire: udp_packet.read_u32()?, 
                 minimum: udp_packet.read_u32()? 
-            })
+            }),
+            RecordType::OPT => Ok(Self::OPT { udp_payload_size: 0, options: udp_packet.read_bytes(length as usize)? }),
+            RecordType::DS
+            | RecordType::RRSIG
+            | RecordType::NSEC
+            | RecordType::DNSKEY
+            | RecordType::NSEC3 => Ok(Self::Opaque { data: udp_packet.read_bytes(length as usize)? })
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+pub const AUTHENTIC_DATA: u16 = 0b010;      // The AD bit of DnsHeader.z (RFC 4035, section 3.2.3)
+pub const CHECKING_DISABLED: u16 = 0b001;   // The CD bit of DnsHeader.z (RFC 4035, section 3.2.2)
+pub const DNSSEC_OK: u32 = 0x8000;          // The DO bit of the TTL field of an OPT record (RFC 3225)
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsHeader {
     pub id: u16, // 16 bits, packet identifier
 
@@ -350,13 +423,18 @@ pub struct DnsHeader {
     pub truncated: bool,                    // 1 bit, set if the message's content has been truncated due to being too long
     pub recursion_desired: bool,            // 1 bit, set if the resolver desires recursive service
     pub recursion_available: bool,          // 1 bit, set if the name server is willing to provide recursive service
-    pub z: u16,                             // 3 bits, reserved and must be unset
+    pub z: u16,                             // 3 bits, the AD and CD bits of DNSSEC and a reserved bit
     pub response_code: ResponseCode,        // 4 bits, indicates the response status of the name server
 
-    // Metadata about the other sections of the DNS message
+    // Metadata about the other sections of the DNS message, not serialised since DnsMessage
+    // recomputes them from the sections when it is deserialised
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub question_count: u16,                // 16 bits
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub answer_count: u16,                  // 16 bits
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub authority_count: u16,               // 16 bits
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub additional_count: u16               // 16 bits
 }
 
@@ -391,15 +469,20 @@ impl Display for DnsHeader {
         write!(f, "flags:")?;
         if self.response {
             write!(f, " qr")?;
-        } if self.authoritative_answer {
+        }
+        if self.authoritative_answer {
             write!(f, " aa")?;
-        }if self.truncated {
+        }
+        if self.truncated {
             write!(f, " tc")?;
-        } if self.recursion_desired {
+        }
+        if self.recursion_desired {
             write!(f, " rd")?;
-        } if self.recursion_available {
+        }
+        if self.recursion_available {
             write!(f, " ra")?;
-        } if self.z != 0 {
+        }
+        if self.z != 0 {
             write!(f, " z")?;
         }
         writeln!(f, ", QUESTION: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
@@ -412,7 +495,7 @@ impl Display for DnsHeader {
 }
 
 impl DnsHeader {
-    fn write_to_udp_packet(&self, udp_packet: &mut udp_packet::UdpPacket) -> udp_packet::Result<()> {
+    fn write_to_udp_packet<const N: usize>(&self, udp_packet: &mut udp_packet::UdpPacket<N>) -> udp_packet::Result<()> {
         if udp_packet.position >= DNS_HEADER_LENGTH_BYTES {
             panic!("DNS header can only be written within bytes 0-11 (DNS_HEADER_LENGTH_BYTES - 1) of DnsMessage.buffer.")
         }
@@ -436,19 +519,25 @@ impl DnsHeader {
         Ok(())
     }
 
-    fn read_from_udp_packet(udp_packet: &mut udp_packet::UdpPacket) -> udp_packet::Result<Self> {
+    fn read_from_udp_packet<const N: usize>(udp_packet: &mut udp_packet::UdpPacket<N>) -> udp_packet::Result<Self> {
         let id = udp_packet.read_u16()?;
         let flag_bytes = udp_packet.read_u16()?;
+        let invalid_value = |field: &str, value: u16| udp_packet::UdpPacketError::InvalidValue { 
+            field: String::from(field), 
+            value 
+        };
         Ok(Self {
             id, 
             response: u16_to_bool((flag_bytes & 0x8000) >> 15), 
-            operation_code: OperationCode::try_from((flag_bytes & 0x7800) >> 11).unwrap(), 
+            operation_code: OperationCode::try_from((flag_bytes & 0x7800) >> 11)
+            .map_err(|_| invalid_value("OPCODE", (flag_bytes & 0x7800) >> 11))?, 
             authoritative_answer: u16_to_bool((flag_bytes & 0x400) >> 10), 
             truncated: u16_to_bool((flag_bytes & 0x200) >> 9), 
             recursion_desired: u16_to_bool((flag_bytes & 0x100) >> 8), 
             recursion_available: u16_to_bool((flag_bytes & 0x80) >> 7), 
             z: (flag_bytes & 0x70) >> 4, 
-            response_code: ResponseCode::try_from(flag_bytes & 0xf).unwrap(), 
+            response_code: ResponseCode::try_from(flag_bytes & 0xf)
+            .map_err(|_| invalid_value("RCODE", flag_bytes & 0xf))?, 
             question_count: udp_packet.read_u16()?, 
             answer_count: udp_packet.read_u16()?, 
             authority_count: udp_packet.read_u16()?, 
@@ -457,7 +546,8 @@ impl DnsHeader {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsQuestion {
     pub name: udp_packet::DomainName,   // Domain name queried
     pub question_type: CombinedType,    // 16 bits, specifies query type
@@ -481,32 +571,57 @@ impl Default for DnsQuestion {
 }
 
 impl DnsQuestion {
-    fn write_to_udp_packet(&self, udp_packet: &mut udp_packet::UdpPacket) -> udp_packet::Result<()> {
+    fn write_to_udp_packet<const N: usize>(&self, udp_packet: &mut udp_packet::UdpPacket<N>) -> udp_packet::Result<()> {
         udp_packet.write_domain_name(&self.name, Some(4))?;
         udp_packet.write_from_slice(&u16_to_u8(self.question_type.try_into().unwrap()), None)?; 
         udp_packet.write_from_slice(&u16_to_u8(self.question_class.try_into().unwrap()), None)?;
         Ok(())
     }
 
-    fn read_from_udp_packet(udp_packet: &mut udp_packet::UdpPacket) -> udp_packet::Result<Self> {
+    fn read_from_udp_packet<const N: usize>(udp_packet: &mut udp_packet::UdpPacket<N>) -> udp_packet::Result<Self> {
+        let name = udp_packet.read_domain_name()?;
+        let question_type = udp_packet.read_u16()?;
+        let question_class = udp_packet.read_u16()?;
         Ok(Self {
-            name: udp_packet.read_domain_name()?,
-            question_type: CombinedType::try_from(udp_packet.read_u16()?).unwrap(),
-            question_class: CombinedClass::try_from(udp_packet.read_u16()?).unwrap()
+            name,
+            question_type: CombinedType::try_from(question_type)
+            .map_err(|_| udp_packet::UdpPacketError::InvalidValue { field: String::from("QTYPE"), value: question_type })?,
+            question_class: CombinedClass::try_from(question_class)
+            .map_err(|_| udp_packet::UdpPacketError::InvalidValue { field: String::from("QCLASS"), value: question_class })?
         })
     }
 }
 
-#[derive(Debug, PartialEq)]
+// The derived implementations are inherent functions, called by the trait implementations below
+// which recompute the length.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(remote = "Self"))]
 pub struct DnsRecord {
     pub name: udp_packet::DomainName,   // Domain name to which the RR belongs
     pub record_type: RecordType,        // 16 bits, specifies RR type and thus the contents of RDATA
     pub record_class: RecordClass,      // 16 bits, specifies the RR's class and thus the class of the contents of RDATA
     pub ttl: u32,                       // 32 bits, Specifies how long (in seconds) the RR can be cached
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub length: u16,                    // 16 bits, Specifies the length (in bytes) of the contents of RDATA
     pub data: RecordData                // The RDATA field, contains the name server's response data
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for DnsRecord {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Self::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DnsRecord {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let mut record = Self::deserialize(deserializer)?;
+        record.length = record.data.as_bytes().len() as u16;
+        Ok(record)
+    }
+}
+
 impl Display for DnsRecord {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{} {} {} {} {}", self.name, self.ttl, self.record_class, self.record_type, self.data)
@@ -514,30 +629,63 @@ impl Display for DnsRecord {
 }
 
 impl DnsRecord {
-    fn write_to_udp_packet(&self, udp_packet: &mut udp_packet::UdpPacket) -> udp_packet::Result<()> {
+    /// Constructs the OPT pseudo-record of EDNS0 (RFC 6891) which advertises `udp_payload_size`,
+    /// with the DO bit set if `dnssec_ok`, i.e. if DNSSEC records are requested.
+    pub fn opt(udp_payload_size: u16, dnssec_ok: bool) -> Self {
+        Self {
+            name: udp_packet::DomainName::root(),
+            record_type: RecordType::OPT,
+            record_class: RecordClass::IN,
+            ttl: if dnssec_ok { DNSSEC_OK } else { 0 },
+            length: 0,
+            data: RecordData::OPT { udp_payload_size, options: Vec::new() }
+        }
+    }
+
+    fn write_to_udp_packet<const N: usize>(&self, udp_packet: &mut udp_packet::UdpPacket<N>) -> udp_packet::Result<()> {
+        // The RDLENGTH is recomputed from the data since records read from a packet may have
+        // contained compressed domain names, which are always written uncompressed.
+        let data = self.data.as_bytes();
+        let record_class = match self.data {
+            RecordData::OPT { udp_payload_size, .. } => udp_payload_size,
+            _ => self.record_class.try_into().unwrap()
+        };
         udp_packet.write_domain_name(&self.name, Some(10))?;
         udp_packet.write_from_slice(&[
             u16_to_u8(self.record_type.try_into().unwrap()).to_vec(), 
-            u16_to_u8(self.record_class.try_into().unwrap()).to_vec(),
+            u16_to_u8(record_class).to_vec(),
             u32_to_u8(self.ttl).to_vec(),
-            u16_to_u8(self.length).to_vec(),
-            self.data.as_bytes()
+            u16_to_u8(data.len() as u16).to_vec(),
+            data
         ].concat(), None)?;
         Ok(())
     }
 
-    fn read_from_udp_packet(udp_packet: &mut udp_packet::UdpPacket) -> udp_packet::Result<Self> {
+    fn read_from_udp_packet<const N: usize>(udp_packet: &mut udp_packet::UdpPacket<N>) -> udp_packet::Result<Self> {
         let name = udp_packet.read_domain_name()?;
-        let record_type = RecordType::try_from(udp_packet.read_u16()?).unwrap();
-        let record_class = RecordClass::try_from(udp_packet.read_u16()?).unwrap();
+        let record_type = udp_packet.read_u16()?;
+        let record_type = RecordType::try_from(record_type)
+        .map_err(|_| udp_packet::UdpPacketError::InvalidValue { field: String::from("TYPE"), value: record_type })?;
+        let class = udp_packet.read_u16()?;
+        // The CLASS of an OPT record is the UDP payload size of the sender (RFC 6891, section 6.1.2).
+        let record_class = match record_type {
+            RecordType::OPT => RecordClass::IN,
+            _ => RecordClass::try_from(class)
+            .map_err(|_| udp_packet::UdpPacketError::InvalidValue { field: String::from("CLASS"), value: class })?
+        };
         let ttl = udp_packet.read_u32()?;
         let length =  udp_packet.read_u16()?;
-        let data = RecordData::read_from_udp_packet(udp_packet, record_type)?;
+        let data = match RecordData::read_from_udp_packet(udp_packet, record_type, length)? {
+            RecordData::OPT { options, .. } => RecordData::OPT { udp_payload_size: class, options },
+            data => data
+        };
         Ok(Self { name, record_type, record_class, ttl, length, data })
     }
 }
 
-#[derive(Debug, PartialEq)]
+// Like for DnsRecord, the trait implementations below call the derived ones and update the counts.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(remote = "Self"))]
 pub struct DnsMessage {
     pub header: DnsHeader,              // 12 bytes, request and section metadata
     pub questions: Vec<DnsQuestion>,    // Question section, contains the relevant queries
@@ -546,6 +694,22 @@ pub struct DnsMessage {
     pub additional: Vec<DnsRecord>      // Additional section, contains additional resources deemed relevant by the name server
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for DnsMessage {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Self::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DnsMessage {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let mut message = Self::deserialize(deserializer)?;
+        message.update_counts();
+        Ok(message)
+    }
+}
+
 impl Default for DnsMessage {
     fn default() -> Self {
         Self { 
@@ -572,7 +736,7 @@ impl Display for DnsMessage {
         // table.push(vec![Some(format!("{}", self.header)), None, None, None, None]);
 
         table.push(vec![None; 5]);
-        table.push(vec![Some(format!("QUESTIONS")), None, None, None, None]);
+        table.push(vec![Some(String::from("QUESTIONS")), None, None, None, None]);
         for question in self.questions.iter() {
             table.push(
                 vec![
@@ -585,9 +749,9 @@ impl Display for DnsMessage {
             );
         }
 
-        if self.answers.len() > 0 {
+        if !self.answers.is_empty() {
             table.push(vec![None; 5]);
-            table.push(vec![Some(format!("ANSWER SECTION")), None, None, None, None]);
+            table.push(vec![Some(String::from("ANSWER SECTION")), None, None, None, None]);
             for answer in self.answers.iter() {
                 table.push(
                     vec![
@@ -601,9 +765,9 @@ impl Display for DnsMessage {
             }
         }
 
-        if self.authorities.len() > 0 {
+        if !self.authorities.is_empty() {
             table.push(vec![None; 5]);
-            table.push(vec![Some(format!("AUTHORITY SECTION")), None, None, None, None]);
+            table.push(vec![Some(String::from("AUTHORITY SECTION")), None, None, None, None]);
             for authority in self.authorities.iter() {
                 table.push(
                     vec![
@@ -617,9 +781,9 @@ impl Display for DnsMessage {
             }
         }
 
-        if self.additional.len() > 0 {
+        if !self.additional.is_empty() {
             table.push(vec![None; 5]);
-            table.push(vec![Some(format!("ADDITIONAL SECTION")), None, None, None, None]);
+            table.push(vec![Some(String::from("ADDITIONAL SECTION")), None, None, None, None]);
             for additional in self.additional.iter() {
                 table.push(
                     vec![
@@ -640,7 +804,34 @@ impl Display for DnsMessage {
 }
 
 impl DnsMessage {
-    pub fn write_to_udp_packet(&self, udp_packet: &mut udp_packet::UdpPacket) -> udp_packet::Result<()> {
+    /// Constructs an empty response to `self`, i.e. a message with the same id, operation code,
+    /// questions and RD flag, with the QR flag set and the given response code.
+    pub fn reply(&self, response_code: ResponseCode) -> Self {
+        let mut response = Self {
+            header: DnsHeader {
+                id: self.header.id,
+                response: true,
+                operation_code: self.header.operation_code,
+                recursion_desired: self.header.recursion_desired,
+                response_code,
+                ..Default::default()
+            },
+            questions: self.questions.to_vec(),
+            ..Default::default()
+        };
+        response.update_counts();
+        response
+    }
+
+    /// Sets the section counts of the header to the lengths of the corresponding sections.
+    pub fn update_counts(&mut self) {
+        self.header.question_count = self.questions.len() as u16;
+        self.header.answer_count = self.answers.len() as u16;
+        self.header.authority_count = self.authorities.len() as u16;
+        self.header.additional_count = self.additional.len() as u16;
+    }
+
+    pub fn write_to_udp_packet<const N: usize>(&self, udp_packet: &mut udp_packet::UdpPacket<N>) -> udp_packet::Result<()> {
         self.header.write_to_udp_packet(udp_packet)?;
         for index in 0..self.header.question_count {
             self.questions[index as usize].write_to_udp_packet(udp_packet)?;
@@ -657,7 +848,92 @@ impl DnsMessage {
         Ok(())
     }
 
-    pub fn read_from_udp_packet(udp_packet: &mut udp_packet::UdpPacket) -> udp_packet::Result<Self> {
+    /// Returns true if `self` is a response to `query`, i.e. if the QR flag is set and both the
+    /// id and the question section match those of the query.
+    pub fn is_response_to(&self, query: &DnsMessage) -> bool {
+        self.header.response
+        && self.header.id == query.header.id
+        && self.questions.len() == query.questions.len()
+        && self.questions.iter().zip(query.questions.iter()).all(|(question, query_question)| {
+            question.name == query_question.name
+            && question.question_type == query_question.question_type
+            && question.question_class == query_question.question_class
+        })
+    }
+
+    /// Returns true if the question names of `self` have exactly the same case as those of `query`,
+    /// which is required of responses to queries with randomised case (see DomainName::randomise_case).
+    pub fn echoes_question_case(&self, query: &DnsMessage) -> bool {
+        self.questions.len() == query.questions.len()
+        && self.questions.iter().zip(query.questions.iter())
+        .all(|(question, query_question)| question.name.eq_case_sensitive(&query_question.name))
+    }
+
+    /// Sends the message as a query to `server` over UDP and waits at most `timeout` for a response.
+    /// The query is sent with a random id from a random source port, and packets which do not come
+    /// from `server` or are not a response to the query (see DnsMessage::is_response_to) are dropped.
+    /// The id of the returned response is set to the id of `self`. A timeout is reported as
+    /// UdpPacketError::NetworkIo.
+    pub fn exchange(&self, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<Self> {
+        self.exchange_inner(server, timeout, false)
+    }
+
+    /// Like DnsMessage::exchange, but the case of the letters in the question names is randomised as
+    /// well ("DNS 0x20"), and responses which do not echo the names with exactly the same case are
+    /// dropped. The questions of the returned response are those of `self`.
+    pub fn exchange_with_case_randomisation(&self, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<Self> {
+        self.exchange_inner(server, timeout, true)
+    }
+
+    fn exchange_inner(&self, server: net::SocketAddr, timeout: Duration, randomise_case: bool) -> udp_packet::Result<Self> {
+        let io_error = |description: &str| {
+            let description = String::from(description);
+            move |error| udp_packet::UdpPacketError::NetworkIo { description, source: error }
+        };
+        let udp_socket = random::bind_random_port(server).map_err(io_error("failed to bind a UdpSocket"))?;
+        let mut query = self.clone();
+        query.header.id = random::random_u16();
+        if randomise_case {
+            for question in query.questions.iter_mut() {
+                question.name = question.name.randomise_case();
+            }
+        }
+
+        let mut udp_packet = udp_packet::UdpPacket::new();
+        query.write_to_udp_packet(&mut udp_packet)?;
+        udp_packet.send_to(&udp_socket, server)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Unrelated packets must not extend the time spent waiting for the response.
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(udp_packet::UdpPacketError::NetworkIo {
+                    description: String::from("timed out while waiting for a response"),
+                    source: std::io::Error::from(std::io::ErrorKind::TimedOut)
+                });
+            }
+            udp_socket.set_read_timeout(Some(remaining)).map_err(io_error("failed to set the read timeout"))?;
+            let mut response_packet = udp_packet::UdpPacket::new();
+            let (_, source) = response_packet.recv_from(&udp_socket)?;
+            if source != server {
+                log::debug!("ignoring a packet from {} while waiting for {}", source, server);
+                continue;
+            }
+            match Self::read_from_udp_packet(&mut response_packet) {
+                Ok(mut response) if response.is_response_to(&query) && (!randomise_case || response.echoes_question_case(&query)) => {
+                    response.header.id = self.header.id;
+                    if randomise_case {
+                        response.questions = self.questions.clone();
+                    }
+                    return Ok(response);
+                },
+                Ok(_) => log::debug!("ignoring a packet from {} which does not match query {}", source, query.header.id),
+                Err(error) => log::debug!("ignoring a malformed packet from {}: {}", source, error)
+            }
+        }
+    }
+
+    pub fn read_from_udp_packet<const N: usize>(udp_packet: &mut udp_packet::UdpPacket<N>) -> udp_packet::Result<Self> {
         let header = DnsHeader::read_from_udp_packet(udp_packet)?;
         let mut questions: Vec<DnsQuestion> = Vec::new();
         let mut answers: Vec<DnsRecord> = Vec::new();
@@ -789,4 +1065,133 @@ mod tests {
             }
         )
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn exchange_validation_test() {
+        let server = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let spoofer = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let server_address = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut udp_packet = udp_packet::UdpPacket::new();
+            let (_, client) = udp_packet.recv_from(&server).unwrap();
+            let query = DnsMessage::read_from_udp_packet(&mut udp_packet).unwrap();
+            let send = |socket: &net::UdpSocket, response: &DnsMessage| {
+                let mut udp_packet = udp_packet::UdpPacket::new();
+                response.write_to_udp_packet(&mut udp_packet).unwrap();
+                udp_packet.send_to(socket, client).unwrap();
+            };
+            // Only the last response is valid, the others are expected to be dropped.
+            let spoofed = query.reply(ResponseCode::REFUSED);
+            send(&spoofer, &spoofed);
+            let mut wrong_id = query.reply(ResponseCode::REFUSED);
+            wrong_id.header.id = query.header.id.wrapping_add(1);
+            send(&server, &wrong_id);
+            let mut wrong_question = query.reply(ResponseCode::REFUSED);
+            wrong_question.questions[0].name = udp_packet::DomainName::from_str("example.net").unwrap();
+            send(&server, &wrong_question);
+            send(&server, &query.reply(ResponseCode::NOERROR));
+        });
+
+        let mut query = DnsMessage::default();
+        query.header.id = 1234;
+        let response = query.exchange(server_address, Duration::from_secs(5)).expect("Failed to exchange.");
+        handle.join().unwrap();
+        assert_eq!(response.header.id, 1234);
+        assert_eq!(response.header.response_code, ResponseCode::NOERROR);
+    }
+
+    #[test]
+    fn case_randomisation_test() {
+        let server = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let server_address = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut udp_packet = udp_packet::UdpPacket::new();
+            let (_, client) = udp_packet.recv_from(&server).unwrap();
+            let query = DnsMessage::read_from_udp_packet(&mut udp_packet).unwrap();
+            let send = |response: &DnsMessage| {
+                let mut udp_packet = udp_packet::UdpPacket::new();
+                response.write_to_udp_packet(&mut udp_packet).unwrap();
+                udp_packet.send_to(&server, client).unwrap();
+            };
+            // A response whose question differs from the query in case only must be dropped.
+            let mut flipped = query.reply(ResponseCode::REFUSED);
+            for byte in flipped.questions[0].name.bytes.iter_mut().filter(|byte| byte.is_ascii_alphabetic()) {
+                *byte ^= 0x20;
+            }
+            send(&flipped);
+            send(&query.reply(ResponseCode::NOERROR));
+        });
+
+        let mut query = DnsMessage::default();
+        query.questions[0].name = udp_packet::DomainName::from_str("www.example.com").unwrap();
+        let response = query.exchange_with_case_randomisation(server_address, Duration::from_secs(5))
+        .expect("Failed to exchange.");
+        handle.join().unwrap();
+        assert_eq!(response.header.response_code, ResponseCode::NOERROR);
+        assert!(response.questions[0].name.eq_case_sensitive(&query.questions[0].name));
+    }
+
+    #[test]
+    fn edns_test() {
+        let mut message = DnsMessage::default();
+        message.answers.push(DnsRecord {
+            name: udp_packet::DomainName::from_str(TEST_DOMAIN).unwrap(),
+            record_type: RecordType::RRSIG,
+            record_class: RecordClass::IN,
+            ttl: 3600,
+            length: 4,
+            data: RecordData::Opaque { data: vec![0, 1, 0xfe, 0xff] }
+        });
+        message.additional.push(DnsRecord::opt(1232, true));
+        message.update_counts();
+        let mut udp_packet = udp_packet::UdpPacket::new();
+        message.write_to_udp_packet(&mut udp_packet).unwrap();
+        udp_packet.position = 0;
+        let decoded = DnsMessage::read_from_udp_packet(&mut udp_packet).unwrap();
+        assert_eq!(decoded.answers[0].data, message.answers[0].data);
+        assert_eq!(decoded.answers[0].data.to_string(), "\\# 4 0001feff");
+        let opt = &decoded.additional[0];
+        assert_eq!(opt.ttl & DNSSEC_OK, DNSSEC_OK);
+        assert_eq!(opt.data, RecordData::OPT { udp_payload_size: 1232, options: Vec::new() });
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_test() {
+        let name = udp_packet::DomainName::from_str(TEST_DOMAIN).unwrap();
+        let mut message = DnsMessage::default();
+        message.questions[0].name = name.clone();
+        message.answers.push(DnsRecord {
+            name: name.clone(),
+            record_type: RecordType::MX,
+            record_class: RecordClass::IN,
+            ttl: 300,
+            length: 15,
+            data: RecordData::MX { preference: 10, exchange_address: name }
+        });
+        message.update_counts();
+
+        let mut value = serde_json::to_value(&message).expect("Failed to serialize.");
+        assert_eq!(value["header"]["response_code"], "NOERROR");
+        assert_eq!(value["questions"][0]["name"], "example.com.");
+        assert_eq!(value["questions"][0]["question_type"], "A");
+        assert_eq!(value["answers"][0]["record_type"], "MX");
+        assert_eq!(value["answers"][0]["data"]["MX"]["exchange_address"], "example.com.");
+        assert!(value["header"].get("answer_count").is_none());
+        assert!(value["answers"][0].get("length").is_none());
+        let deserialized: DnsMessage = serde_json::from_value(value.clone()).expect("Failed to deserialize.");
+        assert_eq!(deserialized, message);
+
+        // The counts and lengths are recomputed, so they cannot contradict the sections.
+        value["header"]["answer_count"] = serde_json::json!(7);
+        value["answers"][0]["length"] = serde_json::json!(1000);
+        let deserialized: DnsMessage = serde_json::from_value(value).expect("Failed to deserialize.");
+        assert_eq!(deserialized, message);
+
+        let value = serde_json::json!({ "name": "example.com", "question_type": "AAAA", "question_class": "ANY" });
+        let question: DnsQuestion = serde_json::from_value(value).unwrap();
+        assert_eq!(question.question_class, CombinedClass::QuestionClass(QuestionClass::ANY));
+        let value = serde_json::json!({ "name": "example.com", "question_type": "XYZ", "question_class": "IN" });
+        assert!(serde_json::from_value::<DnsQuestion>(value).is_err());
+    }
+}
@@ -0,0 +1,795 @@
+use crate::build_enum;
+use crate::dns_message::DnsMessage;
+use crate::transport::Transport;
+use crate::udp_packet::{self, TcpPacket};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The content type of dnstap Frame Streams.
+pub const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// The prefix of DnstapSink::open destinations which are unix sockets rather than files.
+pub const UNIX_PREFIX: &str = "unix:";
+
+// Frame Streams control frame types and fields, see https://farsightsec.github.io/fstrm/.
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+const MAX_CONTROL_FRAME_LENGTH: usize = 512;        // The limit of the reference implementation, fstrm
+const MAX_DATA_FRAME_LENGTH: usize = 1 << 20;       // Far more than any dnstap message of a DNS message
+pub const QUEUE_LENGTH: usize = 1024;               // The number of messages waiting to be written before further ones are dropped
+
+// Protocol buffer wire types.
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_BYTES: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+const DNSTAP_TYPE_MESSAGE: u64 = 1;     // Dnstap.Type.MESSAGE, the only type defined
+const SOCKET_FAMILY_INET: u64 = 1;
+const SOCKET_FAMILY_INET6: u64 = 2;
+
+build_enum!(
+    MessageType;
+    AUTHQUERY = 1,              // A query received by an authoritative server
+    AUTHRESPONSE = 2,
+    RESOLVERQUERY = 3,         // A query sent by a resolver
+    RESOLVERRESPONSE = 4,
+    CLIENTQUERY = 5,           // A query received by a server from a client
+    CLIENTRESPONSE = 6,
+    FORWARDERQUERY = 7,        // A query sent by a forwarder to its upstreams
+    FORWARDERRESPONSE = 8,
+    STUBQUERY = 9,              // A query sent by a stub resolver
+    STUBRESPONSE = 10,
+    TOOLQUERY = 11,            // A query sent by a tool such as dig
+    TOOLRESPONSE = 12
+);
+
+build_enum!(
+    SocketProtocol;
+    UDP = 1,
+    TCP = 2,
+    DOT = 3,                    // DNS over TLS
+    DOH = 4                     // DNS over HTTPS
+);
+
+/// Error handling type for dnstap and Frame Streams.
+#[derive(Debug)]
+pub enum DnstapError {
+    /// Reading from or writing to the stream failed.
+    Io {
+        description: String,        // What was being done.
+        source: io::Error           // The underlying error.
+    },
+
+    /// The peer does not follow the Frame Streams protocol, e.g. by sending an unexpected control frame.
+    Protocol {
+        description: String         // What went wrong.
+    },
+
+    /// A frame is not a valid dnstap message.
+    Malformed {
+        description: String         // What went wrong.
+    }
+}
+
+impl std::fmt::Display for DnstapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io {
+                description,
+                source
+            } => write!(f, "{}, source: {}", description, source),
+            Self::Protocol {
+                description
+            } => write!(f, "Frame Streams protocol error: {}", description),
+            Self::Malformed {
+                description
+            } => write!(f, "malformed dnstap message: {}", description)
+        }
+    }
+}
+
+impl std::error::Error for DnstapError {}
+
+/// Specialised result type for dnstap and Frame Streams.
+pub type Result<T> = std::result::Result<T, DnstapError>;
+
+fn io_error(description: &str) -> impl FnOnce(io::Error) -> DnstapError {
+    let description = String::from(description);
+    move |source| DnstapError::Io { description, source }
+}
+
+fn protocol_error(description: &str) -> DnstapError {
+    DnstapError::Protocol { description: String::from(description) }
+}
+
+fn malformed(description: &str) -> DnstapError {
+    DnstapError::Malformed { description: String::from(description) }
+}
+
+/// A dnstap Message, i.e. a query or response seen by a server or resolver, with the messages in
+/// wire format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub message_type: MessageType,
+    pub socket_protocol: Option<SocketProtocol>,
+    pub query_address: Option<net::SocketAddr>,         // The initiator of the query, e.g. the client of a server
+    pub response_address: Option<net::SocketAddr>,      // The responder, e.g. the name server queried by a resolver
+    pub query_time: Option<SystemTime>,
+    pub query_message: Option<Vec<u8>>,
+    pub response_time: Option<SystemTime>,
+    pub response_message: Option<Vec<u8>>
+}
+
+impl Message {
+    pub fn new(message_type: MessageType) -> Self {
+        Self {
+            message_type,
+            socket_protocol: None,
+            query_address: None,
+            response_address: None,
+            query_time: None,
+            query_message: None,
+            response_time: None,
+            response_message: None
+        }
+    }
+}
+
+/// A dnstap Dnstap, i.e. the payload of a data frame, see https://dnstap.info/.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dnstap {
+    pub identity: Option<Vec<u8>>,      // The name of the server
+    pub version: Option<Vec<u8>>,       // The software and version of the server
+    pub message: Message
+}
+
+impl Dnstap {
+    /// Encodes `self` as a protocol buffer.
+    pub fn encode(&self) -> Vec<u8> {
+        let message = &self.message;
+        let mut encoded = Vec::new();
+        put_varint_field(&mut encoded, 1, TryInto::<u16>::try_into(message.message_type).unwrap() as u64);
+        match message.query_address.or(message.response_address) {
+            Some(net::SocketAddr::V4(_)) => put_varint_field(&mut encoded, 2, SOCKET_FAMILY_INET),
+            Some(net::SocketAddr::V6(_)) => put_varint_field(&mut encoded, 2, SOCKET_FAMILY_INET6),
+            None => ()
+        }
+        if let Some(socket_protocol) = message.socket_protocol {
+            put_varint_field(&mut encoded, 3, TryInto::<u16>::try_into(socket_protocol).unwrap() as u64);
+        }
+        for (address, address_field, port_field) in [(message.query_address, 4, 6), (message.response_address, 5, 7)] {
+            if let Some(address) = address {
+                match address.ip() {
+                    net::IpAddr::V4(ip) => put_bytes_field(&mut encoded, address_field, &ip.octets()),
+                    net::IpAddr::V6(ip) => put_bytes_field(&mut encoded, address_field, &ip.octets())
+                }
+                put_varint_field(&mut encoded, port_field, address.port() as u64);
+            }
+        }
+        for (time, seconds_field, nanoseconds_field) in [(message.query_time, 8, 9), (message.response_time, 12, 13)] {
+            if let Some(time) = time {
+                let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                put_varint_field(&mut encoded, seconds_field, time.as_secs());
+                put_fixed32_field(&mut encoded, nanoseconds_field, time.subsec_nanos());
+            }
+        }
+        if let Some(query_message) = &message.query_message {
+            put_bytes_field(&mut encoded, 10, query_message);
+        }
+        if let Some(response_message) = &message.response_message {
+            put_bytes_field(&mut encoded, 14, response_message);
+        }
+
+        let mut dnstap = Vec::new();
+        if let Some(identity) = &self.identity {
+            put_bytes_field(&mut dnstap, 1, identity);
+        }
+        if let Some(version) = &self.version {
+            put_bytes_field(&mut dnstap, 2, version);
+        }
+        put_bytes_field(&mut dnstap, 14, &encoded);
+        put_varint_field(&mut dnstap, 15, DNSTAP_TYPE_MESSAGE);
+        dnstap
+    }
+
+    /// Decodes a protocol buffer written by Dnstap::encode, or by other dnstap implementations.
+    /// Fields which are not represented in Message are skipped.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut identity = None;
+        let mut version = None;
+        let mut message = None;
+        let mut dnstap_type = None;
+        for (field, value) in fields(bytes)? {
+            match (field, value) {
+                (1, Value::Bytes(bytes)) => identity = Some(bytes.to_vec()),
+                (2, Value::Bytes(bytes)) => version = Some(bytes.to_vec()),
+                (14, Value::Bytes(bytes)) => message = Some(decode_message(bytes)?),
+                (15, Value::Varint(value)) => dnstap_type = Some(value),
+                _ => ()
+            }
+        }
+        if dnstap_type != Some(DNSTAP_TYPE_MESSAGE) {
+            return Err(malformed("the type is not MESSAGE"));
+        }
+        Ok(Self { identity, version, message: message.ok_or_else(|| malformed("the message is missing"))? })
+    }
+}
+
+fn decode_message(bytes: &[u8]) -> Result<Message> {
+    let mut message = Message::new(MessageType::default());
+    let mut message_type = None;
+    let mut addresses: [Option<net::IpAddr>; 2] = [None, None];    // The query and response addresses
+    let mut ports = [0, 0];
+    let mut seconds: [Option<u64>; 2] = [None, None];               // The query and response times
+    let mut nanoseconds = [0, 0];
+    for (field, value) in fields(bytes)? {
+        match (field, value) {
+            (1, Value::Varint(value)) => {
+                message_type = Some(u16::try_from(value).ok()
+                .and_then(|value| MessageType::try_from(value).ok())
+                .ok_or_else(|| malformed("unknown message type"))?);
+            },
+            (3, Value::Varint(value)) => {
+                message.socket_protocol = u16::try_from(value).ok().and_then(|value| SocketProtocol::try_from(value).ok());
+            },
+            (4 | 5, Value::Bytes(bytes)) => {
+                let address = match bytes.len() {
+                    4 => net::IpAddr::from(<[u8; 4]>::try_from(bytes).unwrap()),
+                    16 => net::IpAddr::from(<[u8; 16]>::try_from(bytes).unwrap()),
+                    _ => return Err(malformed("an address is neither 4 nor 16 bytes long"))
+                };
+                addresses[field as usize - 4] = Some(address);
+            },
+            (6 | 7, Value::Varint(value)) => {
+                ports[field as usize - 6] = u16::try_from(value).map_err(|_| malformed("a port does not fit in 16 bits"))?;
+            },
+            (8, Value::Varint(value)) => seconds[0] = Some(value),
+            (12, Value::Varint(value)) => seconds[1] = Some(value),
+            (9, Value::Fixed32(value)) => nanoseconds[0] = value,
+            (13, Value::Fixed32(value)) => nanoseconds[1] = value,
+            (10, Value::Bytes(bytes)) => message.query_message = Some(bytes.to_vec()),
+            (14, Value::Bytes(bytes)) => message.response_message = Some(bytes.to_vec()),
+            _ => ()
+        }
+    }
+    message.message_type = message_type.ok_or_else(|| malformed("the message type is missing"))?;
+    message.query_address = addresses[0].map(|address| net::SocketAddr::new(address, ports[0]));
+    message.response_address = addresses[1].map(|address| net::SocketAddr::new(address, ports[1]));
+    let time = |seconds: Option<u64>, nanoseconds: u32| seconds.map(|seconds| UNIX_EPOCH + Duration::new(seconds, nanoseconds));
+    message.query_time = time(seconds[0], nanoseconds[0]);
+    message.response_time = time(seconds[1], nanoseconds[1]);
+    Ok(message)
+}
+
+/// A field of a protocol buffer.
+enum Value<'a> {
+    Varint(u64),
+    Fixed32(u32),
+    Fixed64,                // Skipped, since no field of dnstap uses it
+    Bytes(&'a [u8])
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn put_varint_field(buffer: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buffer, field << 3 | WIRE_VARINT);
+    put_varint(buffer, value);
+}
+
+fn put_fixed32_field(buffer: &mut Vec<u8>, field: u64, value: u32) {
+    put_varint(buffer, field << 3 | WIRE_FIXED32);
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes_field(buffer: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buffer, field << 3 | WIRE_BYTES);
+    put_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn get_varint(bytes: &[u8], position: &mut usize) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*position).ok_or_else(|| malformed("a varint is truncated"))?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(malformed("a varint is longer than 10 bytes"))
+}
+
+fn get_slice<'a>(bytes: &'a [u8], position: &mut usize, length: usize) -> Result<&'a [u8]> {
+    let slice = position.checked_add(length)
+    .and_then(|end| bytes.get(*position..end))
+    .ok_or_else(|| malformed("a field is truncated"))?;
+    *position += length;
+    Ok(slice)
+}
+
+/// Splits a protocol buffer into its fields, in order.
+fn fields(bytes: &[u8]) -> Result<Vec<(u64, Value<'_>)>> {
+    let mut fields = Vec::new();
+    let mut position = 0;
+    while position < bytes.len() {
+        let key = get_varint(bytes, &mut position)?;
+        let value = match key & 0x07 {
+            WIRE_VARINT => Value::Varint(get_varint(bytes, &mut position)?),
+            WIRE_FIXED64 => {
+                get_slice(bytes, &mut position, 8)?;
+                Value::Fixed64
+            },
+            WIRE_BYTES => {
+                let length = get_varint(bytes, &mut position)?;
+                Value::Bytes(get_slice(bytes, &mut position, usize::try_from(length).unwrap_or(usize::MAX))?)
+            },
+            WIRE_FIXED32 => Value::Fixed32(u32::from_le_bytes(get_slice(bytes, &mut position, 4)?.try_into().unwrap())),
+            _ => return Err(malformed("unsupported wire type"))
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+fn write_control<W: Write + ?Sized>(writer: &mut W, control_type: u32, content_type: Option<&[u8]>) -> Result<()> {
+    let mut frame = control_type.to_be_bytes().to_vec();
+    if let Some(content_type) = content_type {
+        frame.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        frame.extend_from_slice(&(content_type.len() as u32).to_be_bytes());
+        frame.extend_from_slice(content_type);
+    }
+    writer.write_all(&[&0u32.to_be_bytes()[..], &(frame.len() as u32).to_be_bytes(), &frame].concat())
+    .and_then(|_| writer.flush())
+    .map_err(io_error("failed to write a control frame"))
+}
+
+fn read_u32<R: Read + ?Sized>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Reads a control frame whose escape sequence has already been read, and returns its type and
+/// content types.
+fn read_control_body<R: Read + ?Sized>(reader: &mut R) -> Result<(u32, Vec<Vec<u8>>)> {
+    let length = read_u32(reader).map_err(io_error("failed to read a control frame"))? as usize;
+    if !(4..=MAX_CONTROL_FRAME_LENGTH).contains(&length) {
+        return Err(protocol_error("invalid control frame length"));
+    }
+    let mut frame = vec![0; length];
+    reader.read_exact(&mut frame).map_err(io_error("failed to read a control frame"))?;
+    let control_type = u32::from_be_bytes(frame[..4].try_into().unwrap());
+    let mut content_types = Vec::new();
+    let mut fields = &frame[4..];
+    while !fields.is_empty() {
+        let header = fields.get(..8).ok_or_else(|| protocol_error("truncated control field"))?;
+        let field_type = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let field_length = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+        let value = fields.get(8..8 + field_length).ok_or_else(|| protocol_error("truncated control field"))?;
+        if field_type == FIELD_CONTENT_TYPE {
+            content_types.push(value.to_vec());
+        }
+        fields = &fields[8 + field_length..];
+    }
+    Ok((control_type, content_types))
+}
+
+/// Reads a control frame, which must be of type `expected`, and checks that it allows CONTENT_TYPE.
+fn expect_control<R: Read + ?Sized>(reader: &mut R, expected: u32, name: &str) -> Result<()> {
+    if read_u32(reader).map_err(io_error("failed to read a control frame"))? != 0 {
+        return Err(protocol_error(&format!("expected a {} control frame, received a data frame", name)));
+    }
+    let (control_type, content_types) = read_control_body(reader)?;
+    if control_type != expected {
+        return Err(protocol_error(&format!("expected a {} control frame, received type {}", name, control_type)));
+    }
+    // Frames without content types are allowed, and mean that any content type is acceptable.
+    if !content_types.is_empty() && !content_types.iter().any(|content_type| content_type == CONTENT_TYPE) {
+        return Err(protocol_error("the peer does not accept dnstap"));
+    }
+    Ok(())
+}
+
+/// The sending end of a Frame Streams stream of dnstap messages. The stream is stopped when the
+/// writer is finished or dropped.
+pub struct FrameStreamWriter<W: Write> {
+    writer: W,
+    acknowledgements: Option<Box<dyn Read + Send>>,     // The other direction of a bidirectional stream
+    stopped: bool
+}
+
+impl<W: Write> FrameStreamWriter<W> {
+    /// Starts a unidirectional stream, e.g. to a file.
+    pub fn new(mut writer: W) -> Result<Self> {
+        write_control(&mut writer, CONTROL_START, Some(CONTENT_TYPE))?;
+        Ok(Self { writer, acknowledgements: None, stopped: false })
+    }
+
+    /// Starts a bidirectional stream, e.g. over a socket, where the reader has to accept the
+    /// stream before it is started and acknowledges that it is stopped.
+    pub fn handshake<R: Read + Send + 'static>(mut writer: W, mut reader: R) -> Result<Self> {
+        write_control(&mut writer, CONTROL_READY, Some(CONTENT_TYPE))?;
+        expect_control(&mut reader, CONTROL_ACCEPT, "ACCEPT")?;
+        write_control(&mut writer, CONTROL_START, Some(CONTENT_TYPE))?;
+        Ok(Self { writer, acknowledgements: Some(Box::new(reader)), stopped: false })
+    }
+
+    pub fn write_frame(&mut self, data: &[u8]) -> Result<()> {
+        // A length of 0 would be read as the escape sequence of a control frame.
+        if data.is_empty() || data.len() > MAX_DATA_FRAME_LENGTH {
+            return Err(protocol_error("invalid data frame length"));
+        }
+        self.writer.write_all(&[&(data.len() as u32).to_be_bytes()[..], data].concat())
+        .and_then(|_| self.writer.flush())
+        .map_err(io_error("failed to write a data frame"))
+    }
+
+    /// Stops the stream, waiting for the acknowledgement of the reader of a bidirectional stream.
+    pub fn finish(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.stopped = true;
+        write_control(&mut self.writer, CONTROL_STOP, None)?;
+        match self.acknowledgements.as_mut() {
+            Some(reader) => expect_control(reader.as_mut(), CONTROL_FINISH, "FINISH"),
+            None => Ok(())
+        }
+    }
+}
+
+impl<W: Write> Drop for FrameStreamWriter<W> {
+    fn drop(&mut self) {
+        if let Err(error) = self.stop() {
+            log::warn!("failed to stop a dnstap stream: {}", error);
+        }
+    }
+}
+
+/// The receiving end of a Frame Streams stream of dnstap messages, see FrameStreamWriter.
+pub struct FrameStreamReader<R: Read> {
+    reader: R,
+    acknowledgements: Option<Box<dyn Write + Send>>,    // The other direction of a bidirectional stream
+    stopped: bool
+}
+
+impl<R: Read> FrameStreamReader<R> {
+    /// Reads the start of a unidirectional stream, e.g. from a file.
+    pub fn new(mut reader: R) -> Result<Self> {
+        expect_control(&mut reader, CONTROL_START, "START")?;
+        Ok(Self { reader, acknowledgements: None, stopped: false })
+    }
+
+    /// Accepts a bidirectional stream, e.g. over a socket, see FrameStreamWriter::handshake.
+    pub fn accept<W: Write + Send + 'static>(mut reader: R, mut writer: W) -> Result<Self> {
+        expect_control(&mut reader, CONTROL_READY, "READY")?;
+        write_control(&mut writer, CONTROL_ACCEPT, Some(CONTENT_TYPE))?;
+        expect_control(&mut reader, CONTROL_START, "START")?;
+        Ok(Self { reader, acknowledgements: Some(Box::new(writer)), stopped: false })
+    }
+
+    /// Reads the next data frame, or returns None once the writer has stopped the stream.
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.stopped {
+            return Ok(None);
+        }
+        let length = read_u32(&mut self.reader).map_err(io_error("failed to read a frame"))? as usize;
+        if length == 0 {
+            let (control_type, _) = read_control_body(&mut self.reader)?;
+            if control_type != CONTROL_STOP {
+                return Err(protocol_error(&format!("unexpected control frame of type {}", control_type)));
+            }
+            self.stopped = true;
+            if let Some(writer) = self.acknowledgements.as_mut() {
+                write_control(writer.as_mut(), CONTROL_FINISH, None)?;
+            }
+            return Ok(None);
+        }
+        if length > MAX_DATA_FRAME_LENGTH {
+            return Err(protocol_error("data frame too large"));
+        }
+        let mut frame = vec![0; length];
+        self.reader.read_exact(&mut frame).map_err(io_error("failed to read a frame"))?;
+        Ok(Some(frame))
+    }
+}
+
+/// A destination of dnstap messages shared by servers and transports, which writes every message to
+/// a Frame Streams stream. The messages are written by a thread of their own, so that a slow
+/// destination does not delay queries. Messages are dropped when QUEUE_LENGTH of them are waiting,
+/// and failures to write are logged rather than returned, since neither must affect the resolution
+/// of queries. Dropping the sink waits for the waiting messages to be written and stops the stream.
+pub struct DnstapSink {
+    sender: Option<mpsc::SyncSender<Vec<u8>>>,     // Encoded messages for the writer, None once dropped
+    writer: Option<thread::JoinHandle<()>>,
+    identity: Option<Vec<u8>>,
+    version: Option<Vec<u8>>
+}
+
+impl DnstapSink {
+    pub fn new(mut writer: FrameStreamWriter<Box<dyn Write + Send>>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(QUEUE_LENGTH);
+        let writer = thread::spawn(move || {
+            for frame in receiver {
+                if let Err(error) = writer.write_frame(&frame) {
+                    log::warn!("failed to write a dnstap message: {}", error);
+                }
+            }
+        });
+        let version = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        Self { sender: Some(sender), writer: Some(writer), identity: None, version: Some(version.into_bytes()) }
+    }
+
+    /// Creates (or truncates) a file and starts a unidirectional stream to it.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = fs::File::create(path).map_err(io_error("failed to create the dnstap file"))?;
+        Ok(Self::new(FrameStreamWriter::new(Box::new(file) as Box<dyn Write + Send>)?))
+    }
+
+    /// Connects to a Frame Streams reader listening on a unix socket, e.g. a dnstap collector.
+    #[cfg(unix)]
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let stream = UnixStream::connect(path).map_err(io_error("failed to connect to the dnstap socket"))?;
+        let reader = stream.try_clone().map_err(io_error("failed to clone the dnstap socket"))?;
+        Ok(Self::new(FrameStreamWriter::handshake(Box::new(stream) as Box<dyn Write + Send>, reader)?))
+    }
+
+    /// Opens a destination given as a path, or as UNIX_PREFIX followed by the path of a unix socket.
+    pub fn open(destination: &str) -> Result<Self> {
+        match destination.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => Self::connect(path),
+            #[cfg(not(unix))]
+            Some(_) => Err(protocol_error("unix sockets are not supported on this platform")),
+            None => Self::create(destination)
+        }
+    }
+
+    /// Sets the identity of the messages, e.g. the host name of the server. None by default.
+    pub fn set_identity(&mut self, identity: Option<Vec<u8>>) {
+        self.identity = identity;
+    }
+
+    /// Sets the version of the messages, which is the name and version of this crate by default.
+    pub fn set_version(&mut self, version: Option<Vec<u8>>) {
+        self.version = version;
+    }
+
+    pub fn log(&self, message: Message) {
+        let dnstap = Dnstap { identity: self.identity.clone(), version: self.version.clone(), message };
+        let sender = self.sender.as_ref().unwrap();
+        match sender.try_send(dnstap.encode()) {
+            Ok(()) => (),
+            Err(mpsc::TrySendError::Full(_)) => log::warn!("dropping a dnstap message, {} messages are waiting to be written", QUEUE_LENGTH),
+            Err(mpsc::TrySendError::Disconnected(_)) => log::warn!("dropping a dnstap message, the writer has stopped")
+        }
+    }
+}
+
+impl Drop for DnstapSink {
+    fn drop(&mut self) {
+        // Closing the channel ends the writer thread once it has written the waiting messages.
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                log::warn!("the dnstap writer panicked");
+            }
+        }
+    }
+}
+
+/// Encodes a message in wire format, for inclusion in a dnstap message.
+pub fn wire_format(message: &DnsMessage) -> Option<Vec<u8>> {
+    let mut tcp_packet = Box::new(TcpPacket::empty());
+    message.write_to_udp_packet(&mut tcp_packet).ok()?;
+    Some(tcp_packet.buffer[..tcp_packet.position].to_vec())
+}
+
+/// A Transport which logs every query and response of another transport to a DnstapSink. The id of
+/// the logged query is the one passed to the transport, which may choose another one on the wire.
+pub struct DnstapTransport<T> {
+    inner: T,
+    sink: Arc<DnstapSink>,
+    message_type: MessageType,              // The type of queries, e.g. RESOLVERQUERY, responses are of the next type
+    socket_protocol: SocketProtocol
+}
+
+impl<T: Transport> DnstapTransport<T> {
+    pub fn new(inner: T, sink: Arc<DnstapSink>, message_type: MessageType, socket_protocol: SocketProtocol) -> Self {
+        Self { inner, sink, message_type, socket_protocol }
+    }
+}
+
+impl<T: Transport> Transport for DnstapTransport<T> {
+    fn exchange(&self, query: &DnsMessage, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<DnsMessage> {
+        let mut message = Message {
+            socket_protocol: Some(self.socket_protocol),
+            response_address: Some(server),
+            query_time: Some(SystemTime::now()),
+            query_message: wire_format(query),
+            ..Message::new(self.message_type)
+        };
+        self.sink.log(message.clone());
+        let result = self.inner.exchange(query, server, timeout);
+        if let Ok(response) = &result {
+            let response_type = TryInto::<u16>::try_into(self.message_type).unwrap() + 1;
+            message.message_type = MessageType::try_from(response_type).unwrap_or(self.message_type);
+            message.response_time = Some(SystemTime::now());
+            message.response_message = wire_format(response);
+            self.sink.log(message);
+        }
+        result
+    }
+}
+
+/// Creates a sink writing to memory, and a function which waits for at least the given number of
+/// messages to be written, returning them. Used by the tests of the servers.
+#[cfg(test)]
+pub(crate) fn memory_sink() -> (Arc<DnstapSink>, impl Fn(usize) -> Vec<Message>) {
+    struct MemoryWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for MemoryWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let bytes = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let writer = MemoryWriter(Arc::clone(&bytes));
+    let sink = Arc::new(DnstapSink::new(FrameStreamWriter::new(Box::new(writer) as Box<dyn Write + Send>).unwrap()));
+    let messages = move |count: usize| {
+        for _ in 0..500 {
+            let bytes = bytes.lock().unwrap().clone();
+            let mut reader = FrameStreamReader::new(&bytes[..]).unwrap();
+            let mut messages = Vec::new();
+            // The stream has not been stopped, so reading ends with an error rather than None.
+            while let Ok(Some(frame)) = reader.read_frame() {
+                messages.push(Dnstap::decode(&frame).unwrap().message);
+            }
+            if messages.len() >= count {
+                return messages;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("fewer than {} dnstap messages were written", count);
+    };
+    (sink, messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dnstap::*;
+    use crate::transport::{MockAction, MockTransport};
+    use crate::dns_message::{DnsQuestion, ResponseCode};
+    use std::sync::Mutex;
+
+    #[test]
+    fn encode_test() {
+        let dnstap = Dnstap {
+            identity: Some(b"ns1".to_vec()),
+            version: None,
+            message: Message {
+                socket_protocol: Some(SocketProtocol::UDP),
+                query_address: Some(net::SocketAddr::from(([192, 0, 2, 1], 53000))),
+                response_address: Some(net::SocketAddr::from(([192, 0, 2, 53], 53))),
+                query_time: Some(UNIX_EPOCH + Duration::new(1700000000, 5)),
+                query_message: Some(vec![0xab; 3]),
+                ..Message::new(MessageType::CLIENTQUERY)
+            }
+        };
+        let encoded = dnstap.encode();
+        assert_eq!(&encoded[..5], &[0x0a, 0x03, b'n', b's', b'1']);
+        assert_eq!(&encoded[encoded.len() - 2..], &[0x78, 0x01]);
+        assert_eq!(Dnstap::decode(&encoded).unwrap(), dnstap);
+        assert!(Dnstap::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_test() {
+        use std::os::unix::net::UnixListener;
+        let path = std::env::temp_dir().join(format!("dns_resolver_dnstap_test_{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let reader = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = FrameStreamReader::accept(stream.try_clone().unwrap(), stream).unwrap();
+            let mut frames = Vec::new();
+            while let Some(frame) = reader.read_frame().unwrap() {
+                frames.push(Dnstap::decode(&frame).unwrap());
+            }
+            frames
+        });
+
+        let transport = MockTransport::new();
+        transport.script(DnsQuestion::default(), vec![MockAction::Fail(ResponseCode::NAMEERROR)]);
+        let sink = Arc::new(DnstapSink::connect(&path).unwrap());
+        let transport = DnstapTransport::new(transport, Arc::clone(&sink), MessageType::RESOLVERQUERY, SocketProtocol::UDP);
+        let server = net::SocketAddr::from(([192, 0, 2, 53], 53));
+        let query = DnsMessage::default();
+        let response = transport.exchange(&query, server, Duration::from_secs(1)).unwrap();
+        // Dropping the last reference stops the stream, which the reader acknowledges.
+        drop(transport);
+        drop(sink);
+        let frames = reader.join().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let types: Vec<MessageType> = frames.iter().map(|frame| frame.message.message_type).collect();
+        assert_eq!(types, vec![MessageType::RESOLVERQUERY, MessageType::RESOLVERRESPONSE]);
+        assert_eq!(frames[1].message.response_address, Some(server));
+        assert_eq!(frames[1].message.query_message, wire_format(&query));
+        assert_eq!(frames[1].message.response_message, wire_format(&response));
+        assert!(frames[1].message.response_time >= frames[1].message.query_time);
+        assert_eq!(frames[0].version.as_deref(), Some(format!("dns_resolver {}", env!("CARGO_PKG_VERSION")).as_bytes()));
+    }
+
+    /// A writer which blocks while the lock is held by the test.
+    struct BlockingWriter {
+        lock: Arc<Mutex<()>>,
+        bytes: Arc<Mutex<Vec<u8>>>
+    }
+
+    impl Write for BlockingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _guard = self.lock.lock().unwrap();
+            self.bytes.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn queue_test() {
+        let lock = Arc::new(Mutex::new(()));
+        let bytes = Arc::new(Mutex::new(Vec::new()));
+        let writer = BlockingWriter { lock: Arc::clone(&lock), bytes: Arc::clone(&bytes) };
+        let sink = DnstapSink::new(FrameStreamWriter::new(Box::new(writer) as Box<dyn Write + Send>).unwrap());
+        // Logging must not block while the writer does, the messages exceeding the queue are dropped.
+        let guard = lock.lock().unwrap();
+        for _ in 0..QUEUE_LENGTH + 10 {
+            sink.log(Message::new(MessageType::CLIENTQUERY));
+        }
+        drop(guard);
+        drop(sink);
+
+        let bytes = bytes.lock().unwrap();
+        let mut reader = FrameStreamReader::new(&bytes[..]).unwrap();
+        let mut frames = 0;
+        while reader.read_frame().unwrap().is_some() {
+            frames += 1;
+        }
+        // The writer may have taken the first message off the queue before blocking.
+        assert!(frames == QUEUE_LENGTH || frames == QUEUE_LENGTH + 1, "{} frames", frames);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::dns_message::{DnsHeader, DnsMessage, DnsRecord, RecordData, ResponseCode, AUTHENTIC_DATA, CHECKING_DISABLED};
+use std::fmt::{self, Display, Write};
+
+/// A way of rendering a DnsMessage as text. Every formatter writes whole lines, so that the
+/// renderings of several messages can be concatenated. Library users can implement it to render
+/// messages in their own format, and display them through DnsMessage::formatted.
+pub trait Formatter {
+    fn write(&self, message: &DnsMessage, out: &mut dyn Write) -> fmt::Result;
+
+    fn format(&self, message: &DnsMessage) -> String {
+        let mut string = String::new();
+        self.write(message, &mut string).expect("Writing to a String cannot fail.");
+        string
+    }
+}
+
+/// A DnsMessage along with the formatter used to display it, see DnsMessage::formatted.
+pub struct Formatted<'a> {
+    message: &'a DnsMessage,
+    formatter: &'a dyn Formatter
+}
+
+impl Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.formatter.write(self.message, f)
+    }
+}
+
+impl DnsMessage {
+    /// Returns a value which displays `self` using `formatter`, e.g. in println!.
+    pub fn formatted<'a>(&'a self, formatter: &'a dyn Formatter) -> Formatted<'a> {
+        Formatted { message: self, formatter }
+    }
+}
+
+/// Returns the formatter with the given name, i.e. one of dig, short, table, yaml, zone and, with
+/// the json feature, json.
+pub fn formatter(name: &str) -> Option<Box<dyn Formatter>> {
+    match name {
+        "dig" => Some(Box::new(DigFormatter)),
+        "short" => Some(Box::new(ShortFormatter)),
+        "table" => Some(Box::new(TableFormatter)),
+        #[cfg(feature = "json")]
+        "json" => Some(Box::new(JsonFormatter)),
+        "yaml" => Some(Box::new(YamlFormatter)),
+        "zone" => Some(Box::new(ZoneFormatter)),
+        _ => None
+    }
+}
+
+/// Returns the presentation format of record data, as in zone files. Unlike Display for
+/// RecordData, character strings are quoted, since they may contain spaces.
+pub fn presentation_data(data: &RecordData) -> String {
+    match data {
+        RecordData::HINFO { cpu, os } => format!("{} {}", quote(&cpu.to_string()), quote(&os.to_string())),
+        data => data.to_string()
+    }
+}
+
+/// Quotes a character string, escaping quotes and backslashes.
+fn quote(string: &str) -> String {
+    format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The output of dig, i.e. the header followed by every non-empty section, with records in the
+/// presentation format.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DigFormatter;
+
+impl Formatter for DigFormatter {
+    fn write(&self, message: &DnsMessage, out: &mut dyn Write) -> fmt::Result {
+        let header = &message.header;
+        writeln!(out, ";; ->>HEADER<<- opcode: {}, status: {}, id: {}", header.operation_code, dig_status(header.response_code), header.id)?;
+        writeln!(out, ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags(header).join(" "), message.questions.len(), message.answers.len(), message.authorities.len(), message.additional.len())?;
+        if !message.questions.is_empty() {
+            writeln!(out)?;
+            writeln!(out, ";; QUESTION SECTION:")?;
+            for question in message.questions.iter() {
+                writeln!(out, ";{}\t\t{}\t{}", question.name, question.question_class, question.question_type)?;
+            }
+        }
+        for (section, records) in [("ANSWER", &message.answers), ("AUTHORITY", &message.authorities), ("ADDITIONAL", &message.additional)] {
+            if !records.is_empty() {
+                writeln!(out)?;
+                writeln!(out, ";; {} SECTION:", section)?;
+                write_records(records, out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The output of dig +short, i.e. the data of the answers, one per line.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShortFormatter;
+
+impl Formatter for ShortFormatter {
+    fn write(&self, message: &DnsMessage, out: &mut dyn Write) -> fmt::Result {
+        for answer in message.answers.iter() {
+            writeln!(out, "{}", presentation_data(&answer.data))?;
+        }
+        Ok(())
+    }
+}
+
+/// The tab separated table of Display for DnsMessage.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TableFormatter;
+
+impl Formatter for TableFormatter {
+    fn write(&self, message: &DnsMessage, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", message)
+    }
+}
+
+/// The JSON format of DNS over HTTPS APIs on a single line, see the json module. Requires the json
+/// feature.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonFormatter;
+
+#[cfg(feature = "json")]
+impl Formatter for JsonFormatter {
+    fn write(&self, message: &DnsMessage, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "{}", message.to_json())
+    }
+}
+
+/// A YAML document mirroring the structure of DnsMessage. Names and record data are always double
+/// quoted, so that they are read back as strings.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct YamlFormatter;
+
+impl Formatter for YamlFormatter {
+    fn write(&self, message: &DnsMessage, out: &mut dyn Write) -> fmt::Result {
+        let header = &message.header;
+        writeln!(out, "---")?;
+        writeln!(out, "header:")?;
+        writeln!(out, "  id: {}", header.id)?;
+        writeln!(out, "  opcode: {}", header.operation_code)?;
+        writeln!(out, "  status: {}", header.response_code)?;
+        writeln!(out, "  flags: [{}]", flags(header).join(", "))?;
+        match message.questions.is_empty() {
+            true => writeln!(out, "questions: []")?,
+            false => writeln!(out, "questions:")?
+        }
+        for question in message.questions.iter() {
+            writeln!(out, "  - name: {}", quote(&question.name.to_string()))?;
+            writeln!(out, "    class: {}", question.question_class)?;
+            writeln!(out, "    type: {}", question.question_type)?;
+        }
+        for (section, records) in [("answers", &message.answers), ("authorities", &message.authorities), ("additional", &message.additional)] {
+            match records.is_empty() {
+                true => writeln!(out, "{}: []", section)?,
+                false => writeln!(out, "{}:", section)?
+            }
+            for record in records.iter() {
+                writeln!(out, "  - name: {}", quote(&record.name.to_string()))?;
+                writeln!(out, "    ttl: {}", record.ttl)?;
+                writeln!(out, "    class: {}", record.record_class)?;
+                writeln!(out, "    type: {}", record.record_type)?;
+                writeln!(out, "    data: {}", quote(&presentation_data(&record.data)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The records of every section in the style of a zone file, preceded by the questions as
+/// comments.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ZoneFormatter;
+
+impl Formatter for ZoneFormatter {
+    fn write(&self, message: &DnsMessage, out: &mut dyn Write) -> fmt::Result {
+        for question in message.questions.iter() {
+            writeln!(out, "; {}", question)?;
+        }
+        write_records(&message.answers, out)?;
+        write_records(&message.authorities, out)?;
+        write_records(&message.additional, out)
+    }
+}
+
+fn write_records(records: &[DnsRecord], out: &mut dyn Write) -> fmt::Result {
+    for record in records.iter() {
+        writeln!(out, "{}\t{}\t{}\t{}\t{}", record.name, record.ttl, record.record_class, record.record_type, presentation_data(&record.data))?;
+    }
+    Ok(())
+}
+
+/// Returns the names of the flags which are set, in the order of dig.
+fn flags(header: &DnsHeader) -> Vec<&'static str> {
+    [
+        (header.response, "qr"),
+        (header.authoritative_answer, "aa"),
+        (header.truncated, "tc"),
+        (header.recursion_desired, "rd"),
+        (header.recursion_available, "ra"),
+        (header.z & AUTHENTIC_DATA != 0, "ad"),
+        (header.z & CHECKING_DISABLED != 0, "cd")
+    ].into_iter().filter(|(set, _)| *set).map(|(_, flag)| flag).collect()
+}
+
+/// Returns the mnemonic used by dig for a response code.
+fn dig_status(response_code: ResponseCode) -> &'static str {
+    match response_code {
+        ResponseCode::NOERROR => "NOERROR",
+        ResponseCode::FORMATERROR => "FORMERR",
+        ResponseCode::SERVERFAILURE => "SERVFAIL",
+        ResponseCode::NAMEERROR => "NXDOMAIN",
+        ResponseCode::NOTIMPLEMENTED => "NOTIMP",
+        ResponseCode::REFUSED => "REFUSED"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::*;
+    use crate::dns_message::{DnsQuestion, RecordClass, RecordType};
+    use crate::udp_packet::{CharacterString, DomainName};
+    use std::str::FromStr;
+
+    fn message() -> DnsMessage {
+        let record = |data: RecordData, record_type: RecordType| DnsRecord {
+            name: DomainName::from_str("example.com.").unwrap(),
+            record_type,
+            record_class: RecordClass::IN,
+            ttl: 300,
+            length: 0,
+            data
+        };
+        let mut message = DnsMessage {
+            header: DnsHeader { id: 4660, response: true, recursion_available: true, z: AUTHENTIC_DATA, ..Default::default() },
+            questions: vec![DnsQuestion { name: DomainName::from_str("example.com.").unwrap(), ..Default::default() }],
+            answers: vec![
+                record(RecordData::A { ipv4_address: "192.0.2.1".parse().unwrap() }, RecordType::A),
+                record(RecordData::HINFO {
+                    cpu: CharacterString::from_str("Intel x86").unwrap(),
+                    os: CharacterString::from_str("Linux").unwrap()
+                }, RecordType::HINFO)
+            ],
+            ..Default::default()
+        };
+        message.update_counts();
+        message
+    }
+
+    #[test]
+    fn dig_test() {
+        assert_eq!(DigFormatter.format(&message()), "\
+;; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4660
+;; flags: qr rd ra ad; QUERY: 1, ANSWER: 2, AUTHORITY: 0, ADDITIONAL: 0
+
+;; QUESTION SECTION:
+;example.com.\t\tIN\tA
+
+;; ANSWER SECTION:
+example.com.\t300\tIN\tA\t192.0.2.1
+example.com.\t300\tIN\tHINFO\t\"Intel x86\" \"Linux\"
+");
+        assert_eq!(ShortFormatter.format(&message()), "192.0.2.1\n\"Intel x86\" \"Linux\"\n");
+        assert_eq!(message().formatted(&ZoneFormatter).to_string(), "\
+; example.com. IN A
+example.com.\t300\tIN\tA\t192.0.2.1
+example.com.\t300\tIN\tHINFO\t\"Intel x86\" \"Linux\"
+");
+    }
+
+    #[test]
+    fn yaml_test() {
+        assert_eq!(formatter("yaml").unwrap().format(&message()), "\
+---
+header:
+  id: 4660
+  opcode: QUERY
+  status: NOERROR
+  flags: [qr, rd, ra, ad]
+questions:
+  - name: \"example.com.\"
+    class: IN
+    type: A
+answers:
+  - name: \"example.com.\"
+    ttl: 300
+    class: IN
+    type: A
+    data: \"192.0.2.1\"
+  - name: \"example.com.\"
+    ttl: 300
+    class: IN
+    type: HINFO
+    data: \"\\\"Intel x86\\\" \\\"Linux\\\"\"
+authorities: []
+additional: []
+");
+        assert!(formatter("xml").is_none());
+    }
+}
@@ -0,0 +1,804 @@
+use crate::dns_message::{DnsMessage, RecordData};
+#[cfg(feature = "json")]
+use crate::json;
+use crate::dnstap::{self, DnstapSink, SocketProtocol};
+use crate::server::{ClientTap, QueryHandler};
+use crate::tls::{self, TlsClientConfig, TlsError};
+use crate::transport::Transport;
+use crate::udp_packet::{self, TcpPacket, UdpPacketError};
+use base64::Engine;
+use bytes::Bytes;
+use rustls::pki_types::ServerName;
+use std::collections::HashMap;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+pub const DOH_PORT: u16 = 443;
+pub const DEFAULT_PATH: &str = "/dns-query";    // The path suggested by RFC 8484, section 3.
+#[cfg(feature = "json")]
+pub const JSON_PATH: &str = "/resolve";         // The path of the JSON API of Google.
+
+const CONTENT_TYPE: &str = "application/dns-message";
+const ALPN_PROTOCOL: &[u8] = b"h2";
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10); // Time the server waits for a client to complete the TLS handshake.
+
+/// The HTTP method used to send queries, see RFC 8484, section 4.1.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HttpsMethod {
+    /// The query is the body of the request.
+    #[default]
+    Post,
+
+    /// The query is the dns parameter of the URI, encoded as base64url, which makes the response cacheable.
+    Get
+}
+
+/// Configuration of an HttpsTransport.
+#[derive(Clone, Debug)]
+pub struct HttpsClientConfig {
+    pub tls: TlsClientConfig,           // The server name is also used as the authority of requests
+    pub path: String,                   // The path of the DoH endpoint on the server
+    pub method: HttpsMethod
+}
+
+impl Default for HttpsClientConfig {
+    fn default() -> Self {
+        Self {
+            tls: TlsClientConfig::default(),
+            path: String::from(DEFAULT_PATH),
+            method: HttpsMethod::default()
+        }
+    }
+}
+
+/// Error handling type for DNS over HTTPS.
+#[derive(Debug)]
+pub enum HttpsError {
+    /// The TLS configuration could not be built.
+    Tls {
+        source: TlsError                // The underlying error.
+    },
+
+    /// The runtime carrying out the HTTP/2 connections could not be started.
+    Runtime {
+        source: std::io::Error          // The underlying error.
+    }
+}
+
+impl std::fmt::Display for HttpsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tls {
+                source
+            } => write!(f, "{}", source),
+            Self::Runtime {
+                source
+            } => write!(f, "failed to start the runtime, source: {}", source)
+        }
+    }
+}
+
+impl std::error::Error for HttpsError {}
+
+impl From<TlsError> for HttpsError {
+    fn from(source: TlsError) -> Self {
+        Self::Tls { source }
+    }
+}
+
+/// Specialised result type for DNS over HTTPS.
+pub type Result<T> = std::result::Result<T, HttpsError>;
+
+fn https_error(description: &str) -> impl FnOnce(h2::Error) -> UdpPacketError {
+    let description = String::from(description);
+    move |error| UdpPacketError::NetworkIo {
+        description,
+        source: match error.get_io() {
+            Some(source) => std::io::Error::new(source.kind(), error.to_string()),
+            None => std::io::Error::other(error)
+        }
+    }
+}
+
+fn invalid_response(description: String) -> UdpPacketError {
+    UdpPacketError::NetworkIo { description, source: std::io::Error::from(std::io::ErrorKind::InvalidData) }
+}
+
+/// DNS over HTTPS (RFC 8484) over HTTP/2. One connection is kept open per server and shared by
+/// all queries sent to it, which are carried out on a runtime owned by the transport.
+///
+/// This is a blocking transport only: it waits for its own runtime, which tokio does not allow
+/// within another runtime. Queries sent from a thread of a tokio runtime, including its blocking
+/// threads, fail with an error, and the transport must not be dropped on one either.
+pub struct HttpsTransport {
+    config: HttpsClientConfig,
+    tls_config: Arc<rustls::ClientConfig>,
+    runtime: tokio::runtime::Runtime,
+    connections: Mutex<HashMap<net::SocketAddr, h2::client::SendRequest<Bytes>>>
+}
+
+impl HttpsTransport {
+    pub fn new(config: HttpsClientConfig) -> Result<Self> {
+        let tls_config = tls::client_config(&config.tls, vec![ALPN_PROTOCOL.to_vec()])?;
+        // The connections are driven by the worker thread, also while no query is being sent.
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(|source| HttpsError::Runtime { source })?;
+        Ok(Self { config, tls_config, runtime, connections: Mutex::new(HashMap::new()) })
+    }
+
+    /// Returns the number of open connections, i.e. the number of servers queried so far.
+    pub fn num_connections(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    fn authority(&self, server: net::SocketAddr) -> String {
+        match &self.config.tls.server_name {
+            Some(server_name) if server.port() == DOH_PORT => server_name.clone(),
+            Some(server_name) => format!("{}:{}", server_name, server.port()),
+            None => server.to_string()
+        }
+    }
+
+    async fn connect(&self, server: net::SocketAddr) -> udp_packet::Result<h2::client::SendRequest<Bytes>> {
+        let io_error = |description: &str| {
+            let description = String::from(description);
+            move |error| UdpPacketError::NetworkIo { description, source: error }
+        };
+        let server_name = match &self.config.tls.server_name {
+            Some(server_name) => ServerName::try_from(server_name.clone())
+            .map_err(|error| io_error("invalid server name")(std::io::Error::other(error)))?,
+            None => ServerName::IpAddress(server.ip().into())
+        };
+        let stream = tokio::net::TcpStream::connect(server).await.map_err(io_error("failed to connect"))?;
+        let stream = tokio_rustls::TlsConnector::from(Arc::clone(&self.tls_config)).connect(server_name, stream).await
+        .map_err(io_error("failed to establish a TLS session"))?;
+        let (send_request, connection) = h2::client::handshake(stream).await
+        .map_err(https_error("failed to establish an HTTP/2 connection"))?;
+        self.runtime.spawn(async move {
+            // The connection is dropped from the pool the next time it is used after failing.
+            let _ = connection.await;
+        });
+        Ok(send_request)
+    }
+
+    /// Sends a query on an existing connection to `server` if there is one, opening one otherwise.
+    async fn send(&self, query: &DnsMessage, server: net::SocketAddr) -> udp_packet::Result<DnsMessage> {
+        let pooled = self.connections.lock().unwrap().get(&server).cloned();
+        if let Some(send_request) = pooled {
+            match self.send_on(send_request, query, server).await {
+                // The server may have closed the connection since it was last used, whereas invalid
+                // responses would be the same on a new connection.
+                Err(error) if !matches!(&error, UdpPacketError::NetworkIo { source, .. }
+                    if source.kind() == std::io::ErrorKind::InvalidData) => {
+                    self.connections.lock().unwrap().remove(&server);
+                },
+                result => return result
+            }
+        }
+        let send_request = self.connect(server).await?;
+        self.connections.lock().unwrap().insert(server, send_request.clone());
+        self.send_on(send_request, query, server).await
+    }
+
+    async fn send_on(
+        &self,
+        send_request: h2::client::SendRequest<Bytes>,
+        query: &DnsMessage,
+        server: net::SocketAddr
+    ) -> udp_packet::Result<DnsMessage> {
+        // The id is 0 to make responses to GET requests cacheable (RFC 8484, section 4.1).
+        let mut sent = query.clone();
+        sent.header.id = 0;
+        let mut tcp_packet = Box::new(TcpPacket::empty());
+        sent.write_to_udp_packet(&mut tcp_packet)?;
+        let body = Bytes::copy_from_slice(&tcp_packet.buffer[..tcp_packet.position]);
+        log::trace!("sending {} bytes over HTTPS to {}:\n{}", body.len(), server, udp_packet::hex_dump(&body));
+
+        let request = http::Request::builder().header(http::header::ACCEPT, CONTENT_TYPE);
+        let request = match self.config.method {
+            HttpsMethod::Post => request
+            .method(http::Method::POST)
+            .uri(format!("https://{}{}", self.authority(server), self.config.path))
+            .header(http::header::CONTENT_TYPE, CONTENT_TYPE)
+            .header(http::header::CONTENT_LENGTH, body.len()),
+            HttpsMethod::Get => request
+            .method(http::Method::GET)
+            .uri(format!(
+                "https://{}{}?dns={}",
+                self.authority(server),
+                self.config.path,
+                base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&body)
+            ))
+        };
+        let request = request.body(()).map_err(|error| invalid_response(format!("invalid request: {}", error)))?;
+
+        let mut send_request = send_request.ready().await.map_err(https_error("the connection was closed"))?;
+        let end_of_stream = self.config.method == HttpsMethod::Get;
+        let (response, mut send_stream) = send_request.send_request(request, end_of_stream)
+        .map_err(https_error("failed to send a request"))?;
+        if !end_of_stream {
+            send_stream.send_data(body, true).map_err(https_error("failed to send a request"))?;
+        }
+        let response = response.await.map_err(https_error("failed to receive a response"))?;
+        if response.status() != http::StatusCode::OK {
+            return Err(invalid_response(format!("the server responded with HTTP status {}", response.status())));
+        }
+        let content_type = response.headers().get(http::header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        if content_type != Some(CONTENT_TYPE) {
+            return Err(invalid_response(format!("the server responded with content type {:?}", content_type)));
+        }
+
+        let mut body = response.into_body();
+        let mut response_packet = Box::new(TcpPacket::empty());
+        let mut length = 0;
+        while let Some(data) = body.data().await {
+            let data = data.map_err(https_error("failed to receive a response"))?;
+            if length + data.len() > response_packet.buffer.len() {
+                return Err(invalid_response(String::from("the response is too large")));
+            }
+            response_packet.buffer[length..(length + data.len())].copy_from_slice(&data);
+            length += data.len();
+            let _ = body.flow_control().release_capacity(data.len());
+        }
+        log::trace!("received {} bytes over HTTPS from {}:\n{}", length, server, udp_packet::hex_dump(&response_packet.buffer[..length]));
+        let mut response = DnsMessage::read_from_udp_packet(&mut response_packet)?;
+        if !response.is_response_to(&sent) {
+            return Err(invalid_response(String::from("received a response which does not match the query")));
+        }
+        response.header.id = query.header.id;
+        Ok(response)
+    }
+}
+
+impl Transport for HttpsTransport {
+    fn exchange(&self, query: &DnsMessage, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<DnsMessage> {
+        // Blocking on the runtime of the transport would panic.
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(UdpPacketError::NetworkIo {
+                description: String::from("HttpsTransport is blocking and cannot be used within a tokio runtime"),
+                source: std::io::Error::from(std::io::ErrorKind::Unsupported)
+            });
+        }
+        self.runtime.block_on(async {
+            match tokio::time::timeout(timeout, self.send(query, server)).await {
+                Ok(result) => result,
+                Err(_) => Err(UdpPacketError::NetworkIo {
+                    description: String::from("timed out while waiting for a response"),
+                    source: std::io::Error::from(std::io::ErrorKind::TimedOut)
+                })
+            }
+        })
+    }
+}
+
+/// A DNS over HTTPS server which answers queries sent to DEFAULT_PATH using a QueryHandler. The
+/// connections are served by a runtime owned by the server, and every query is handled on a
+/// blocking thread of it, so that a slow upstream does not hold up other queries.
+pub struct HttpsServer<H> {
+    listener: net::TcpListener,
+    acceptor: tokio_rustls::TlsAcceptor,
+    handler: Arc<H>,
+    dnstap: Option<Arc<DnstapSink>>     // Receives CLIENTQUERY and CLIENTRESPONSE messages, see set_dnstap
+}
+
+impl<H: QueryHandler + 'static> HttpsServer<H> {
+    /// Binds the server socket. The ALPN protocols of `config` are replaced by HTTP/2, so that the
+    /// config made by tls::server_config can be used.
+    pub fn bind<A: net::ToSocketAddrs>(address: A, config: Arc<rustls::ServerConfig>, handler: H) -> udp_packet::Result<Self> {
+        let listener = net::TcpListener::bind(address).map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to bind the server socket"),
+            source: error
+        })?;
+        let mut config = (*config).clone();
+        config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        Ok(Self { listener, acceptor, handler: Arc::new(handler), dnstap: None })
+    }
+
+    /// Logs every query received and response sent to `dnstap`.
+    pub fn set_dnstap(&mut self, dnstap: Arc<DnstapSink>) {
+        self.dnstap = Some(dnstap);
+    }
+
+    pub fn local_addr(&self) -> udp_packet::Result<net::SocketAddr> {
+        self.listener.local_addr().map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to read the address of the server socket"),
+            source: error
+        })
+    }
+
+    /// Serves connections until accepting one fails.
+    pub fn run(&self) -> udp_packet::Result<()> {
+        let io_error = |description: &str| {
+            let description = String::from(description);
+            move |error| UdpPacketError::NetworkIo { description, source: error }
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(io_error("failed to start the runtime"))?;
+        runtime.block_on(async {
+            let listener = self.listener.try_clone().map_err(io_error("failed to clone the server socket"))?;
+            listener.set_nonblocking(true).map_err(io_error("failed to make the server socket non-blocking"))?;
+            let listener = tokio::net::TcpListener::from_std(listener).map_err(io_error("failed to register the server socket"))?;
+            loop {
+                let (stream, client) = listener.accept().await.map_err(io_error("failed to accept a connection"))?;
+                let dnstap = self.dnstap.clone().map(|sink| Arc::new(ClientTap::new(sink, SocketProtocol::DOH, client, stream.local_addr().ok())));
+                tokio::spawn(serve_connection(self.acceptor.clone(), stream, Arc::clone(&self.handler), dnstap));
+            }
+        })
+    }
+
+    /// Runs the server on a background thread, mostly useful for tests and embedding.
+    pub fn spawn(self) -> thread::JoinHandle<udp_packet::Result<()>> {
+        thread::spawn(move || self.run())
+    }
+}
+
+/// Serves every request of a connection. A failure to serve a single client should not stop the server.
+async fn serve_connection<H: QueryHandler + 'static>(
+    acceptor: tokio_rustls::TlsAcceptor,
+    stream: tokio::net::TcpStream,
+    handler: Arc<H>,
+    dnstap: Option<Arc<ClientTap>>
+) {
+    let stream = match tokio::time::timeout(IDLE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        _ => return
+    };
+    let mut connection = match h2::server::handshake(stream).await {
+        Ok(connection) => connection,
+        Err(_) => return
+    };
+    while let Some(Ok((request, respond))) = connection.accept().await {
+        tokio::spawn(serve_request(request, respond, Arc::clone(&handler), dnstap.clone()));
+    }
+}
+
+async fn serve_request<H: QueryHandler + 'static>(
+    request: http::Request<h2::RecvStream>,
+    respond: h2::server::SendResponse<Bytes>,
+    handler: Arc<H>,
+    dnstap: Option<Arc<ClientTap>>
+) {
+    let (parts, body) = request.into_parts();
+    #[cfg(feature = "json")]
+    if is_json_request(&parts) {
+        let query = json::query_from_parameters(parts.uri.query().unwrap_or_default())
+        .map_err(|_| http::StatusCode::BAD_REQUEST);
+        return answer(query, respond, handler, dnstap, |response| Some((json::CONTENT_TYPE, response.to_json().into_bytes()))).await;
+    }
+    answer(read_query(parts, body).await, respond, handler, dnstap, |response| {
+        let mut tcp_packet = Box::new(TcpPacket::empty());
+        response.write_to_udp_packet(&mut tcp_packet).ok()?;
+        log::trace!("sending a response of {} bytes over HTTPS:\n{}", tcp_packet.position, udp_packet::hex_dump(&tcp_packet.buffer[..tcp_packet.position]));
+        Some((CONTENT_TYPE, tcp_packet.buffer[..tcp_packet.position].to_vec()))
+    }).await
+}
+
+/// Encodes a response as the value of the Content-Type header and the body, or returns None if it
+/// cannot be encoded.
+type Encoder = fn(&DnsMessage) -> Option<(&'static str, Vec<u8>)>;
+
+/// Responds to a request with the handler's response to `query`, encoded as a content type and
+/// body by `encode`, or with the HTTP status of a request which could not be decoded. Queries and
+/// responses are logged to `dnstap` in wire format, whichever encoding the request used.
+async fn answer<H: QueryHandler + 'static>(
+    query: std::result::Result<DnsMessage, http::StatusCode>,
+    mut respond: h2::server::SendResponse<Bytes>,
+    handler: Arc<H>,
+    dnstap: Option<Arc<ClientTap>>,
+    encode: Encoder
+) {
+    let response = match query {
+        Ok(query) => tokio::task::spawn_blocking(move || {
+            let logged = dnstap.as_ref()
+            .and_then(|tap| Some(tap.log_query(SystemTime::now(), dnstap::wire_format(&query)?)));
+            let response = handler.handle(&query);
+            if let Some((tap, message)) = dnstap.as_ref().zip(logged) {
+                if let Some(wire_response) = dnstap::wire_format(&response) {
+                    tap.log_response(message, wire_response);
+                }
+            }
+            response
+        }).await
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR),
+        Err(status) => Err(status)
+    };
+    let encoded = response.and_then(|response| match encode(&response) {
+        Some((content_type, body)) => Ok((content_type, body, max_age(&response))),
+        None => Err(http::StatusCode::INTERNAL_SERVER_ERROR)
+    });
+    let (content_type, body, max_age) = match encoded {
+        Ok(encoded) => encoded,
+        Err(status) => {
+            let _ = respond.send_response(http::Response::builder().status(status).body(()).unwrap(), true);
+            return;
+        }
+    };
+    let http_response = http::Response::builder()
+    .header(http::header::CONTENT_TYPE, content_type)
+    .header(http::header::CONTENT_LENGTH, body.len())
+    .header(http::header::CACHE_CONTROL, format!("max-age={}", max_age))
+    .body(())
+    .unwrap();
+    if let Ok(mut send_stream) = respond.send_response(http_response, false) {
+        let _ = send_stream.send_data(Bytes::from(body), true);
+    }
+}
+
+/// Returns true for GET requests to JSON_PATH, as in the API of Google, and for GET requests to
+/// DEFAULT_PATH which accept JSON, as in the API of Cloudflare.
+#[cfg(feature = "json")]
+fn is_json_request(parts: &http::request::Parts) -> bool {
+    let accepts_json = parts.headers.get_all(http::header::ACCEPT).iter()
+    .filter_map(|value| value.to_str().ok())
+    .any(|value| value.contains(json::CONTENT_TYPE));
+    parts.method == http::Method::GET
+    && (parts.uri.path() == JSON_PATH || (parts.uri.path() == DEFAULT_PATH && accepts_json))
+}
+
+/// Decodes the query of a DoH request, or returns the HTTP status to respond with if the request is
+/// not a valid one.
+async fn read_query(parts: http::request::Parts, mut body: h2::RecvStream) -> std::result::Result<DnsMessage, http::StatusCode> {
+    if parts.uri.path() != DEFAULT_PATH {
+        return Err(http::StatusCode::NOT_FOUND);
+    }
+    let message = match parts.method {
+        http::Method::GET => {
+            let dns = parts.uri.query().unwrap_or_default()
+            .split('&')
+            .find_map(|parameter| parameter.strip_prefix("dns="))
+            .ok_or(http::StatusCode::BAD_REQUEST)?;
+            // Padding is not allowed, but is harmless to accept.
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(dns.trim_end_matches('='))
+            .map_err(|_| http::StatusCode::BAD_REQUEST)?
+        },
+        http::Method::POST => {
+            let content_type = parts.headers.get(http::header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+            if content_type != Some(CONTENT_TYPE) {
+                return Err(http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            let mut message = Vec::new();
+            while let Some(data) = body.data().await {
+                let data = data.map_err(|_| http::StatusCode::BAD_REQUEST)?;
+                let _ = body.flow_control().release_capacity(data.len());
+                message.extend_from_slice(&data);
+                if message.len() > usize::from(u16::MAX) {
+                    return Err(http::StatusCode::PAYLOAD_TOO_LARGE);
+                }
+            }
+            message
+        },
+        _ => return Err(http::StatusCode::METHOD_NOT_ALLOWED)
+    };
+    let mut tcp_packet = Box::new(TcpPacket::empty());
+    if message.len() > tcp_packet.buffer.len() {
+        return Err(http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    tcp_packet.buffer[..message.len()].copy_from_slice(&message);
+    log::trace!("received a query of {} bytes over HTTPS:\n{}", message.len(), udp_packet::hex_dump(&message));
+    DnsMessage::read_from_udp_packet(&mut tcp_packet).map_err(|_| http::StatusCode::BAD_REQUEST)
+}
+
+/// Returns the number of seconds for which an HTTP cache may store `response`, which is the lowest
+/// TTL of its answers, or the negative caching TTL of its SOA record if it has no answers
+/// (RFC 8484, section 5.1). Responses without either must not be stored at all.
+fn max_age(response: &DnsMessage) -> u32 {
+    match response.answers.iter().map(|record| record.ttl).min() {
+        Some(ttl) => ttl,
+        None => response.authorities.iter().find_map(|record| match record.data {
+            RecordData::SOA { minimum, .. } => Some(record.ttl.min(minimum)),
+            _ => None
+        }).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_message::*;
+    use crate::dnstap::MessageType;
+    use crate::https::*;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const SERVER_NAME: &str = "doh.example.test";
+
+    /// Returns a server config with a self-signed certificate for SERVER_NAME, and the certificate.
+    fn self_signed() -> (Arc<rustls::ServerConfig>, CertificateDer<'static>) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let certificate = rcgen::CertificateParams::new(vec![String::from(SERVER_NAME)]).unwrap().self_signed(&key).unwrap();
+        let private_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        (tls::server_config(vec![certificate.der().clone()], private_key).unwrap(), certificate.der().clone())
+    }
+
+    fn client_config(ca: CertificateDer<'static>, method: HttpsMethod) -> HttpsClientConfig {
+        HttpsClientConfig {
+            tls: TlsClientConfig {
+                server_name: Some(String::from(SERVER_NAME)),
+                root_certificates: vec![ca],
+                ..Default::default()
+            },
+            method,
+            ..Default::default()
+        }
+    }
+
+    fn record(record_type: RecordType, ttl: u32, data: RecordData) -> DnsRecord {
+        DnsRecord {
+            name: udp_packet::DomainName::from_str(TEST_DOMAIN).unwrap(),
+            record_type,
+            record_class: RecordClass::IN,
+            ttl,
+            length: 0,
+            data
+        }
+    }
+
+    /// Answers a single DoH request with NOERROR, or with 400 if it does not hold a query.
+    async fn answer(request: http::Request<h2::RecvStream>, mut respond: h2::server::SendResponse<Bytes>) {
+        let (parts, mut body) = request.into_parts();
+        let message = match parts.method {
+            http::Method::GET => parts.uri.query()
+            .and_then(|query| query.strip_prefix("dns="))
+            .and_then(|dns| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(dns).ok())
+            .unwrap_or_default(),
+            _ => {
+                let mut message = Vec::new();
+                while let Some(Ok(data)) = body.data().await {
+                    message.extend_from_slice(&data);
+                }
+                message
+            }
+        };
+        let mut udp_packet = udp_packet::UdpPacket::new();
+        udp_packet.buffer[..message.len()].copy_from_slice(&message);
+        let query = match DnsMessage::read_from_udp_packet(&mut udp_packet) {
+            Ok(query) if parts.uri.path() == DEFAULT_PATH => query,
+            _ => {
+                let response = http::Response::builder().status(400).body(()).unwrap();
+                let _ = respond.send_response(response, true);
+                return;
+            }
+        };
+        let mut response = query.reply(ResponseCode::NOERROR);
+        response.header.authoritative_answer = parts.method == http::Method::GET;
+        let mut udp_packet = udp_packet::UdpPacket::new();
+        response.write_to_udp_packet(&mut udp_packet).unwrap();
+        let http_response = http::Response::builder().header(http::header::CONTENT_TYPE, CONTENT_TYPE).body(()).unwrap();
+        let mut send_stream = respond.send_response(http_response, false).unwrap();
+        let _ = send_stream.send_data(Bytes::copy_from_slice(&udp_packet.buffer[..udp_packet.position]), true);
+    }
+
+    /// Starts an HTTPS stub, returning its address, its CA certificate and a connection counter.
+    fn start_stub() -> (net::SocketAddr, CertificateDer<'static>, Arc<AtomicUsize>) {
+        let (config, certificate) = self_signed();
+        let mut config = (*config).clone();
+        config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        let listener = std::net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let num_connections = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&num_connections);
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let acceptor = acceptor.clone();
+                    tokio::spawn(async move {
+                        let stream = acceptor.accept(stream).await.unwrap();
+                        let mut connection = h2::server::handshake(stream).await.unwrap();
+                        while let Some(Ok((request, respond))) = connection.accept().await {
+                            tokio::spawn(answer(request, respond));
+                        }
+                    });
+                }
+            });
+        });
+        (address, certificate, num_connections)
+    }
+
+    #[test]
+    fn post_and_get_test() {
+        let (address, ca, num_connections) = start_stub();
+        for method in [HttpsMethod::Post, HttpsMethod::Get] {
+            let transport = HttpsTransport::new(HttpsClientConfig {
+                tls: TlsClientConfig {
+                    server_name: Some(String::from(SERVER_NAME)),
+                    root_certificates: vec![ca.clone()],
+                    ..Default::default()
+                },
+                method,
+                ..Default::default()
+            }).unwrap();
+            for id in 1..=3 {
+                let mut query = DnsMessage::default();
+                query.header.id = id;
+                let response = transport.exchange(&query, address, Duration::from_secs(5)).expect("Failed to exchange.");
+                assert_eq!(response.header.id, id);
+                // The stub only sets the AA flag for GET requests.
+                assert_eq!(response.header.authoritative_answer, method == HttpsMethod::Get);
+            }
+            assert_eq!(transport.num_connections(), 1);
+        }
+        // Every transport reused its connection for all of its queries.
+        assert_eq!(num_connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn runtime_test() {
+        let (address, ca, _) = start_stub();
+        let transport = HttpsTransport::new(client_config(ca, HttpsMethod::Post)).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let result = runtime.block_on(async { transport.exchange(&DnsMessage::default(), address, Duration::from_secs(5)) });
+        assert!(result.unwrap_err().to_string().contains("within a tokio runtime"));
+        assert!(transport.exchange(&DnsMessage::default(), address, Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn wrong_path_test() {
+        let (address, ca, _) = start_stub();
+        let transport = HttpsTransport::new(HttpsClientConfig {
+            tls: TlsClientConfig {
+                server_name: Some(String::from(SERVER_NAME)),
+                root_certificates: vec![ca],
+                ..Default::default()
+            },
+            path: String::from("/resolve"),
+            ..Default::default()
+        }).unwrap();
+        let error = transport.exchange(&DnsMessage::default(), address, Duration::from_secs(5)).unwrap_err();
+        assert!(error.to_string().contains("400"), "{}", error);
+    }
+
+    /// Starts an HttpsServer answering with an A record with a TTL of 300 and one with a TTL of 60.
+    fn start_server() -> (net::SocketAddr, CertificateDer<'static>) {
+        start_server_with_dnstap(None)
+    }
+
+    fn start_server_with_dnstap(dnstap: Option<Arc<DnstapSink>>) -> (net::SocketAddr, CertificateDer<'static>) {
+        let (config, certificate) = self_signed();
+        let mut server = HttpsServer::bind((net::Ipv4Addr::LOCALHOST, 0), config, |query: &DnsMessage| {
+            let mut response = query.reply(ResponseCode::NOERROR);
+            response.answers = vec![
+                record(RecordType::A, 300, RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, 1) }),
+                record(RecordType::A, 60, RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, 2) })
+            ];
+            response.update_counts();
+            response
+        }).unwrap();
+        if let Some(sink) = dnstap {
+            server.set_dnstap(sink);
+        }
+        let address = server.local_addr().unwrap();
+        server.spawn();
+        (address, certificate)
+    }
+
+    /// Sends a single request to an HTTPS server, returning the response and its body.
+    async fn send_request(address: net::SocketAddr, ca: CertificateDer<'static>, request: http::Request<()>, body: &[u8]) -> (http::response::Parts, Vec<u8>) {
+        let config = tls::client_config(&client_config(ca, HttpsMethod::Post).tls, vec![ALPN_PROTOCOL.to_vec()]).unwrap();
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let server_name = ServerName::try_from(SERVER_NAME).unwrap();
+        let stream = tokio_rustls::TlsConnector::from(config).connect(server_name, stream).await.unwrap();
+        let (send_request, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let mut send_request = send_request.ready().await.unwrap();
+        let (response, mut send_stream) = send_request.send_request(request, body.is_empty()).unwrap();
+        if !body.is_empty() {
+            send_stream.send_data(Bytes::copy_from_slice(body), true).unwrap();
+        }
+        let (parts, mut body) = response.await.unwrap().into_parts();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        (parts, data)
+    }
+
+    #[test]
+    fn server_test() {
+        let (address, ca) = start_server();
+        for method in [HttpsMethod::Post, HttpsMethod::Get] {
+            let transport = HttpsTransport::new(client_config(ca.clone(), method)).unwrap();
+            let mut query = DnsMessage::default();
+            query.header.id = 42;
+            let response = transport.exchange(&query, address, Duration::from_secs(5)).expect("Failed to exchange.");
+            assert_eq!(response.header.id, 42);
+            assert_eq!(response.answers.len(), 2);
+        }
+    }
+
+    #[test]
+    fn dnstap_test() {
+        let (sink, messages) = dnstap::memory_sink();
+        let (address, ca) = start_server_with_dnstap(Some(sink));
+        let transport = HttpsTransport::new(client_config(ca, HttpsMethod::Post)).unwrap();
+        let response = transport.exchange(&DnsMessage::default(), address, Duration::from_secs(5)).expect("Failed to exchange.");
+        let messages = messages(2);
+        let types: Vec<MessageType> = messages.iter().map(|message| message.message_type).collect();
+        assert_eq!(types, vec![MessageType::CLIENTQUERY, MessageType::CLIENTRESPONSE]);
+        assert!(messages.iter().all(|message| message.socket_protocol == Some(SocketProtocol::DOH)));
+        assert_eq!(messages[1].response_message, dnstap::wire_format(&response));
+    }
+
+    #[tokio::test]
+    async fn server_status_test() {
+        let (address, ca) = start_server();
+        let mut udp_packet = udp_packet::UdpPacket::new();
+        DnsMessage::default().write_to_udp_packet(&mut udp_packet).unwrap();
+        let query = &udp_packet.buffer[..udp_packet.position];
+        let uri = |path: &str| format!("https://{}{}", SERVER_NAME, path);
+
+        let request = http::Request::get(uri(&format!(
+            "{}?ct&dns={}",
+            DEFAULT_PATH,
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(query)
+        ))).body(()).unwrap();
+        let (response, _) = send_request(address, ca.clone(), request, &[]).await;
+        assert_eq!(response.status, http::StatusCode::OK);
+        assert_eq!(response.headers[http::header::CONTENT_TYPE], CONTENT_TYPE);
+        assert_eq!(response.headers[http::header::CACHE_CONTROL], "max-age=60");
+
+        let request = http::Request::post(uri("/resolve")).header(http::header::CONTENT_TYPE, CONTENT_TYPE).body(()).unwrap();
+        assert_eq!(send_request(address, ca.clone(), request, query).await.0.status, http::StatusCode::NOT_FOUND);
+        let request = http::Request::post(uri(DEFAULT_PATH)).header(http::header::CONTENT_TYPE, "text/plain").body(()).unwrap();
+        assert_eq!(send_request(address, ca.clone(), request, query).await.0.status, http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let request = http::Request::get(uri(&format!("{}?dns=%%%", DEFAULT_PATH))).body(()).unwrap();
+        assert_eq!(send_request(address, ca.clone(), request, &[]).await.0.status, http::StatusCode::BAD_REQUEST);
+        let request = http::Request::put(uri(DEFAULT_PATH)).body(()).unwrap();
+        assert_eq!(send_request(address, ca, request, query).await.0.status, http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn json_test() {
+        let (address, ca) = start_server();
+        let uri = format!("https://{}{}?name={}&type=A", SERVER_NAME, JSON_PATH, TEST_DOMAIN);
+        let (response, body) = send_request(address, ca.clone(), http::Request::get(uri).body(()).unwrap(), &[]).await;
+        assert_eq!(response.headers[http::header::CONTENT_TYPE], json::CONTENT_TYPE);
+        assert_eq!(response.headers[http::header::CACHE_CONTROL], "max-age=60");
+        let message = DnsMessage::from_json(std::str::from_utf8(&body).unwrap()).expect("Failed to parse.");
+        assert_eq!(message.questions[0].name, udp_packet::DomainName::from_str(TEST_DOMAIN).unwrap());
+        assert_eq!(message.answers.len(), 2);
+
+        // Cloudflare uses the path of RFC 8484, telling the formats apart by the accepted content type.
+        let uri = format!("https://{}{}?name={}", SERVER_NAME, DEFAULT_PATH, TEST_DOMAIN);
+        let request = http::Request::get(uri).header(http::header::ACCEPT, json::CONTENT_TYPE).body(()).unwrap();
+        let (response, _) = send_request(address, ca.clone(), request, &[]).await;
+        assert_eq!(response.headers[http::header::CONTENT_TYPE], json::CONTENT_TYPE);
+        let uri = format!("https://{}{}?type=A", SERVER_NAME, JSON_PATH);
+        let (response, _) = send_request(address, ca, http::Request::get(uri).body(()).unwrap(), &[]).await;
+        assert_eq!(response.status, http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn max_age_test() {
+        let mut response = DnsMessage::default().reply(ResponseCode::NAMEERROR);
+        assert_eq!(max_age(&response), 0);
+        let soa = RecordData::SOA {
+            domain_name: udp_packet::DomainName::from_str(TEST_DOMAIN).unwrap(),
+            mailbox_address: udp_packet::DomainName::from_str(TEST_DOMAIN).unwrap(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 900
+        };
+        response.authorities.push(record(RecordType::SOA, 3600, soa));
+        assert_eq!(max_age(&response), 900);
+        response.answers.push(record(RecordType::A, 1800, RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, 1) }));
+        assert_eq!(max_age(&response), 1800);
+    }
+}
@@ -0,0 +1,993 @@
+use crate::dns_message::{
+    CombinedClass, CombinedType, DnsHeader, DnsMessage, DnsQuestion, DnsRecord, RecordClass, RecordData, RecordType,
+    ResponseCode
+};
+use crate::transport::{Transport, UdpTransport};
+use crate::udp_packet::{self, DomainName};
+use std::net;
+use std::time::{Duration, Instant};
+
+const NAME_SERVER_PORT: u16 = 53;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);     // Time to wait for each name server before trying the next.
+const MAX_QUERIES: usize = 64;                              // The maximum number of queries sent during one resolution.
+const MAX_GLUELESS_DEPTH: usize = 4;                        // The maximum nesting of glueless name server resolutions.
+const MAX_ALIAS_CHAIN_LENGTH: usize = 8;                    // The maximum number of CNAME:s followed during one resolution.
+
+/// The IPv4 addresses of the root name servers, [a-m].root-servers.net.
+pub const ROOT_HINTS: [net::Ipv4Addr; 13] = [
+    net::Ipv4Addr::new(198, 41, 0, 4),
+    net::Ipv4Addr::new(170, 247, 170, 2),
+    net::Ipv4Addr::new(192, 33, 4, 12),
+    net::Ipv4Addr::new(199, 7, 91, 13),
+    net::Ipv4Addr::new(192, 203, 230, 10),
+    net::Ipv4Addr::new(192, 5, 5, 241),
+    net::Ipv4Addr::new(192, 112, 36, 4),
+    net::Ipv4Addr::new(198, 97, 190, 53),
+    net::Ipv4Addr::new(192, 36, 148, 17),
+    net::Ipv4Addr::new(192, 58, 128, 30),
+    net::Ipv4Addr::new(193, 0, 14, 129),
+    net::Ipv4Addr::new(199, 7, 83, 42),
+    net::Ipv4Addr::new(202, 12, 27, 33)
+];
+
+/// Error handling type for iterative resolution.
+#[derive(Debug)]
+pub enum IterativeError {
+    /// None of the name servers of a zone gave a usable response.
+    NoResponse {
+        zone: DomainName,               // The zone whose name servers were queried.
+        source: Option<udp_packet::UdpPacketError>  // The error of the last attempt, if any.
+    },
+
+    /// No name server of a zone gave a usable response, but some responded with an error, e.g.
+    /// REFUSED or SERVFAIL, so the zone is reachable.
+    ErrorResponse {
+        zone: DomainName,               // The zone whose name servers were queried.
+        response_code: ResponseCode     // The response code of the last response.
+    },
+
+    /// The name servers of a zone responded, but none of them authoritatively, e.g. because they
+    /// answered from a cache instead of referring to the zone which holds the name.
+    NotAuthoritative {
+        zone: DomainName                // The zone whose name servers were queried.
+    },
+
+    /// A referral was received, but the addresses of the name servers it points to could not be found.
+    NoNameServerAddress {
+        zone: DomainName                // The zone which was delegated to.
+    },
+
+    /// The resolution required more queries than allowed.
+    MaxQueriesExceeded,
+
+    /// The addresses of name servers without glue required more nested resolutions than allowed.
+    MaxGluelessDepthExceeded,
+
+    /// A CNAME or DNAME chain led back to a name which was already visited.
+    AliasLoop {
+        name: DomainName                // The name which was visited twice.
+    },
+
+    /// A CNAME or DNAME chain was longer than allowed.
+    AliasChainTooLong
+}
+
+impl std::fmt::Display for IterativeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoResponse {
+                zone,
+                source
+            } => match source {
+                Some(source) => write!(f, "no name server for zone {} responded, last error: {}", zone, source),
+                None => write!(f, "no name server for zone {} gave a usable response", zone)
+            },
+            Self::ErrorResponse {
+                zone,
+                response_code
+            } => write!(f, "no name server for zone {} gave a usable response, last response code: {}", zone, response_code),
+            Self::NotAuthoritative {
+                zone
+            } => write!(f, "no name server for zone {} responded authoritatively", zone),
+            Self::NoNameServerAddress {
+                zone
+            } => write!(f, "failed to find the address of any name server for zone {}", zone),
+            Self::MaxQueriesExceeded => write!(f, "maximum number of queries exceeded during resolution"),
+            Self::MaxGluelessDepthExceeded => write!(f, "maximum nesting of name server address resolutions exceeded"),
+            Self::AliasLoop {
+                name
+            } => write!(f, "encountered a CNAME loop at {}", name),
+            Self::AliasChainTooLong => write!(f, "maximum length of a CNAME chain exceeded")
+        }
+    }
+}
+
+impl std::error::Error for IterativeError {}
+
+/// Specialised result type for iterative resolution.
+pub type Result<T> = std::result::Result<T, IterativeError>;
+
+/// A query sent during a traced resolution, see IterativeResolver::trace.
+#[derive(Debug)]
+pub struct TraceStep {
+    pub zone: DomainName,                               // The zone whose name servers were queried
+    pub server: net::SocketAddr,
+    pub question: DnsQuestion,
+    pub depth: usize,                                   // The nesting of glueless name server resolutions
+    pub response_time: Duration,
+    pub response: udp_packet::Result<DnsMessage>
+}
+
+impl TraceStep {
+    /// Returns the zone delegated to by the response, along with the names of its name servers.
+    pub fn referral(&self) -> Option<(DomainName, Vec<DomainName>)> {
+        let response = self.response.as_ref().ok()?;
+        referral(response, &self.zone, &self.question.name)
+        .filter(|_| !response.header.authoritative_answer && response.answers.is_empty())
+    }
+
+    /// Returns the addresses of the name servers of the referral which lie within the zone of the
+    /// queried server, i.e. the glue which is trusted.
+    pub fn glue(&self) -> Vec<(DomainName, net::IpAddr)> {
+        match (&self.response, self.referral()) {
+            (Ok(response), Some((_, name_servers))) => glue(response, &self.zone, &name_servers),
+            _ => Vec::new()
+        }
+    }
+
+    /// Returns true if the server responded, but is evidently not authoritative for its zone, see
+    /// is_lame.
+    pub fn is_lame(&self) -> bool {
+        match &self.response {
+            Ok(response) => is_lame(response, &self.zone, &self.question.name),
+            Err(_) => false
+        }
+    }
+}
+
+/// Glue given by the parent zone which does not match the addresses served by the child zone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GlueMismatch {
+    pub zone: DomainName,                   // The child zone
+    pub name_server: DomainName,
+    pub glue: Vec<net::IpAddr>,             // The addresses given by the parent zone
+    pub authoritative: Vec<net::IpAddr>     // The addresses of the same families given by the child zone
+}
+
+/// The outcome of IterativeResolver::trace.
+#[derive(Debug)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,              // Every query sent, in order
+    pub glue_mismatches: Vec<GlueMismatch>,
+    pub result: Result<DnsMessage>
+}
+
+/// The state of a single resolution, shared with the nested resolutions of glueless name servers.
+#[derive(Debug, Default)]
+struct Resolution {
+    num_queries: usize,
+    depth: usize,                           // The nesting of glueless name server resolutions
+    trace: Option<Vec<TraceStep>>           // The queries sent so far, if they are recorded
+}
+
+/// A resolver which answers questions by itself, starting at the root name servers and
+/// following referrals until an authoritative answer is found.
+#[derive(Debug)]
+pub struct IterativeResolver<T = UdpTransport> {
+    root_hints: Vec<net::IpAddr>,
+    port: u16,              // The port used to contact every name server, only differs from 53 in tests
+    timeout: Duration,
+    qname_minimisation: bool,   // Only reveal one label more than the zone of the queried server (RFC 9156)
+    transport: T
+}
+
+impl Default for IterativeResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IterativeResolver {
+    pub fn new() -> Self {
+        let root_hints = ROOT_HINTS.iter().map(|address| net::IpAddr::V4(*address)).collect();
+        Self::with_root_hints(root_hints, NAME_SERVER_PORT)
+    }
+
+    pub fn with_root_hints(root_hints: Vec<net::IpAddr>, port: u16) -> Self {
+        Self::with_transport(root_hints, port, UdpTransport::default())
+    }
+
+    /// Enables DNS 0x20, see DnsMessage::exchange_with_case_randomisation. Note that some
+    /// authoritative servers do not preserve the case of the question, which makes them unreachable.
+    pub fn set_case_randomisation(&mut self, case_randomisation: bool) {
+        self.transport.case_randomisation = case_randomisation;
+    }
+}
+
+impl<T: Transport> IterativeResolver<T> {
+    pub fn with_transport(root_hints: Vec<net::IpAddr>, port: u16, transport: T) -> Self {
+        Self { root_hints, port, timeout: QUERY_TIMEOUT, qname_minimisation: true, transport }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Enables or disables QNAME minimisation (RFC 9156), which is enabled by default. While enabled,
+    /// every server is only asked for the NS records of the name one label below its own zone, and the
+    /// full question is only sent to the servers of the zone closest to the name.
+    pub fn set_qname_minimisation(&mut self, qname_minimisation: bool) {
+        self.qname_minimisation = qname_minimisation;
+    }
+
+    /// Resolves a question, returning the final (authoritative) response. CNAME:s, including those
+    /// synthesised from DNAME records, are followed, in which case the answer section of the returned
+    /// message holds the whole chain followed by the final answer.
+    pub fn resolve(&self, name: &DomainName, record_type: RecordType, record_class: RecordClass) -> Result<DnsMessage> {
+        self.resolve_with(name, record_type, record_class, &mut Resolution::default())
+    }
+
+    /// Resolves a question like IterativeResolver::resolve, but records every query sent along the
+    /// way, including those for the addresses of glueless name servers. Afterwards, the glue of every
+    /// referral is compared with the addresses served by the child zone.
+    pub fn trace(&self, name: &DomainName, record_type: RecordType, record_class: RecordClass) -> Trace {
+        let mut resolution = Resolution { trace: Some(Vec::new()), ..Default::default() };
+        let result = self.resolve_with(name, record_type, record_class, &mut resolution);
+        let steps = resolution.trace.unwrap_or_default();
+        let glue_mismatches = self.check_glue(&steps);
+        Trace { steps, glue_mismatches, result }
+    }
+
+    fn resolve_with(&self, name: &DomainName, record_type: RecordType, record_class: RecordClass, resolution: &mut Resolution) -> Result<DnsMessage> {
+        let mut chain = Vec::new();
+        let mut visited = vec![name.clone()];
+        let mut current_name = name.clone();
+        loop {
+            let mut response = self.resolve_from_root(&current_name, record_type, record_class, resolution)?;
+            let (records, next_name) = follow_aliases(&response, &current_name, record_type, &mut visited)?;
+            let answered = records.iter().any(|record| record.record_type == record_type);
+            chain.extend(records);
+            if answered || response.header.response_code != ResponseCode::NOERROR || next_name == current_name {
+                response.questions[0].name = name.clone();
+                response.answers = chain;
+                response.update_counts();
+                return Ok(response);
+            }
+            current_name = next_name;
+        }
+    }
+
+    fn resolve_from_root(
+        &self,
+        name: &DomainName,
+        record_type: RecordType,
+        record_class: RecordClass,
+        resolution: &mut Resolution
+    ) -> Result<DnsMessage> {
+        if resolution.depth > MAX_GLUELESS_DEPTH {
+            return Err(IterativeError::MaxGluelessDepthExceeded);
+        }
+        let mut zone = DomainName::root();
+        let mut servers = self.root_hints.to_vec();
+        let labels = name.labels();
+        let mut minimise = self.qname_minimisation;
+        let mut num_labels = 0;     // The number of labels of the name asked for while minimising
+        loop {
+            num_labels = num_labels.max(zone.labels().len()) + 1;
+            let minimised = minimise && num_labels < labels.len();
+            let response = if minimised {
+                let minimised_name = DomainName::from_labels(&labels[(labels.len() - num_labels)..]);
+                match self.query_servers(&zone, &servers, &minimised_name, RecordType::NS, record_class, resolution) {
+                    Ok(response) if response.header.response_code == ResponseCode::NOERROR => response,
+                    // Some servers wrongly answer NXDOMAIN for empty non-terminals, or refuse NS queries
+                    // altogether, so the full name is asked for instead (RFC 9156, section 2.3). Servers
+                    // which did not respond at all are not asked again.
+                    Ok(_) | Err(IterativeError::ErrorResponse { .. } | IterativeError::NotAuthoritative { .. }) => {
+                        minimise = false;
+                        continue;
+                    },
+                    Err(error) => return Err(error)
+                }
+            } else {
+                self.query_servers(&zone, &servers, name, record_type, record_class, resolution)?
+            };
+            let referral = referral(&response, &zone, name)
+            .filter(|_| !response.header.authoritative_answer && response.answers.is_empty());
+            let (child_zone, name_servers) = match referral {
+                Some(referral) => referral,
+                None if minimised => {
+                    // There is no zone cut at the minimised name, so the same servers are asked for the
+                    // next label, unless the answer holds something unexpected, e.g. an alias.
+                    if !response.answers.iter().all(|record| record.record_type == RecordType::NS) {
+                        minimise = false;
+                    }
+                    continue;
+                },
+                None => return Ok(response)
+            };
+
+            let mut addresses: Vec<net::IpAddr> = glue(&response, &zone, &name_servers).into_iter()
+            .map(|(_, address)| address)
+            .collect();
+            if addresses.is_empty() {
+                addresses = self.resolve_glueless(&name_servers, resolution)?;
+            }
+            if addresses.is_empty() {
+                return Err(IterativeError::NoNameServerAddress { zone: child_zone });
+            }
+            zone = child_zone;
+            servers = addresses;
+        }
+    }
+
+    /// Looks up the addresses of name servers for which no glue was given, stopping at the
+    /// first name server whose address could be found.
+    fn resolve_glueless(&self, name_servers: &[DomainName], resolution: &mut Resolution) -> Result<Vec<net::IpAddr>> {
+        for name_server in name_servers {
+            resolution.depth += 1;
+            let result = self.resolve_from_root(name_server, RecordType::A, RecordClass::IN, resolution);
+            resolution.depth -= 1;
+            let response = match result {
+                Ok(response) => response,
+                Err(error @ (IterativeError::MaxQueriesExceeded | IterativeError::MaxGluelessDepthExceeded)) => return Err(error),
+                Err(_) => continue
+            };
+            let addresses: Vec<net::IpAddr> = response.answers.iter()
+            .filter_map(|record| match record.data {
+                RecordData::A { ipv4_address } => Some(net::IpAddr::V4(ipv4_address)),
+                _ => None
+            })
+            .collect();
+            if !addresses.is_empty() {
+                return Ok(addresses);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// Sends a non-recursive query to each server in turn, returning the first usable response.
+    fn query_servers(
+        &self,
+        zone: &DomainName,
+        servers: &[net::IpAddr],
+        name: &DomainName,
+        record_type: RecordType,
+        record_class: RecordClass,
+        resolution: &mut Resolution
+    ) -> Result<DnsMessage> {
+        let query = non_recursive_query(name, record_type, record_class);
+        let mut last_error = None;
+        let mut last_response_code = None;
+        let mut lame = false;
+        for server in servers {
+            if resolution.num_queries >= MAX_QUERIES {
+                return Err(IterativeError::MaxQueriesExceeded);
+            }
+            resolution.num_queries += 1;
+            let server = net::SocketAddr::new(*server, self.port);
+            let start = Instant::now();
+            let result = self.transport.exchange(&query, server, self.timeout);
+            if let Some(trace) = resolution.trace.as_mut() {
+                trace.push(TraceStep {
+                    zone: zone.clone(),
+                    server,
+                    question: query.questions[0].clone(),
+                    depth: resolution.depth,
+                    response_time: start.elapsed(),
+                    response: match &result {
+                        Ok(response) => Ok(response.clone()),
+                        // Errors cannot be cloned, so the step gets an equivalent one.
+                        Err(error) => Err(udp_packet::UdpPacketError::NetworkIo {
+                            description: error.to_string(),
+                            source: std::io::Error::from(match error.is_timeout() {
+                                true => std::io::ErrorKind::TimedOut,
+                                false => std::io::ErrorKind::Other
+                            })
+                        })
+                    }
+                });
+            }
+            match result {
+                // Lame or broken servers are skipped in favour of the other servers of the zone.
+                Ok(response) => match response.header.response_code {
+                    ResponseCode::NOERROR | ResponseCode::NAMEERROR if !is_lame(&response, zone, name) => return Ok(response),
+                    ResponseCode::NOERROR | ResponseCode::NAMEERROR => {
+                        log::debug!("{} answered {} {} without authority, trying the next server of {}", server, name, record_type, zone);
+                        lame = true;
+                    },
+                    response_code => {
+                        log::debug!("{} answered {} {} with {}, trying the next server of {}", server, name, record_type, response_code, zone);
+                        last_response_code = Some(response_code);
+                    }
+                },
+                Err(error) => {
+                    log::debug!("no response from {} for {} {}: {}", server, name, record_type, error);
+                    last_error = Some(error);
+                }
+            }
+        }
+        match (last_response_code, lame) {
+            (Some(response_code), _) => Err(IterativeError::ErrorResponse { zone: zone.clone(), response_code }),
+            (None, true) => Err(IterativeError::NotAuthoritative { zone: zone.clone() }),
+            (None, false) => Err(IterativeError::NoResponse { zone: zone.clone(), source: last_error })
+        }
+    }
+
+    /// Compares the glue of every referral in `steps` with the addresses served by the child zone,
+    /// which are asked for at the first server of the child zone that responded properly. Only glue
+    /// within the child zone is checked, and only authoritative answers count.
+    fn check_glue(&self, steps: &[TraceStep]) -> Vec<GlueMismatch> {
+        let mut mismatches = Vec::new();
+        let mut checked: Vec<(DomainName, DomainName)> = Vec::new();
+        for (index, step) in steps.iter().enumerate() {
+            let child_zone = match step.referral() {
+                Some((child_zone, _)) => child_zone,
+                None => continue
+            };
+            let server = steps[(index + 1)..].iter()
+            .find(|later| later.zone == child_zone && later.response.is_ok() && !later.is_lame())
+            .map(|later| later.server);
+            let server = match server {
+                Some(server) => server,
+                None => continue
+            };
+            let glue = step.glue();
+            for (name_server, _) in glue.iter().filter(|(name_server, _)| name_server.is_subdomain_of(&child_zone)) {
+                let key = (child_zone.clone(), name_server.clone());
+                if checked.contains(&key) {
+                    continue;
+                }
+                checked.push(key);
+                let glue_addresses: Vec<net::IpAddr> = glue.iter()
+                .filter(|(glued_name, _)| glued_name == name_server)
+                .map(|(_, address)| *address)
+                .collect();
+                let mut authoritative = Vec::new();
+                let mut mismatched = false;
+                for (record_type, is_ipv4) in [(RecordType::A, true), (RecordType::AAAA, false)] {
+                    let mut expected: Vec<net::IpAddr> = glue_addresses.iter().copied()
+                    .filter(|address| address.is_ipv4() == is_ipv4)
+                    .collect();
+                    if expected.is_empty() {
+                        continue;
+                    }
+                    let mut actual = match self.authoritative_addresses(name_server, record_type, server) {
+                        Some(actual) => actual,
+                        None => continue
+                    };
+                    expected.sort();
+                    expected.dedup();
+                    actual.sort();
+                    actual.dedup();
+                    mismatched |= expected != actual;
+                    authoritative.extend(actual);
+                }
+                if mismatched {
+                    mismatches.push(GlueMismatch {
+                        zone: child_zone.clone(),
+                        name_server: name_server.clone(),
+                        glue: glue_addresses,
+                        authoritative
+                    });
+                }
+            }
+        }
+        mismatches
+    }
+
+    /// Asks `server` for the addresses of `name`, returning None unless it answers authoritatively.
+    fn authoritative_addresses(&self, name: &DomainName, record_type: RecordType, server: net::SocketAddr) -> Option<Vec<net::IpAddr>> {
+        let query = non_recursive_query(name, record_type, RecordClass::IN);
+        let response = self.transport.exchange(&query, server, self.timeout).ok()?;
+        if !response.header.authoritative_answer || response.header.response_code != ResponseCode::NOERROR {
+            return None;
+        }
+        Some(response.answers.iter()
+        .filter(|record| record.name == *name)
+        .filter_map(|record| match record.data {
+            RecordData::A { ipv4_address } => Some(net::IpAddr::V4(ipv4_address)),
+            RecordData::AAAA { ipv6_address } => Some(net::IpAddr::V6(ipv6_address)),
+            _ => None
+        })
+        .collect())
+    }
+}
+
+fn non_recursive_query(name: &DomainName, record_type: RecordType, record_class: RecordClass) -> DnsMessage {
+    DnsMessage {
+        header: DnsHeader {
+            recursion_desired: false,
+            ..Default::default()
+        },
+        questions: vec![DnsQuestion {
+            name: name.clone(),
+            question_type: CombinedType::RecordType(record_type),
+            question_class: CombinedClass::RecordClass(record_class)
+        }],
+        ..Default::default()
+    }
+}
+
+/// Returns the addresses of `name_servers` in the additional section of `response`. Glue is only
+/// trusted if it lies within the zone of the server which sent it.
+fn glue(response: &DnsMessage, zone: &DomainName, name_servers: &[DomainName]) -> Vec<(DomainName, net::IpAddr)> {
+    response.additional.iter()
+    .filter(|record| record.name.is_subdomain_of(zone))
+    .filter(|record| name_servers.contains(&record.name))
+    .filter_map(|record| match record.data {
+        RecordData::A { ipv4_address } => Some((record.name.clone(), net::IpAddr::V4(ipv4_address))),
+        RecordData::AAAA { ipv6_address } => Some((record.name.clone(), net::IpAddr::V6(ipv6_address))),
+        _ => None
+    })
+    .collect()
+}
+
+/// Follows the CNAME and DNAME records in the answer section of `response`, starting at `name`.
+/// Returns the records of the chain, including any synthesised CNAME:s and the final answer if
+/// present, along with the name at the end of the chain. Every name reached is added to `visited`.
+fn follow_aliases(
+    response: &DnsMessage,
+    name: &DomainName,
+    record_type: RecordType,
+    visited: &mut Vec<DomainName>
+) -> Result<(Vec<DnsRecord>, DomainName)> {
+    let mut records = Vec::new();
+    let mut name = name.clone();
+    loop {
+        let answers: Vec<&DnsRecord> = response.answers.iter()
+        .filter(|record| record.record_type == record_type && record.name == name)
+        .collect();
+        if !answers.is_empty() {
+            records.extend(answers.into_iter().cloned());
+            return Ok((records, name));
+        }
+        if record_type == RecordType::CNAME || record_type == RecordType::DNAME {
+            return Ok((records, name));
+        }
+        let dname = response.answers.iter().find_map(|record| match &record.data {
+            RecordData::DNAME { target } => {
+                name.replace_suffix(&record.name, target).map(|canonical_name| (record, canonical_name))
+            },
+            _ => None
+        });
+        let cname = response.answers.iter().find(|record| {
+            record.record_type == RecordType::CNAME && record.name == name
+        });
+        let canonical_name = match (dname, cname) {
+            (Some((dname, canonical_name)), cname) => {
+                records.push(dname.clone());
+                // Servers usually include the synthesised CNAME, but it is created here if not.
+                records.push(match cname {
+                    Some(cname) => cname.clone(),
+                    None => DnsRecord {
+                        name: name.clone(),
+                        record_type: RecordType::CNAME,
+                        record_class: dname.record_class,
+                        ttl: dname.ttl,
+                        length: canonical_name.bytes.len() as u16,
+                        data: RecordData::CNAME { canonical_name: canonical_name.clone() }
+                    }
+                });
+                canonical_name
+            },
+            (None, Some(cname)) => match &cname.data {
+                RecordData::CNAME { canonical_name } => {
+                    records.push(cname.clone());
+                    canonical_name.clone()
+                },
+                _ => return Ok((records, name))
+            },
+            (None, None) => return Ok((records, name))
+        };
+        if visited.contains(&canonical_name) {
+            return Err(IterativeError::AliasLoop { name: canonical_name });
+        }
+        if visited.len() > MAX_ALIAS_CHAIN_LENGTH {
+            return Err(IterativeError::AliasChainTooLong);
+        }
+        visited.push(canonical_name.clone());
+        name = canonical_name;
+    }
+}
+
+/// Returns true if `response`, from a server of `zone` asked about `name`, shows that the server is
+/// not authoritative for the zone: it refused or failed to answer, or it neither answered
+/// authoritatively, nor gave a negative answer with the SOA record of a zone enclosing `name`, nor
+/// referred to a child zone. Such responses, e.g. from caches, are never taken as the final answer.
+fn is_lame(response: &DnsMessage, zone: &DomainName, name: &DomainName) -> bool {
+    match response.header.response_code {
+        ResponseCode::NOERROR | ResponseCode::NAMEERROR => {
+            let referral = referral(response, zone, name).filter(|_| response.answers.is_empty());
+            let negative = response.answers.is_empty() && response.authorities.iter().any(|record| {
+                record.record_type == RecordType::SOA && record.name.is_subdomain_of(zone) && name.is_subdomain_of(&record.name)
+            });
+            !response.header.authoritative_answer && referral.is_none() && !negative
+        },
+        _ => true
+    }
+}
+
+/// Extracts a referral from `response`, i.e. the closest zone below `zone` which encloses `name`,
+/// along with the names of its name servers.
+fn referral(response: &DnsMessage, zone: &DomainName, name: &DomainName) -> Option<(DomainName, Vec<DomainName>)> {
+    let child_zone = response.authorities.iter()
+    .filter(|record| record.record_type == RecordType::NS)
+    .map(|record| &record.name)
+    .filter(|child_zone| child_zone.is_subdomain_of(zone) && *child_zone != zone)
+    .filter(|child_zone| name.is_subdomain_of(child_zone))
+    .max_by_key(|child_zone| child_zone.labels().len())?;
+    let name_servers = response.authorities.iter()
+    .filter(|record| record.name == *child_zone)
+    .filter_map(|record| match &record.data {
+        RecordData::NS { domain_name } => Some(domain_name.clone()),
+        _ => None
+    })
+    .collect();
+    Some((child_zone.clone(), name_servers))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_message::*;
+    use crate::iterative::*;
+    use crate::server::{QueryHandler, Server};
+    use crate::transport::{MockAction, MockTransport, UdpTransport};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    // The test hierarchy consists of a root server on 127.0.0.1, a com server on 127.0.0.2,
+    // an example.com server on 127.0.0.3 and a glueless.com server on 127.0.0.4. Since referrals
+    // cannot carry ports, and only Linux routes all of 127.0.0.0/8 to the loopback interface, these
+    // addresses are only used in referrals: every server listens on its own port of 127.0.0.1, to
+    // which PortMapping sends the queries for its address.
+
+    /// A Transport which sends the queries for each test address to a port of 127.0.0.1.
+    #[derive(Default)]
+    struct PortMapping {
+        ports: HashMap<net::IpAddr, u16>,
+        transport: UdpTransport
+    }
+
+    impl PortMapping {
+        /// Starts a server on a port of 127.0.0.1 answering the queries for `address`.
+        fn serve<H: QueryHandler + 'static>(&mut self, address: [u8; 4], handler: H) {
+            let server = Server::bind((net::Ipv4Addr::LOCALHOST, 0), handler).unwrap();
+            self.ports.insert(net::IpAddr::from(address), server.local_addr().unwrap().port());
+            server.spawn();
+        }
+    }
+
+    impl Transport for PortMapping {
+        fn exchange(&self, query: &DnsMessage, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<DnsMessage> {
+            let port = self.ports.get(&server.ip()).copied().expect("Queries are only sent to test addresses.");
+            self.transport.exchange(query, net::SocketAddr::from((net::Ipv4Addr::LOCALHOST, port)), timeout)
+        }
+    }
+
+    fn name(name: &str) -> DomainName {
+        DomainName::from_str(name).unwrap()
+    }
+
+    fn record(owner: &str, data: RecordData) -> DnsRecord {
+        let record_type = match data {
+            RecordData::A { .. } => RecordType::A,
+            RecordData::NS { .. } => RecordType::NS,
+            RecordData::SOA { .. } => RecordType::SOA,
+            RecordData::DNAME { .. } => RecordType::DNAME,
+            _ => RecordType::CNAME
+        };
+        DnsRecord { name: name(owner), record_type, record_class: RecordClass::IN, ttl: 3600, length: 0, data }
+    }
+
+    fn ns(owner: &str, target: &str) -> DnsRecord {
+        record(owner, RecordData::NS { domain_name: name(target) })
+    }
+
+    fn a(owner: &str, address: [u8; 4]) -> DnsRecord {
+        record(owner, RecordData::A { ipv4_address: net::Ipv4Addr::from(address) })
+    }
+
+    fn referral_response(query: &DnsMessage, authorities: Vec<DnsRecord>, additional: Vec<DnsRecord>) -> DnsMessage {
+        let mut response = query.reply(ResponseCode::NOERROR);
+        response.authorities = authorities;
+        response.additional = additional;
+        response.update_counts();
+        response
+    }
+
+    fn authoritative_response(query: &DnsMessage, answers: Vec<DnsRecord>) -> DnsMessage {
+        let mut response = query.reply(ResponseCode::NOERROR);
+        response.header.authoritative_answer = true;
+        if answers.is_empty() {
+            response.header.response_code = ResponseCode::NAMEERROR;
+        }
+        response.answers = answers;
+        response.update_counts();
+        response
+    }
+
+    fn start_hierarchy() -> PortMapping {
+        let mut servers = PortMapping::default();
+        servers.serve([127, 0, 0, 1], |query: &DnsMessage| {
+            referral_response(query, vec![ns("com", "ns.com")], vec![a("ns.com", [127, 0, 0, 2])])
+        });
+
+        servers.serve([127, 0, 0, 2], |query: &DnsMessage| {
+            if query.questions[0].name.is_subdomain_of(&name("glueless.com")) {
+                referral_response(query, vec![ns("glueless.com", "ns.example.com")], Vec::new())
+            } else {
+                // The glue for ns.example.net is out of bailiwick and must be ignored.
+                referral_response(
+                    query,
+                    vec![ns("example.com", "ns.example.com"), ns("example.com", "ns.example.net")],
+                    vec![a("ns.example.com", [127, 0, 0, 3]), a("ns.example.net", [192, 0, 2, 99])]
+                )
+            }
+        });
+
+        servers.serve([127, 0, 0, 3], |query: &DnsMessage| {
+            let question = &query.questions[0];
+            if question.name.is_subdomain_of(&name("old.example.com")) {
+                return authoritative_response(query, vec![
+                    record("old.example.com", RecordData::DNAME { target: name(TEST_DOMAIN) })
+                ]);
+            }
+            let answers = match question.name.to_string().to_ascii_lowercase().as_str() {
+                "www.example.com." => vec![a("www.example.com", [192, 0, 2, 1])],
+                "ns.example.com." => vec![a("ns.example.com", [127, 0, 0, 4])],
+                "alias.example.com." => vec![record("alias.example.com", RecordData::CNAME { canonical_name: name("www.glueless.com") })],
+                "loop1.example.com." => vec![record("loop1.example.com", RecordData::CNAME { canonical_name: name("loop2.example.com") })],
+                "loop2.example.com." => vec![record("loop2.example.com", RecordData::CNAME { canonical_name: name("loop1.example.com") })],
+                _ => Vec::new()
+            };
+            authoritative_response(query, answers)
+        });
+
+        servers.serve([127, 0, 0, 4], |query: &DnsMessage| {
+            authoritative_response(query, vec![a("www.glueless.com", [192, 0, 2, 2])])
+        });
+        servers
+    }
+
+    fn resolver(servers: PortMapping) -> IterativeResolver<PortMapping> {
+        let mut resolver = IterativeResolver::with_transport(vec![net::IpAddr::from([127, 0, 0, 1])], NAME_SERVER_PORT, servers);
+        resolver.set_timeout(Duration::from_millis(500));
+        resolver
+    }
+
+    #[test]
+    fn referral_with_glue_test() {
+        let resolver = resolver(start_hierarchy());
+        let response = resolver.resolve(&name("www.example.com"), RecordType::A, RecordClass::IN)
+        .expect("Failed to resolve.");
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].data, a("www.example.com", [192, 0, 2, 1]).data);
+
+        let response = resolver.resolve(&name("missing.example.com"), RecordType::A, RecordClass::IN)
+        .expect("Failed to resolve.");
+        assert_eq!(response.header.response_code, ResponseCode::NAMEERROR);
+    }
+
+    #[test]
+    fn glueless_referral_test() {
+        let response = resolver(start_hierarchy()).resolve(&name("www.glueless.com"), RecordType::A, RecordClass::IN)
+        .expect("Failed to resolve.");
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].data, a("www.glueless.com", [192, 0, 2, 2]).data);
+    }
+
+    #[test]
+    fn cname_chain_test() {
+        // The stub servers echo the question as-is, so DNS 0x20 must not get in the way.
+        let mut servers = start_hierarchy();
+        servers.transport.case_randomisation = true;
+        let resolver = resolver(servers);
+        let response = resolver.resolve(&name("alias.example.com"), RecordType::A, RecordClass::IN)
+        .expect("Failed to resolve.");
+        assert_eq!(response.questions[0].name, name("alias.example.com"));
+        let types: Vec<RecordType> = response.answers.iter().map(|record| record.record_type).collect();
+        assert_eq!(types, vec![RecordType::CNAME, RecordType::A]);
+        assert_eq!(response.answers[1].name, name("www.glueless.com"));
+    }
+
+    #[test]
+    fn dname_synthesis_test() {
+        let response = resolver(start_hierarchy()).resolve(&name("www.old.example.com"), RecordType::A, RecordClass::IN)
+        .expect("Failed to resolve.");
+        let types: Vec<RecordType> = response.answers.iter().map(|record| record.record_type).collect();
+        assert_eq!(types, vec![RecordType::DNAME, RecordType::CNAME, RecordType::A]);
+        assert_eq!(response.answers[1].data, RecordData::CNAME { canonical_name: name("www.example.com") });
+        assert_eq!(response.answers[2].data, a("www.example.com", [192, 0, 2, 1]).data);
+    }
+
+    #[test]
+    fn cname_loop_test() {
+        let result = resolver(start_hierarchy()).resolve(&name("loop1.example.com"), RecordType::A, RecordClass::IN);
+        assert!(matches!(result, Err(IterativeError::AliasLoop { .. })));
+    }
+
+    #[test]
+    fn trace_test() {
+        let trace = resolver(start_hierarchy()).trace(&name("www.example.com"), RecordType::A, RecordClass::IN);
+        assert_eq!(trace.result.expect("Failed to resolve.").answers.len(), 1);
+        let zones: Vec<String> = trace.steps.iter().map(|step| step.zone.to_string()).collect();
+        assert_eq!(zones, vec![".", "com.", "example.com."]);
+        assert!(trace.steps.iter().all(|step| !step.is_lame()));
+        assert_eq!(trace.steps[1].referral(), Some((name(TEST_DOMAIN), vec![name("ns.example.com"), name("ns.example.net")])));
+        assert_eq!(trace.steps[1].glue(), vec![(name("ns.example.com"), net::IpAddr::from([127, 0, 0, 3]))]);
+        // The example.com server claims that its own name server is on 127.0.0.4.
+        assert_eq!(trace.glue_mismatches, vec![GlueMismatch {
+            zone: name(TEST_DOMAIN),
+            name_server: name("ns.example.com"),
+            glue: vec![net::IpAddr::from([127, 0, 0, 3])],
+            authoritative: vec![net::IpAddr::from([127, 0, 0, 4])]
+        }]);
+    }
+
+    #[test]
+    fn lame_delegation_test() {
+        // lame.test is delegated to 127.0.0.2, which refuses to answer, and to 127.0.0.3, which is
+        // authoritative for it.
+        let mut servers = PortMapping::default();
+        servers.serve([127, 0, 0, 1], |query: &DnsMessage| {
+            referral_response(
+                query,
+                vec![ns("lame.test", "ns1.lame.test"), ns("lame.test", "ns2.lame.test")],
+                vec![a("ns1.lame.test", [127, 0, 0, 2]), a("ns2.lame.test", [127, 0, 0, 3])]
+            )
+        });
+        servers.serve([127, 0, 0, 2], |query: &DnsMessage| query.reply(ResponseCode::REFUSED));
+        servers.serve([127, 0, 0, 3], |query: &DnsMessage| {
+            authoritative_response(query, vec![a("www.lame.test", [192, 0, 2, 4])])
+        });
+
+        let mut resolver = resolver(servers);
+        resolver.set_qname_minimisation(false);
+        let trace = resolver.trace(&name("www.lame.test"), RecordType::A, RecordClass::IN);
+        assert!(trace.result.is_ok());
+        let lame: Vec<bool> = trace.steps.iter().map(TraceStep::is_lame).collect();
+        assert_eq!(lame, vec![false, true, false]);
+        assert_eq!(trace.steps[1].server, net::SocketAddr::from(([127, 0, 0, 2], NAME_SERVER_PORT)));
+    }
+
+    #[test]
+    fn non_authoritative_answer_test() {
+        // test is delegated to 127.0.0.2, which answers from a cache without the AA bit, and to
+        // 127.0.0.3, which is authoritative for it.
+        let mut servers = PortMapping::default();
+        servers.serve([127, 0, 0, 1], |query: &DnsMessage| {
+            referral_response(
+                query,
+                vec![ns("test", "ns1.test"), ns("test", "ns2.test")],
+                vec![a("ns1.test", [127, 0, 0, 2]), a("ns2.test", [127, 0, 0, 3])]
+            )
+        });
+        servers.serve([127, 0, 0, 2], |query: &DnsMessage| {
+            let mut response = authoritative_response(query, vec![a("www.test", [192, 0, 2, 66])]);
+            response.header.authoritative_answer = false;
+            response
+        });
+        servers.serve([127, 0, 0, 3], |query: &DnsMessage| {
+            authoritative_response(query, vec![a("www.test", [192, 0, 2, 5])])
+        });
+
+        let mut resolver = resolver(servers);
+        resolver.set_qname_minimisation(false);
+        let trace = resolver.trace(&name("www.test"), RecordType::A, RecordClass::IN);
+        let response = trace.result.expect("Failed to resolve.");
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers[0].data, a("www.test", [192, 0, 2, 5]).data);
+        let lame: Vec<bool> = trace.steps.iter().map(TraceStep::is_lame).collect();
+        assert_eq!(lame, vec![false, true, false]);
+    }
+
+    #[test]
+    fn not_authoritative_test() {
+        // A negative answer counts as authoritative if it carries the SOA record of the zone.
+        let transport = MockTransport::new();
+        let mut negative = DnsMessage::default();
+        negative.header.response_code = ResponseCode::NAMEERROR;
+        negative.authorities.push(record("test", RecordData::SOA {
+            domain_name: name("ns.test"),
+            mailbox_address: name("hostmaster.test"),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300
+        }));
+        transport.script(question("missing.test", RecordType::A), vec![MockAction::Respond(negative)]);
+        transport.script(question("cached.test", RecordType::A), vec![MockAction::Respond(DnsMessage {
+            answers: vec![a("cached.test", [192, 0, 2, 6])],
+            ..Default::default()
+        })]);
+        let mut resolver = IterativeResolver::with_transport(vec![net::IpAddr::from([192, 0, 2, 53])], NAME_SERVER_PORT, transport);
+        resolver.set_qname_minimisation(false);
+        let response = resolver.resolve(&name("missing.test"), RecordType::A, RecordClass::IN)
+        .expect("Failed to resolve.");
+        assert_eq!(response.header.response_code, ResponseCode::NAMEERROR);
+        let result = resolver.resolve(&name("cached.test"), RecordType::A, RecordClass::IN);
+        assert!(matches!(result, Err(IterativeError::NotAuthoritative { .. })));
+    }
+
+    /// Starts a single root server which is authoritative for a.b.c.test and answers minimised (NS)
+    /// queries for its ancestors with `minimised_response_code`, logging every question it receives.
+    fn start_minimisation_server(minimised_response_code: ResponseCode) -> (PortMapping, Arc<Mutex<Vec<String>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let server_log = Arc::clone(&log);
+        let mut servers = PortMapping::default();
+        servers.serve([127, 0, 0, 1], move |query: &DnsMessage| {
+            let question = &query.questions[0];
+            server_log.lock().unwrap().push(format!("{} {}", question.name, question.question_type));
+            let mut response = match question.question_type {
+                CombinedType::RecordType(RecordType::NS) => query.reply(minimised_response_code),
+                _ => authoritative_response(query, vec![a("a.b.c.test", [192, 0, 2, 3])])
+            };
+            response.header.authoritative_answer = true;
+            response
+        });
+        (servers, log)
+    }
+
+    fn minimisation_log(minimised_response_code: ResponseCode, qname_minimisation: bool) -> Vec<String> {
+        let (servers, log) = start_minimisation_server(minimised_response_code);
+        let mut resolver = resolver(servers);
+        resolver.set_qname_minimisation(qname_minimisation);
+        let response = resolver.resolve(&name("a.b.c.test"), RecordType::A, RecordClass::IN)
+        .expect("Failed to resolve.");
+        assert_eq!(response.answers[0].data, a("a.b.c.test", [192, 0, 2, 3]).data);
+        let log = log.lock().unwrap().clone();
+        log
+    }
+
+    #[test]
+    fn qname_minimisation_test() {
+        assert_eq!(minimisation_log(ResponseCode::NOERROR, true), vec![
+            "test. NS", "c.test. NS", "b.c.test. NS", "a.b.c.test. A"
+        ]);
+        assert_eq!(minimisation_log(ResponseCode::NOERROR, false), vec!["a.b.c.test. A"]);
+    }
+
+    #[test]
+    fn qname_minimisation_fallback_test() {
+        assert_eq!(minimisation_log(ResponseCode::NAMEERROR, true), vec!["test. NS", "a.b.c.test. A"]);
+        assert_eq!(minimisation_log(ResponseCode::REFUSED, true), vec!["test. NS", "a.b.c.test. A"]);
+        assert_eq!(minimisation_log(ResponseCode::NOTIMPLEMENTED, true), vec!["test. NS", "a.b.c.test. A"]);
+    }
+
+    fn question(name: &str, record_type: RecordType) -> DnsQuestion {
+        DnsQuestion { name: self::name(name), question_type: CombinedType::RecordType(record_type), ..Default::default() }
+    }
+
+    #[test]
+    fn qname_minimisation_timeout_test() {
+        // A server which does not respond to the minimised query is not asked for the full name.
+        let transport = MockTransport::new();
+        transport.script(question("test", RecordType::NS), vec![MockAction::Drop]);
+        let resolver = IterativeResolver::with_transport(vec![net::IpAddr::from([192, 0, 2, 53])], NAME_SERVER_PORT, transport);
+        let result = resolver.resolve(&name("a.b.c.test"), RecordType::A, RecordClass::IN);
+        assert!(matches!(result, Err(IterativeError::NoResponse { source: Some(_), .. })));
+        assert_eq!(resolver.transport.queries().len(), 1);
+    }
+
+    #[test]
+    fn glueless_depth_test() {
+        // Every zone is delegated to a name server within itself, without glue, so that finding the
+        // address of each name server requires resolving the next one.
+        let transport = MockTransport::new();
+        let mut owner = String::from("www.test");
+        for _ in 0..=MAX_GLUELESS_DEPTH {
+            let mut referral = DnsMessage::default();
+            referral.authorities.push(ns(&owner, &format!("ns.{}", owner)));
+            transport.script(question(&owner, RecordType::A), vec![MockAction::Respond(referral)]);
+            owner = format!("ns.{}", owner);
+        }
+        let mut resolver = IterativeResolver::with_transport(vec![net::IpAddr::from([192, 0, 2, 53])], NAME_SERVER_PORT, transport);
+        resolver.set_qname_minimisation(false);
+        let result = resolver.resolve(&name("www.test"), RecordType::A, RecordClass::IN);
+        assert!(matches!(result, Err(IterativeError::MaxGluelessDepthExceeded)));
+    }
+}
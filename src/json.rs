@@ -0,0 +1,391 @@
+use crate::dns_message::{CombinedType, DnsMessage, DnsQuestion, DnsRecord, RecordClass, RecordData, RecordType, ResponseCode, AUTHENTIC_DATA, CHECKING_DISABLED};
+use crate::format;
+use crate::udp_packet::{CharacterString, DomainName};
+use serde_json::{json, Map, Value};
+use std::net;
+use std::str::FromStr;
+
+pub const CONTENT_TYPE: &str = "application/dns-json";
+
+/// Error handling type for the JSON format.
+#[derive(Debug)]
+pub enum JsonError {
+    /// The input is not valid JSON.
+    Syntax {
+        source: serde_json::Error       // The underlying error.
+    },
+
+    /// A field which is required is missing or has the wrong JSON type.
+    MissingField {
+        field: String                   // The name of the field.
+    },
+
+    /// A field has a value which cannot be represented in a DnsMessage.
+    InvalidValue {
+        field: String,                  // The name of the field.
+        value: String                   // The offending value.
+    }
+}
+
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Syntax {
+                source
+            } => write!(f, "invalid JSON, source: {}", source),
+            Self::MissingField {
+                field
+            } => write!(f, "missing field {}", field),
+            Self::InvalidValue {
+                field,
+                value
+            } => write!(f, "invalid value {} of field {}", value, field)
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(source: serde_json::Error) -> Self {
+        Self::Syntax { source }
+    }
+}
+
+/// Specialised result type for the JSON format.
+pub type Result<T> = std::result::Result<T, JsonError>;
+
+/// The JSON format of the DNS over HTTPS APIs of Google and Cloudflare, as in
+/// {"Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+///  "Question": [{"name": "example.com.", "type": 1}],
+///  "Answer": [{"name": "example.com.", "type": 1, "TTL": 300, "data": "192.0.2.1"}]}
+/// Empty record sections are left out, and the class of questions and records is always IN.
+impl DnsMessage {
+    pub fn to_json(&self) -> String {
+        self.to_json_value().to_string()
+    }
+
+    pub fn to_json_value(&self) -> Value {
+        let mut object = Map::new();
+        object.insert(String::from("Status"), json!(TryInto::<u16>::try_into(self.header.response_code).unwrap()));
+        object.insert(String::from("TC"), json!(self.header.truncated));
+        object.insert(String::from("RD"), json!(self.header.recursion_desired));
+        object.insert(String::from("RA"), json!(self.header.recursion_available));
+        object.insert(String::from("AD"), json!(self.header.z & AUTHENTIC_DATA != 0));
+        object.insert(String::from("CD"), json!(self.header.z & CHECKING_DISABLED != 0));
+        let questions: Vec<Value> = self.questions.iter().map(|question| json!({
+            "name": question.name.to_string(),
+            "type": TryInto::<u16>::try_into(question.question_type).unwrap()
+        })).collect();
+        object.insert(String::from("Question"), Value::Array(questions));
+        for (section, records) in [("Answer", &self.answers), ("Authority", &self.authorities), ("Additional", &self.additional)] {
+            if !records.is_empty() {
+                object.insert(String::from(section), Value::Array(records.iter().map(record_to_json).collect()));
+            }
+        }
+        Value::Object(object)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Self::from_json_value(&serde_json::from_str(json)?)
+    }
+
+    /// The inverse of to_json_value. The message is a response whose id is 0.
+    pub fn from_json_value(value: &Value) -> Result<Self> {
+        let status = u64_field(value, "Status")?;
+        let mut message = DnsMessage::default();
+        message.header.response = true;
+        message.header.response_code = u16::try_from(status).ok()
+        .and_then(|status| ResponseCode::try_from(status).ok())
+        .ok_or_else(|| invalid_value("Status", status))?;
+        message.header.truncated = bool_field(value, "TC")?;
+        message.header.recursion_desired = bool_field(value, "RD")?;
+        message.header.recursion_available = bool_field(value, "RA")?;
+        if bool_field(value, "AD")? {
+            message.header.z |= AUTHENTIC_DATA;
+        }
+        if bool_field(value, "CD")? {
+            message.header.z |= CHECKING_DISABLED;
+        }
+        message.questions = array_field(value, "Question")?.iter().map(|question| {
+            let question_type = u64_field(question, "type")?;
+            Ok(DnsQuestion {
+                name: name_field(question, "name")?,
+                question_type: u16::try_from(question_type).ok()
+                .and_then(|question_type| CombinedType::try_from(question_type).ok())
+                .ok_or_else(|| invalid_value("type", question_type))?,
+                ..Default::default()
+            })
+        }).collect::<Result<_>>()?;
+        message.answers = records_from_json(value, "Answer")?;
+        message.authorities = records_from_json(value, "Authority")?;
+        message.additional = records_from_json(value, "Additional")?;
+        message.update_counts();
+        Ok(message)
+    }
+}
+
+/// Builds a query from the URI query of a request to a JSON API, e.g. name=example.com&type=AAAA.
+/// The type is a mnemonic or a number and defaults to A, and cd=1 sets the CD bit. Other
+/// parameters, such as the DNSSEC OK bit and the content type, are ignored.
+pub fn query_from_parameters(parameters: &str) -> Result<DnsMessage> {
+    let mut query = DnsMessage::default();
+    let mut name = None;
+    for parameter in parameters.split('&').filter(|parameter| !parameter.is_empty()) {
+        let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+        let value = percent_decode(value).ok_or_else(|| invalid_value(key, value))?;
+        match key {
+            "name" => name = Some(DomainName::from_str(&value).map_err(|_| invalid_value(key, &value))?),
+            "type" => query.questions[0].question_type = value.parse::<u16>().ok()
+            .and_then(|question_type| CombinedType::try_from(question_type).ok())
+            .or_else(|| CombinedType::from_str(&value.to_ascii_uppercase()).ok())
+            .ok_or_else(|| invalid_value(key, &value))?,
+            "cd" if value == "1" || value == "true" => query.header.z |= CHECKING_DISABLED,
+            _ => ()
+        }
+    }
+    query.questions[0].name = name.ok_or_else(|| missing_field("name"))?;
+    Ok(query)
+}
+
+/// Decodes the %XX escapes of a URI component, as well as + for spaces.
+fn percent_decode(component: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut iter = component.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            b'+' => bytes.push(b' '),
+            byte => bytes.push(byte)
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn record_to_json(record: &DnsRecord) -> Value {
+    json!({
+        "name": record.name.to_string(),
+        "type": TryInto::<u16>::try_into(record.record_type).unwrap(),
+        "TTL": record.ttl,
+        "data": format::presentation_data(&record.data)
+    })
+}
+
+fn records_from_json(value: &Value, section: &str) -> Result<Vec<DnsRecord>> {
+    let records = match value.get(section) {
+        Some(_) => array_field(value, section)?,
+        None => return Ok(Vec::new())
+    };
+    records.iter().map(|record| {
+        let record_type = u64_field(record, "type")?;
+        let record_type = u16::try_from(record_type).ok()
+        .and_then(|record_type| RecordType::try_from(record_type).ok())
+        .ok_or_else(|| invalid_value("type", record_type))?;
+        let ttl = u64_field(record, "TTL")?;
+        let data = record.get("data").and_then(Value::as_str).ok_or_else(|| missing_field("data"))?;
+        Ok(DnsRecord {
+            name: name_field(record, "name")?,
+            record_type,
+            record_class: RecordClass::IN,
+            ttl: u32::try_from(ttl).map_err(|_| invalid_value("TTL", ttl))?,
+            length: 0,
+            data: parse_record_data(record_type, data).ok_or_else(|| invalid_value("data", data))?
+        })
+    }).collect()
+}
+
+/// Parses the presentation format of record data, as written by record_to_json.
+fn parse_record_data(record_type: RecordType, data: &str) -> Option<RecordData> {
+    let name = |name: &str| DomainName::from_str(name).ok();
+    let fields: Vec<&str> = data.split_whitespace().collect();
+    match (record_type, fields.as_slice()) {
+        (RecordType::A, [address]) => Some(RecordData::A { ipv4_address: net::Ipv4Addr::from_str(address).ok()? }),
+        (RecordType::AAAA, [address]) => Some(RecordData::AAAA { ipv6_address: net::Ipv6Addr::from_str(address).ok()? }),
+        (RecordType::CNAME, [canonical_name]) => Some(RecordData::CNAME { canonical_name: name(canonical_name)? }),
+        (RecordType::DNAME, [target]) => Some(RecordData::DNAME { target: name(target)? }),
+        (RecordType::NS, [domain_name]) => Some(RecordData::NS { domain_name: name(domain_name)? }),
+        (RecordType::PTR, [domain_name]) => Some(RecordData::PTR { domain_name: name(domain_name)? }),
+        (RecordType::MX, [preference, exchange_address]) => Some(RecordData::MX {
+            preference: preference.parse().ok()?,
+            exchange_address: name(exchange_address)?
+        }),
+        (RecordType::SOA, [domain_name, mailbox_address, serial, refresh, retry, expire, minimum]) => Some(RecordData::SOA {
+            domain_name: name(domain_name)?,
+            mailbox_address: name(mailbox_address)?,
+            serial: serial.parse().ok()?,
+            refresh: refresh.parse().ok()?,
+            retry: retry.parse().ok()?,
+            expire: expire.parse().ok()?,
+            minimum: minimum.parse().ok()?
+        }),
+        (RecordType::HINFO, _) => {
+            let strings = parse_character_strings(data)?;
+            match strings.as_slice() {
+                [cpu, os] => Some(RecordData::HINFO {
+                    cpu: CharacterString::from_str(cpu).ok()?,
+                    os: CharacterString::from_str(os).ok()?
+                }),
+                _ => None
+            }
+        },
+        (RecordType::DS | RecordType::RRSIG | RecordType::NSEC | RecordType::DNSKEY | RecordType::NSEC3, ["\\#", length, hex @ ..]) => {
+            let hex = hex.concat();
+            let data = (0..hex.len()).step_by(2)
+            .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+            (data.len() == length.parse::<usize>().ok()?).then_some(RecordData::Opaque { data })
+        },
+        _ => None
+    }
+}
+
+/// Splits `data` into character strings, which are either quoted, with backslash escapes, or
+/// delimited by whitespace.
+fn parse_character_strings(data: &str) -> Option<Vec<String>> {
+    let mut strings = Vec::new();
+    let mut chars = data.trim_start().chars().peekable();
+    while let Some(first) = chars.next() {
+        let mut string = String::new();
+        match first {
+            '"' => loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => string.push(chars.next()?),
+                    c => string.push(c)
+                }
+            },
+            c => {
+                string.push(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    string.push(c);
+                }
+            }
+        }
+        strings.push(string);
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+    Some(strings)
+}
+
+fn missing_field(field: &str) -> JsonError {
+    JsonError::MissingField { field: String::from(field) }
+}
+
+fn invalid_value<V: std::fmt::Display>(field: &str, value: V) -> JsonError {
+    JsonError::InvalidValue { field: String::from(field), value: value.to_string() }
+}
+
+fn u64_field(value: &Value, field: &str) -> Result<u64> {
+    value.get(field).and_then(Value::as_u64).ok_or_else(|| missing_field(field))
+}
+
+fn bool_field(value: &Value, field: &str) -> Result<bool> {
+    value.get(field).and_then(Value::as_bool).ok_or_else(|| missing_field(field))
+}
+
+fn array_field<'a>(value: &'a Value, field: &str) -> Result<&'a Vec<Value>> {
+    value.get(field).and_then(Value::as_array).ok_or_else(|| missing_field(field))
+}
+
+fn name_field(value: &Value, field: &str) -> Result<DomainName> {
+    let name = value.get(field).and_then(Value::as_str).ok_or_else(|| missing_field(field))?;
+    DomainName::from_str(name).map_err(|_| invalid_value(field, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_message::*;
+    use crate::json::*;
+
+    fn record(name: &str, record_type: RecordType, data: RecordData) -> DnsRecord {
+        DnsRecord {
+            name: DomainName::from_str(name).unwrap(),
+            record_type,
+            record_class: RecordClass::IN,
+            ttl: 300,
+            length: 0,
+            data
+        }
+    }
+
+    #[test]
+    fn to_json_test() {
+        let mut query = DnsMessage::default();
+        query.questions[0].name = DomainName::from_str(TEST_DOMAIN).unwrap();
+        let mut response = query.reply(ResponseCode::NOERROR);
+        response.header.recursion_available = true;
+        response.header.z = AUTHENTIC_DATA;
+        response.answers.push(record(TEST_DOMAIN, RecordType::A, RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, 1) }));
+        response.update_counts();
+        assert_eq!(response.to_json_value(), json!({
+            "Status": 0,
+            "TC": false,
+            "RD": true,
+            "RA": true,
+            "AD": true,
+            "CD": false,
+            "Question": [{ "name": "example.com.", "type": 1 }],
+            "Answer": [{ "name": "example.com.", "type": 1, "TTL": 300, "data": "192.0.2.1" }]
+        }));
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut response = DnsMessage::default().reply(ResponseCode::NAMEERROR);
+        response.header.z = CHECKING_DISABLED;
+        let name = DomainName::from_str(TEST_DOMAIN).unwrap();
+        response.answers = vec![
+            record(TEST_DOMAIN, RecordType::AAAA, RecordData::AAAA { ipv6_address: net::Ipv6Addr::from_str("2001:db8::1").unwrap() }),
+            record(TEST_DOMAIN, RecordType::MX, RecordData::MX { preference: 10, exchange_address: name.clone() }),
+            record(TEST_DOMAIN, RecordType::HINFO, RecordData::HINFO {
+                cpu: CharacterString::from_str("Intel \"x86\"").unwrap(),
+                os: CharacterString::from_str("GNU\\Linux").unwrap()
+            }),
+            record(TEST_DOMAIN, RecordType::RRSIG, RecordData::Opaque { data: vec![0, 1, 0xfe, 0xff] })
+        ];
+        response.authorities.push(record(TEST_DOMAIN, RecordType::SOA, RecordData::SOA {
+            domain_name: name.clone(),
+            mailbox_address: name,
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 900
+        }));
+        response.update_counts();
+
+        let parsed = DnsMessage::from_json(&response.to_json()).expect("Failed to parse.");
+        assert_eq!(parsed.header.response_code, ResponseCode::NAMEERROR);
+        assert_eq!(parsed.header.z, CHECKING_DISABLED);
+        assert_eq!(parsed.questions, response.questions);
+        let data = |records: &[DnsRecord]| records.iter().map(|record| record.data.clone()).collect::<Vec<_>>();
+        assert_eq!(data(&parsed.answers), data(&response.answers));
+        assert_eq!(data(&parsed.authorities), data(&response.authorities));
+        assert!(parsed.additional.is_empty());
+    }
+
+    #[test]
+    fn query_from_parameters_test() {
+        let query = query_from_parameters("name=www%2Eexample.com&type=aaaa&cd=1&do=1").unwrap();
+        assert_eq!(query.questions[0].name, DomainName::from_str("www.example.com").unwrap());
+        assert_eq!(query.questions[0].question_type, CombinedType::RecordType(RecordType::AAAA));
+        assert_eq!(query.header.z, CHECKING_DISABLED);
+        let query = query_from_parameters("name=example.com&type=15").unwrap();
+        assert_eq!(query.questions[0].question_type, CombinedType::RecordType(RecordType::MX));
+        assert_eq!(query_from_parameters("name=example.com").unwrap().questions[0].question_type, CombinedType::default());
+        assert!(matches!(query_from_parameters("type=A"), Err(JsonError::MissingField { .. })));
+        assert!(matches!(query_from_parameters("name=example.com&type=XYZ"), Err(JsonError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn invalid_json_test() {
+        assert!(matches!(DnsMessage::from_json("{"), Err(JsonError::Syntax { .. })));
+        assert!(matches!(DnsMessage::from_json("{\"Status\": 0}"), Err(JsonError::MissingField { .. })));
+        let json = r#"{"Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false, "Question": [],
+            "Answer": [{"name": "example.com.", "type": 1, "TTL": 300, "data": "not an address"}]}"#;
+        assert!(matches!(DnsMessage::from_json(json), Err(JsonError::InvalidValue { field, .. }) if field == "data"));
+    }
+}
@@ -68,12 +68,9 @@ pub mod tabulation {
         pub fn get_column_max_length(&self, column: usize) -> usize {
             let column = self.get_column(column);
             let mut max_length = 0;
-            for value in column {
-                match value {
-                    Some(value) => if value.len() > max_length {
-                        max_length = value.len();
-                    },
-                    _ => ()
+            for value in column.into_iter().flatten() {
+                if value.len() > max_length {
+                    max_length = value.len();
                 }
             }
             max_length
@@ -118,6 +115,64 @@ pub mod tabulation {
 /// applications.
 pub mod udp_packet;
 
+/// Module containing a simple UDP DNS server, along with a caching forwarder which answers
+/// queries by forwarding cache misses to a set of upstream recursive name servers.
+pub mod server;
+
+/// Module containing a TTL-aware cache of resource record sets, usable by resolvers as well as
+/// by the server.
+pub mod cache;
+
+/// Module containing an iterative resolver, which starts at the root name servers and follows
+/// referrals instead of relying on a recursive name server.
+pub mod iterative;
+
+/// Module containing the Transport trait, which abstracts how queries reach name servers, along
+/// with UDP, TCP and scriptable in-memory implementations.
+pub mod transport;
+
+/// Module containing a DNS over TLS transport and server, including SPKI pinning. Requires the
+/// tls feature.
+#[cfg(feature = "tls")]
+pub mod tls;
+
+/// Module containing a DNS over HTTPS transport and server using HTTP/2. Requires the https feature.
+#[cfg(feature = "https")]
+pub mod https;
+
+/// Module containing the JSON format of DNS over HTTPS APIs. Requires the json feature.
+#[cfg(feature = "json")]
+pub mod json;
+
+/// Module containing the Formatter trait, which renders DnsMessage:s as text, along with dig, short,
+/// table, JSON, YAML and zone file style implementations.
+pub mod format;
+
+/// Module containing a dnstap logger, which writes the queries and responses of servers and
+/// resolvers as Frame Streams to a file or unix socket, along with a reader of such streams.
+pub mod dnstap;
+
+/// Module containing a reader of pcap and pcapng captures, which extracts the DNS messages sent
+/// over UDP and TCP along with their timestamps and endpoints.
+pub mod pcap;
+
+/// Module containing a stub resolver, which sends queries to recursive name servers with
+/// configurable timeouts, retries and server rotation.
+pub mod resolver;
+
+/// Module containing an asynchronous stub resolver built on tokio, which multiplexes concurrent
+/// queries over a single socket. Requires the async feature.
+#[cfg(feature = "async")]
+pub mod async_resolver;
+
+/// Module containing readers for the system configuration of the resolver, i.e. resolv.conf
+/// and the hosts file.
+pub mod system_config;
+
+/// Module containing helpers for generating cryptographically secure random values, such as
+/// query ids and source ports.
+pub mod random;
+
 /// Module containing macros used for various purposes in other modules. The macros
 /// are primarily used to reduce repetitive boilerplate code and to facilitate code
 /// maintenance.
@@ -159,21 +214,24 @@ impl std::error::Error for BuildEnumError {}
 /// 
 /// Note that the variants of the enum cannot include anything other than an identifier,
 /// which means that no named or unnamed parameters can be included in a variant.
+///
+/// With the serde feature, the enum is serialised as the name of its variant, i.e. its mnemonic.
 #[macro_export]
 macro_rules! build_enum {
     ($name:ident; $($variant:ident = $value:expr),*$(,)?) => {
-        #[derive(Clone, Copy, Debug, Default, PartialEq)]
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
         pub enum $name {
             #[default]
             $($variant,)*
         }
+        $crate::serde_as_str!($name);
         impl std::convert::TryFrom<u16> for $name {
-            type Error = crate::macros::BuildEnumError;
+            type Error = $crate::macros::BuildEnumError;
 
-            fn try_from(value: u16) -> Result<Self, Self::Error> { 
+            fn try_from(value: u16) -> std::result::Result<Self, Self::Error> { 
                 match value {
                     $($value => Ok(Self::$variant),)*
-                    _ => Err(crate::macros::BuildEnumError::InvalidU16 {
+                    _ => Err($crate::macros::BuildEnumError::InvalidU16 {
                         uint_16: value,
                     })
                 }
@@ -182,7 +240,7 @@ macro_rules! build_enum {
         impl std::convert::TryInto<u16> for $name {
             type Error = ();
 
-            fn try_into(self) -> Result<u16, Self::Error> { 
+            fn try_into(self) -> std::result::Result<u16, Self::Error> { 
                 match self {
                     $(Self::$variant => Ok($value),)*
                 }
@@ -194,12 +252,12 @@ macro_rules! build_enum {
             }
         }
         impl std::str::FromStr for $name {
-            type Err = crate::macros::BuildEnumError;
+            type Err = $crate::macros::BuildEnumError;
         
-            fn from_str(s: &str) -> Result<Self, Self::Err> {
+            fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
                 match s {
                     $(stringify!($variant) => Ok(Self::$variant),)*
-                    _ => Err(crate::macros::BuildEnumError::InvalidStrVariant {
+                    _ => Err($crate::macros::BuildEnumError::InvalidStrVariant {
                         variant_str: String::from(s),
                     })
                 }
@@ -207,4 +265,42 @@ macro_rules! build_enum {
         }
     };
 }
+
+/// The serde crate, so that exported macros can refer to it as $crate::macros::serde.
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub use serde;
+
+/// A macro implementing Serialize and Deserialize for a type which implements Display and
+/// FromStr, such that it is serialised as its presentation string, e.g. a DomainName.
+///
+/// Whether the serde feature is enabled is decided in this crate, so build_enum! can use the
+/// macro in crates which do not have such a feature. Without it, the macro expands to nothing.
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! serde_as_str {
+    ($name:ty) => {
+        impl $crate::macros::serde::Serialize for $name {
+            fn serialize<S: $crate::macros::serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+        impl<'de> $crate::macros::serde::Deserialize<'de> for $name {
+            fn deserialize<D: $crate::macros::serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+                let s = <std::borrow::Cow<'de, str> as $crate::macros::serde::Deserialize>::deserialize(deserializer)?;
+                std::str::FromStr::from_str(&s).map_err(|_| $crate::macros::serde::de::Error::custom(
+                    format!("invalid {} '{}'", stringify!($name), s)
+                ))
+            }
+        }
+    };
+}
+
+#[cfg(not(feature = "serde"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! serde_as_str {
+    ($name:ty) => {};
+}
 }
\ No newline at end of file
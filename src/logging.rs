@@ -0,0 +1,120 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const LEVEL_VARIABLE: &str = "DNS_RESOLVER_LOG";       // One of off, error, warn, info, debug and trace
+pub const FILE_VARIABLE: &str = "DNS_RESOLVER_LOG_FILE";   // A file which is appended to instead of writing to stderr
+
+const DEFAULT_LEVEL: log::LevelFilter = log::LevelFilter::Warn;
+
+/// Error handling type for the configuration of logging.
+#[derive(Debug)]
+pub enum LoggingError {
+    /// The level is not one of those of log::LevelFilter.
+    InvalidLevel {
+        level: String           // The level as given in the environment.
+    },
+
+    /// The log file could not be opened for appending.
+    UnopenableFile {
+        path: String,           // The path as given in the environment.
+        source: io::Error       // The underlying error.
+    }
+}
+
+impl std::fmt::Display for LoggingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidLevel {
+                level
+            } => write!(f, "invalid log level '{}' in {}, expected off, error, warn, info, debug or trace", level, LEVEL_VARIABLE),
+            Self::UnopenableFile {
+                path,
+                source
+            } => write!(f, "could not open log file {}, source: {}", path, source)
+        }
+    }
+}
+
+impl std::error::Error for LoggingError {}
+
+/// Specialised result type for the configuration of logging.
+pub type Result<T> = std::result::Result<T, LoggingError>;
+
+/// A logger writing one line per record, preceded by the time since the Unix epoch, the level and
+/// the module the record comes from.
+struct Logger {
+    level: log::LevelFilter,
+    destination: Mutex<Box<dyn Write + Send>>
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut destination = self.destination.lock().unwrap();
+        // There is nowhere to report a failure to log.
+        let _ = writeln!(destination, "{}.{:03} {:<5} {}: {}", time.as_secs(), time.subsec_millis(), record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {
+        let _ = self.destination.lock().unwrap().flush();
+    }
+}
+
+/// Builds a logger from the values of LEVEL_VARIABLE and FILE_VARIABLE.
+fn logger(level: Option<String>, path: Option<OsString>) -> Result<Logger> {
+    let level = match level {
+        Some(level) => log::LevelFilter::from_str(&level).map_err(|_| LoggingError::InvalidLevel { level })?,
+        None => DEFAULT_LEVEL
+    };
+    let destination: Box<dyn Write + Send> = match path {
+        Some(path) => Box::new(fs::OpenOptions::new().create(true).append(true).open(&path)
+        .map_err(|source| LoggingError::UnopenableFile { path: path.to_string_lossy().into_owned(), source })?),
+        None => Box::new(io::stderr())
+    };
+    Ok(Logger { level, destination: Mutex::new(destination) })
+}
+
+/// Installs the logger configured by the environment, see LEVEL_VARIABLE and FILE_VARIABLE. Must
+/// only be called once.
+pub fn init() -> Result<()> {
+    let logger = logger(env::var(LEVEL_VARIABLE).ok(), env::var_os(FILE_VARIABLE))?;
+    let level = logger.level;
+    log::set_logger(Box::leak(Box::new(logger))).expect("The logger is only installed once.");
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::*;
+    use log::Log;
+
+    #[test]
+    fn logger_test() {
+        let path = env::temp_dir().join(format!("dns_resolver_logging_test_{}.log", std::process::id()));
+        let logger = logger(Some(String::from("debug")), Some(path.clone().into_os_string())).unwrap();
+        for level in [log::Level::Info, log::Level::Trace] {
+            logger.log(&log::Record::builder().level(level).target("dns_resolver::server").args(format_args!("a {} message", level)).build());
+        }
+        logger.flush();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with(" INFO  dns_resolver::server: a INFO message"), "{}", lines[0]);
+
+        assert!(matches!(super::logger(Some(String::from("loud")), None), Err(LoggingError::InvalidLevel { .. })));
+    }
+}
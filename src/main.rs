@@ -1,74 +1,148 @@
-use dns_resolver::{dns_message, udp_packet};
+mod cli;
+mod logging;
+
+use dns_resolver::cache::{Cache, SystemClock};
+use dns_resolver::dnstap::{DnstapSink, DnstapTransport, MessageType, SocketProtocol};
+use dns_resolver::transport::{Transport, UdpTransport};
+use dns_resolver::{server, system_config};
+#[cfg(feature = "tls")]
+use dns_resolver::tls;
+#[cfg(feature = "https")]
+use dns_resolver::https;
 use std::env;
+use std::process::ExitCode;
 use std::str::FromStr;
 use std::net;
+use std::sync::Arc;
 
-const LOCAL_ADDRESS: (net::Ipv4Addr, u16) = (net::Ipv4Addr::UNSPECIFIED, 0);
-const NAME_SERVER_ADDRESS: (&str, u16) = ("8.8.8.8", 53);
-const ACTIVATE_LOGGING: bool = true;
+const NAME_SERVER_PORT: u16 = 53;
+const LISTEN_ADDRESS: (&str, u16) = ("127.0.0.1", 53);
 
-// Grammar: <Operation code> <Question class> <Question type> <Domain name>.
-// TODO: Set the flags via CLI, instead of hardcoded constants.
-#[derive(Debug)]
-struct Arguments {
-    operation_code: dns_message::OperationCode,
-    question_class: dns_message::CombinedClass,
-    question_type: dns_message::CombinedType,
-    domain_name: udp_packet::DomainName
+// Grammar: serve [<Listen address>[:<Port>]] [<Upstream address>[:<Port>] ...].
+// The name servers of resolv.conf are used as upstreams if none are given.
+fn serve(env_args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let listen_address = match env_args.first() {
+        Some(address) => parse_socket_address(address, LISTEN_ADDRESS.1)?,
+        None => parse_socket_address(LISTEN_ADDRESS.0, LISTEN_ADDRESS.1)?
+    };
+    let dnstap = cli::dnstap_sink()?;
+    let mut server = server::Server::bind(listen_address, forwarder(upstreams(env_args)?, dnstap.as_ref()))?;
+    if let Some(sink) = dnstap {
+        server.set_dnstap(sink);
+    }
+    println!("Listening on {}", server.local_addr()?);
+    Ok(server.run()?)
 }
 
-// TODO: Replace the *::Unknown here with some other member indicating an error.
-impl Arguments {
-    fn get() -> udp_packet::Result<Self> {
-        let env_args: Vec<String> = env::args().collect();
-        if env_args.len() != 5 {
-            panic!("Must supply 4 arguments.")
-        }
-        let arguments = Self {
-            operation_code: FromStr::from_str(env_args[1].to_ascii_uppercase().as_str()).unwrap(),
-            question_class: FromStr::from_str(env_args[2].to_uppercase().as_str()).unwrap(),
-            question_type: FromStr::from_str(env_args[3].to_uppercase().as_str()).unwrap(),
-            domain_name: udp_packet::DomainName::from_str(env_args[4].as_str())?
-        };
-        Ok(arguments)
+// Grammar: serve-tls <Certificate chain (PEM)> <Private key (PEM)> [<Listen address>[:<Port>]] [<Upstream address>[:<Port>] ...].
+#[cfg(feature = "tls")]
+fn serve_tls(env_args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if env_args.len() < 2 {
+        return Err("serve-tls requires a certificate chain and a private key".into());
     }
+    let config = tls::server_config(tls::read_certificates(&env_args[0])?, tls::read_private_key(&env_args[1])?)?;
+    let listen_address = match env_args.get(2) {
+        Some(address) => parse_socket_address(address, tls::DOT_PORT)?,
+        None => parse_socket_address(LISTEN_ADDRESS.0, tls::DOT_PORT)?
+    };
+    let dnstap = cli::dnstap_sink()?;
+    let mut server = tls::TlsServer::bind(listen_address, config, forwarder(upstreams(&env_args[2..])?, dnstap.as_ref()))?;
+    if let Some(sink) = dnstap {
+        server.set_dnstap(sink);
+    }
+    println!("Listening on {} (TLS)", server.local_addr()?);
+    Ok(server.run()?)
 }
 
-fn main() -> udp_packet::Result<()> {
-    let arguments = Arguments::get()?;
-    let dns_message: dns_message::DnsMessage = dns_message::DnsMessage {
-        header: dns_message::DnsHeader {
-            operation_code: arguments.operation_code,
-            ..Default::default()
-        },
-        questions: vec![
-            dns_message::DnsQuestion {
-                name: arguments.domain_name,
-                question_class: arguments.question_class,
-                question_type: arguments.question_type
-            },
-        ],
-        ..Default::default()
+// Grammar: serve-https <Certificate chain (PEM)> <Private key (PEM)> [<Listen address>[:<Port>]] [<Upstream address>[:<Port>] ...].
+// Queries are answered at https::DEFAULT_PATH.
+#[cfg(feature = "https")]
+fn serve_https(env_args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if env_args.len() < 2 {
+        return Err("serve-https requires a certificate chain and a private key".into());
+    }
+    let config = tls::server_config(tls::read_certificates(&env_args[0])?, tls::read_private_key(&env_args[1])?)?;
+    let listen_address = match env_args.get(2) {
+        Some(address) => parse_socket_address(address, https::DOH_PORT)?,
+        None => parse_socket_address(LISTEN_ADDRESS.0, https::DOH_PORT)?
     };
-    
-    let mut udp_packet: udp_packet::UdpPacket = udp_packet::UdpPacket::new();
-    dns_message.write_to_udp_packet(&mut udp_packet)?;
-    
-    let udp_socket = net::UdpSocket::bind(LOCAL_ADDRESS)
-    .expect("Failed to bind a UdpSocket to address.");
-    udp_socket.connect(NAME_SERVER_ADDRESS).expect("Failed to connect to name server.");
+    let dnstap = cli::dnstap_sink()?;
+    let mut server = https::HttpsServer::bind(listen_address, config, forwarder(upstreams(&env_args[2..])?, dnstap.as_ref()))?;
+    if let Some(sink) = dnstap {
+        server.set_dnstap(sink);
+    }
+    println!("Listening on {} (HTTPS)", server.local_addr()?);
+    Ok(server.run()?)
+}
 
-    udp_packet.send(&udp_socket)?;
-    let mut response_packet: udp_packet::UdpPacket = udp_packet::UdpPacket::new();
-    response_packet.recv(&udp_socket)?;
+// Forwards queries to the upstreams over UDP, logging the forwarder messages to the dnstap sink if any.
+fn forwarder(upstreams: Vec<net::SocketAddr>, dnstap: Option<&Arc<DnstapSink>>) -> server::Forwarder<SystemClock, Box<dyn Transport>> {
+    let transport: Box<dyn Transport> = match dnstap {
+        Some(sink) => Box::new(DnstapTransport::new(UdpTransport::default(), Arc::clone(sink), MessageType::FORWARDERQUERY, SocketProtocol::UDP)),
+        None => Box::new(UdpTransport::default())
+    };
+    server::Forwarder::with_transport(upstreams, server::UPSTREAM_TIMEOUT, Cache::default(), transport)
+}
 
-    let decoded_message = dns_message::DnsMessage::read_from_udp_packet(&mut response_packet)?;
-    println!("{}", decoded_message);
+// The upstreams follow the listen address, see serve.
+fn upstreams(env_args: &[String]) -> Result<Vec<net::SocketAddr>, Box<dyn std::error::Error>> {
+    let upstreams: Vec<net::SocketAddr> = env_args.iter()
+    .skip(1)
+    .map(|address| parse_socket_address(address, NAME_SERVER_PORT))
+    .collect::<Result<_, _>>()?;
+    match upstreams.is_empty() {
+        true => Ok(system_config::read_resolv_conf(system_config::RESOLV_CONF_PATH)?.servers),
+        false => Ok(upstreams)
+    }
+}
 
-    if ACTIVATE_LOGGING {
-        std::fs::write("./logs.txt", format!("{:#?}", decoded_message))
-        .expect("Failed to log raw output.");
+fn parse_socket_address(address: &str, default_port: u16) -> Result<net::SocketAddr, Box<dyn std::error::Error>> {
+    match net::IpAddr::from_str(address) {
+        Ok(ip_address) => Ok(net::SocketAddr::new(ip_address, default_port)),
+        Err(_) => net::SocketAddr::from_str(address).map_err(|_| format!("invalid socket address {}", address).into())
     }
+}
+
+// Grammar: see cli::USAGE.
+fn query(env_args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let command = cli::parse(env_args)?;
+    if command.help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+    cli::run(&command).map(|_| ())
+}
+
+// Grammar: pcap [-s] [-p <Port>] [+short] [+format=<Format>] <Capture>, see cli::USAGE.
+fn pcap(env_args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let command = cli::parse_pcap(env_args)?;
+    if command.help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+    cli::run_pcap(&command)
+}
 
-    Ok(())
-}
\ No newline at end of file
+fn main() -> ExitCode {
+    if let Err(error) = logging::init() {
+        eprintln!("dns_resolver: {}", error);
+        return ExitCode::FAILURE;
+    }
+    let env_args: Vec<String> = env::args().collect();
+    let result = match env_args.get(1).map(String::as_str) {
+        Some("serve") => serve(&env_args[2..]),
+        Some("pcap") => pcap(&env_args[2..]),
+        #[cfg(feature = "tls")]
+        Some("serve-tls") => serve_tls(&env_args[2..]),
+        #[cfg(feature = "https")]
+        Some("serve-https") => serve_https(&env_args[2..]),
+        _ => query(&env_args[1..])
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("dns_resolver: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,849 @@
+use crate::dns_message::DnsMessage;
+use crate::udp_packet::{TcpPacket, UdpPacketError};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::net;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The port of the messages extracted by default, see PcapReader::set_port.
+pub const DNS_PORT: u16 = 53;
+
+// Magic numbers of pcap files, see https://datatracker.ietf.org/doc/draft-ietf-opsawg-pcap/.
+const PCAP_MAGIC_MICROSECONDS: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOSECONDS: u32 = 0xa1b23c4d;
+const PCAP_HEADER_LENGTH: usize = 24;           // Including the magic number
+const PCAP_RECORD_HEADER_LENGTH: usize = 16;
+
+// pcapng block types and options, see https://datatracker.ietf.org/doc/draft-ietf-opsawg-pcapng/.
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d0d0a;  // A palindrome, so that it reads the same in either byte order
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const OPTION_END: u16 = 0;
+const OPTION_TIMESTAMP_RESOLUTION: u16 = 9;     // if_tsresol
+const DEFAULT_UNITS_PER_SECOND: u64 = 1_000_000;
+const MAX_BLOCK_LENGTH: usize = 1 << 24;        // Far more than any frame, to reject corrupt lengths early
+
+// Link types, see https://www.tcpdump.org/linktypes.html.
+const LINKTYPE_NULL: u32 = 0;                   // BSD loopback, with the address family in host byte order
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LOOP: u32 = 108;                 // OpenBSD loopback, with the address family in network byte order
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const LOOPBACK_FAMILIES: [u32; 5] = [2, 10, 24, 28, 30];   // AF_INET, and AF_INET6 of Linux and the BSDs
+
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+const IPV4_FRAGMENT_MASK: u16 = 0x3fff;         // The MF flag and the fragment offset
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+
+const TOP_NAMES: usize = 10;                    // The number of names listed by Display for Summary
+
+/// Error handling type for reading captures.
+#[derive(Debug)]
+pub enum PcapError {
+    /// Reading the capture failed.
+    Io {
+        description: String,        // What was being done.
+        source: io::Error           // The underlying error.
+    },
+
+    /// The capture starts with neither the magic number of pcap nor that of pcapng.
+    UnknownFormat {
+        magic: u32                  // The first four bytes, in big-endian order.
+    },
+
+    /// The capture is truncated or otherwise corrupt. No messages follow this error.
+    Malformed {
+        description: String         // What went wrong.
+    },
+
+    /// A payload sent to or from the DNS port is not a DNS message. Messages may follow this error.
+    InvalidMessage {
+        from: net::SocketAddr,      // The sender of the payload.
+        to: net::SocketAddr,        // The receiver of the payload.
+        source: Box<UdpPacketError> // The decoding error, boxed to keep results small.
+    }
+}
+
+impl fmt::Display for PcapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io {
+                description,
+                source
+            } => write!(f, "{}, source: {}", description, source),
+            Self::UnknownFormat {
+                magic
+            } => write!(f, "not a pcap or pcapng capture, magic number {:#010x}", magic),
+            Self::Malformed {
+                description
+            } => write!(f, "malformed capture: {}", description),
+            Self::InvalidMessage {
+                from,
+                to,
+                source
+            } => write!(f, "invalid DNS message from {} to {}, source: {}", from, to, source)
+        }
+    }
+}
+
+impl std::error::Error for PcapError {}
+
+/// Specialised result type for reading captures.
+pub type Result<T> = std::result::Result<T, PcapError>;
+
+fn io_error(description: &str) -> impl FnOnce(io::Error) -> PcapError {
+    let description = String::from(description);
+    move |source| PcapError::Io { description, source }
+}
+
+fn malformed(description: &str) -> PcapError {
+    PcapError::Malformed { description: String::from(description) }
+}
+
+/// The transport protocol a message was captured on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Udp,
+    Tcp
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp => write!(f, "UDP"),
+            Self::Tcp => write!(f, "TCP")
+        }
+    }
+}
+
+/// A DNS message found in a capture.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedMessage {
+    pub timestamp: SystemTime,          // When the packet, or for TCP the segment completing the message, was captured
+    pub source: net::SocketAddr,
+    pub destination: net::SocketAddr,
+    pub protocol: Protocol,
+    pub message: DnsMessage
+}
+
+/// The layout of a capture, along with what is needed to read its packets.
+enum Format {
+    Pcap {
+        big_endian: bool,
+        nanoseconds: bool,              // Whether timestamps have a nanosecond rather than microsecond resolution
+        link_type: u32
+    },
+    Pcapng {
+        big_endian: bool,               // Of the current section
+        interfaces: Vec<Interface>      // Of the current section, indexed by the interface ids of packets
+    }
+}
+
+/// A pcapng interface description.
+struct Interface {
+    link_type: u32,
+    units_per_second: u64               // The resolution of timestamps, from if_tsresol
+}
+
+/// A captured packet, starting with the header of its link layer.
+struct Frame {
+    timestamp: SystemTime,
+    link_type: u32,
+    data: Vec<u8>
+}
+
+/// A UDP datagram or TCP segment.
+struct Segment<'a> {
+    protocol: Protocol,
+    source: net::SocketAddr,
+    destination: net::SocketAddr,
+    sequence: u32,                      // Always 0 for UDP
+    flags: u8,                          // Always 0 for UDP
+    payload: &'a [u8]
+}
+
+/// The reassembly state of one direction of a TCP connection.
+struct TcpStream {
+    next_sequence: u32,                 // The sequence number of the next byte expected
+    buffer: Vec<u8>                     // Received bytes not yet part of a complete message
+}
+
+/// An iterator over the DNS messages of a pcap or pcapng capture, i.e. the payloads of UDP
+/// datagrams and TCP streams to or from the DNS port, in the order of the capture.
+///
+/// Ethernet (with VLAN tags), Linux cooked, loopback and raw IP captures are supported. Fragmented
+/// IP packets are skipped, and TCP segments are reassembled in order only: a segment which arrives
+/// after a gap discards what was buffered, so that a lost segment cannot stall the stream. Payloads
+/// which are not DNS messages are returned as PcapError::InvalidMessage, and iteration continues
+/// after them, whereas other errors end it.
+pub struct PcapReader<R: Read> {
+    reader: R,
+    format: Format,
+    port: u16,
+    tcp_streams: HashMap<(net::SocketAddr, net::SocketAddr), TcpStream>,
+    pending: VecDeque<Result<CapturedMessage>>,     // Messages of the last frame not returned yet
+    finished: bool
+}
+
+impl PcapReader<BufReader<fs::File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = fs::File::open(path).map_err(io_error("failed to open the capture"))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Reads the header of a capture, detecting whether it is a pcap or pcapng one.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).map_err(io_error("failed to read the header of the capture"))?;
+        let format = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (BLOCK_SECTION_HEADER, _) => Format::Pcapng { big_endian: false, interfaces: Vec::new() },
+            (PCAP_MAGIC_MICROSECONDS, _) | (PCAP_MAGIC_NANOSECONDS, _) | (_, PCAP_MAGIC_MICROSECONDS) | (_, PCAP_MAGIC_NANOSECONDS) => {
+                let big_endian = u32::from_be_bytes(magic) == PCAP_MAGIC_MICROSECONDS || u32::from_be_bytes(magic) == PCAP_MAGIC_NANOSECONDS;
+                let nanoseconds = read_u32(&magic, big_endian) == PCAP_MAGIC_NANOSECONDS;
+                let mut header = [0; PCAP_HEADER_LENGTH - 4];
+                reader.read_exact(&mut header).map_err(io_error("failed to read the header of the capture"))?;
+                // The upper bits of the link type may hold the length of frame check sequences.
+                Format::Pcap { big_endian, nanoseconds, link_type: read_u32(&header[16..20], big_endian) & 0xffff }
+            },
+            (_, magic) => return Err(PcapError::UnknownFormat { magic })
+        };
+        let mut pcap_reader = Self {
+            reader,
+            format,
+            port: DNS_PORT,
+            tcp_streams: HashMap::new(),
+            pending: VecDeque::new(),
+            finished: false
+        };
+        if let Format::Pcapng { .. } = pcap_reader.format {
+            pcap_reader.read_section_header()?;
+        }
+        Ok(pcap_reader)
+    }
+
+    /// Extracts the messages sent to or from `port` rather than DNS_PORT, e.g. for a capture of a
+    /// server listening on another port.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    /// Reads the rest of a pcapng section header block, whose type has been read, and starts a new
+    /// section.
+    fn read_section_header(&mut self) -> Result<()> {
+        let mut header = [0; 8];
+        self.read_exact(&mut header)?;
+        let big_endian = match (u32::from_le_bytes([header[4], header[5], header[6], header[7]]), u32::from_be_bytes([header[4], header[5], header[6], header[7]])) {
+            (BYTE_ORDER_MAGIC, _) => false,
+            (_, BYTE_ORDER_MAGIC) => true,
+            _ => return Err(malformed("invalid byte order magic in a section header"))
+        };
+        let length = block_length(read_u32(&header, big_endian), 28)?;
+        // The versions, the length of the section and the options are of no use.
+        self.read_block(length - 12)?;
+        self.format = Format::Pcapng { big_endian, interfaces: Vec::new() };
+        Ok(())
+    }
+
+    /// Reads exactly buffer.len() bytes, failing on a truncated capture.
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<()> {
+        self.reader.read_exact(buffer).map_err(|error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => malformed("the capture is truncated"),
+            _ => PcapError::Io { description: String::from("failed to read the capture"), source: error }
+        })
+    }
+
+    /// Reads `length` bytes into a new buffer.
+    fn read_block(&mut self, length: usize) -> Result<Vec<u8>> {
+        let mut block = vec![0; length];
+        self.read_exact(&mut block)?;
+        Ok(block)
+    }
+
+    /// Reads exactly buffer.len() bytes, or returns false if the capture ends before the first one.
+    fn read_or_end(&mut self, buffer: &mut [u8]) -> Result<bool> {
+        let mut read = 0;
+        while read < buffer.len() {
+            match self.reader.read(&mut buffer[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(malformed("the capture is truncated")),
+                Ok(length) => read += length,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(PcapError::Io { description: String::from("failed to read the capture"), source: error })
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads the next packet, or returns None at the end of the capture.
+    fn read_frame(&mut self) -> Result<Option<Frame>> {
+        match self.format {
+            Format::Pcap { big_endian, nanoseconds, link_type } => {
+                let mut header = [0; PCAP_RECORD_HEADER_LENGTH];
+                if !self.read_or_end(&mut header)? {
+                    return Ok(None);
+                }
+                let seconds = read_u32(&header[0..4], big_endian) as u64;
+                let fraction = read_u32(&header[4..8], big_endian);
+                let length = read_u32(&header[8..12], big_endian) as usize;
+                if length > MAX_BLOCK_LENGTH {
+                    return Err(malformed("invalid packet length"));
+                }
+                let nanoseconds = match nanoseconds {
+                    true => fraction,
+                    false => fraction.saturating_mul(1000)
+                };
+                let data = self.read_block(length)?;
+                Ok(Some(Frame { timestamp: UNIX_EPOCH + Duration::new(seconds, nanoseconds), link_type, data }))
+            },
+            Format::Pcapng { .. } => self.read_pcapng_frame()
+        }
+    }
+
+    /// Reads pcapng blocks up to the next packet, or the end of the capture.
+    fn read_pcapng_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            let mut block_type = [0; 4];
+            if !self.read_or_end(&mut block_type)? {
+                return Ok(None);
+            }
+            if u32::from_le_bytes(block_type) == BLOCK_SECTION_HEADER {
+                self.read_section_header()?;
+                continue;
+            }
+            let big_endian = match self.format {
+                Format::Pcapng { big_endian, .. } => big_endian,
+                Format::Pcap { .. } => unreachable!("Only pcapng captures have blocks.")
+            };
+            let mut length = [0; 4];
+            self.read_exact(&mut length)?;
+            let length = block_length(read_u32(&length, big_endian), 12)?;
+            let block = self.read_block(length - 8)?;
+            let body = &block[..block.len() - 4];
+            let Format::Pcapng { interfaces, .. } = &mut self.format else {
+                unreachable!("Only pcapng captures have blocks.")
+            };
+            match read_u32(&block_type, big_endian) {
+                BLOCK_INTERFACE_DESCRIPTION => {
+                    if body.len() < 8 {
+                        return Err(malformed("interface description block too short"));
+                    }
+                    let link_type = read_u16(&body[0..2], big_endian) as u32;
+                    let units_per_second = timestamp_resolution(&body[8..], big_endian)?;
+                    interfaces.push(Interface { link_type, units_per_second });
+                },
+                BLOCK_ENHANCED_PACKET => {
+                    if body.len() < 20 {
+                        return Err(malformed("enhanced packet block too short"));
+                    }
+                    let interface = interfaces.get(read_u32(&body[0..4], big_endian) as usize)
+                    .ok_or_else(|| malformed("packet of an undescribed interface"))?;
+                    let timestamp = ((read_u32(&body[4..8], big_endian) as u64) << 32) | read_u32(&body[8..12], big_endian) as u64;
+                    let length = read_u32(&body[12..16], big_endian) as usize;
+                    let data = body.get(20..20 + length).ok_or_else(|| malformed("packet longer than its block"))?;
+                    let timestamp = UNIX_EPOCH + Duration::new(timestamp / interface.units_per_second,
+                        ((timestamp % interface.units_per_second) as u128 * 1_000_000_000 / interface.units_per_second as u128) as u32);
+                    return Ok(Some(Frame { timestamp, link_type: interface.link_type, data: data.to_vec() }));
+                },
+                // Simple packet blocks lack timestamps and are rarely used, the other blocks carry no packets.
+                _ => continue
+            }
+        }
+    }
+
+    /// Extracts the messages of a packet, if it is a datagram or segment to or from the DNS port.
+    fn process(&mut self, frame: &Frame) {
+        let Some(segment) = network_packet(frame.link_type, &frame.data).and_then(transport_segment) else {
+            return;
+        };
+        if segment.source.port() != self.port && segment.destination.port() != self.port {
+            return;
+        }
+        match segment.protocol {
+            Protocol::Udp => {
+                if !segment.payload.is_empty() {
+                    self.pending.push_back(decode(segment.payload, frame.timestamp, &segment));
+                }
+            },
+            Protocol::Tcp => self.reassemble(frame.timestamp, &segment)
+        }
+    }
+
+    /// Appends a TCP segment to its stream, extracting every message the stream then holds. Like
+    /// transport::TcpTransport, each message is preceded by its length on two bytes.
+    fn reassemble(&mut self, timestamp: SystemTime, segment: &Segment) {
+        let key = (segment.source, segment.destination);
+        if segment.flags & TCP_SYN != 0 {
+            self.tcp_streams.insert(key, TcpStream { next_sequence: segment.sequence.wrapping_add(1), buffer: Vec::new() });
+            return;
+        }
+        if !segment.payload.is_empty() {
+            // Without the handshake, e.g. when the capture started mid-connection, this segment is
+            // assumed to start a message.
+            let stream = self.tcp_streams.entry(key).or_insert(TcpStream { next_sequence: segment.sequence, buffer: Vec::new() });
+            let offset = segment.sequence.wrapping_sub(stream.next_sequence) as i32;
+            if offset >= 0 {
+                if offset > 0 {
+                    stream.buffer.clear();
+                }
+                stream.buffer.extend_from_slice(segment.payload);
+                stream.next_sequence = segment.sequence.wrapping_add(segment.payload.len() as u32);
+                while stream.buffer.len() >= 2 {
+                    let length = u16::from_be_bytes([stream.buffer[0], stream.buffer[1]]) as usize;
+                    if stream.buffer.len() < 2 + length {
+                        break;
+                    }
+                    self.pending.push_back(decode(&stream.buffer[2..2 + length], timestamp, segment));
+                    stream.buffer.drain(..2 + length);
+                }
+            }
+            // Otherwise the segment is a retransmission of bytes already read.
+        }
+        if segment.flags & (TCP_FIN | TCP_RST) != 0 {
+            self.tcp_streams.remove(&key);
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = Result<CapturedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(result) = self.pending.pop_front() {
+                return Some(result);
+            }
+            if self.finished {
+                return None;
+            }
+            match self.read_frame() {
+                Ok(Some(frame)) => self.process(&frame),
+                Ok(None) => self.finished = true,
+                Err(error) => {
+                    self.finished = true;
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], big_endian: bool) -> u16 {
+    let bytes = [bytes[0], bytes[1]];
+    match big_endian {
+        true => u16::from_be_bytes(bytes),
+        false => u16::from_le_bytes(bytes)
+    }
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    match big_endian {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes)
+    }
+}
+
+/// Checks the total length of a pcapng block, which is a multiple of 4 of at least `minimum`.
+fn block_length(length: u32, minimum: usize) -> Result<usize> {
+    let length = length as usize;
+    match length >= minimum && length.is_multiple_of(4) && length <= MAX_BLOCK_LENGTH {
+        true => Ok(length),
+        false => Err(malformed("invalid block length"))
+    }
+}
+
+/// Returns the number of timestamp units per second given by the if_tsresol option of an interface
+/// description, i.e. a power of 10 or, if the high bit is set, of 2.
+fn timestamp_resolution(mut options: &[u8], big_endian: bool) -> Result<u64> {
+    while options.len() >= 4 {
+        let code = read_u16(&options[0..2], big_endian);
+        let length = read_u16(&options[2..4], big_endian) as usize;
+        let value = options.get(4..4 + length).ok_or_else(|| malformed("option longer than its block"))?;
+        match (code, value.first()) {
+            (OPTION_END, _) => break,
+            (OPTION_TIMESTAMP_RESOLUTION, Some(&resolution)) => return match resolution & 0x80 {
+                0 => 10u64.checked_pow(resolution as u32),
+                _ => 1u64.checked_shl((resolution & 0x7f) as u32)
+            }.ok_or_else(|| malformed("invalid timestamp resolution")),
+            _ => options = &options[(4 + length).next_multiple_of(4).min(options.len())..]
+        }
+    }
+    Ok(DEFAULT_UNITS_PER_SECOND)
+}
+
+/// Returns the IPv4 or IPv6 packet carried by a frame, if any.
+fn network_packet(link_type: u32, frame: &[u8]) -> Option<&[u8]> {
+    match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            loop {
+                let ethertype = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+                match ethertype {
+                    ETHERTYPE_VLAN | ETHERTYPE_QINQ => offset += 4,
+                    _ => return with_ethertype(ethertype, frame.get(offset + 2..)?)
+                }
+            }
+        },
+        LINKTYPE_LINUX_SLL => with_ethertype(u16::from_be_bytes([*frame.get(14)?, *frame.get(15)?]), frame.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => with_ethertype(u16::from_be_bytes([*frame.first()?, *frame.get(1)?]), frame.get(20..)?),
+        LINKTYPE_NULL | LINKTYPE_LOOP => {
+            let family = frame.get(..4)?;
+            // The byte order of NULL is that of the capturing host, and families are small numbers.
+            let family = u32::from_le_bytes(family.try_into().ok()?).min(u32::from_be_bytes(family.try_into().ok()?));
+            match LOOPBACK_FAMILIES.contains(&family) {
+                true => frame.get(4..),
+                false => None
+            }
+        },
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(frame),
+        _ => None
+    }
+}
+
+/// Returns `packet` if `ethertype` is that of IPv4 or IPv6.
+fn with_ethertype(ethertype: u16, packet: &[u8]) -> Option<&[u8]> {
+    match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(packet),
+        _ => None
+    }
+}
+
+/// Returns the UDP datagram or TCP segment carried by an IP packet, if any. Fragments are skipped.
+fn transport_segment(packet: &[u8]) -> Option<Segment<'_>> {
+    let (source, destination, protocol, payload) = match packet.first()? >> 4 {
+        4 => {
+            let header_length = (packet[0] & 0x0f) as usize * 4;
+            if header_length < 20 || packet.len() < header_length {
+                return None;
+            }
+            if u16::from_be_bytes([packet[6], packet[7]]) & IPV4_FRAGMENT_MASK != 0 {
+                return None;
+            }
+            // The total length may be 0 when segmentation is offloaded, and excludes Ethernet padding.
+            let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+            let end = match total_length < header_length {
+                true => packet.len(),
+                false => total_length.min(packet.len())
+            };
+            let source: [u8; 4] = packet[12..16].try_into().ok()?;
+            let destination: [u8; 4] = packet[16..20].try_into().ok()?;
+            (net::IpAddr::from(source), net::IpAddr::from(destination), packet[9], &packet[header_length..end])
+        },
+        6 => {
+            if packet.len() < 40 {
+                return None;
+            }
+            let payload_length = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+            let end = match payload_length {
+                0 => packet.len(),
+                _ => (40 + payload_length).min(packet.len())
+            };
+            let source: [u8; 16] = packet[8..24].try_into().ok()?;
+            let destination: [u8; 16] = packet[24..40].try_into().ok()?;
+            let mut next_header = packet[6];
+            let mut payload = &packet[40..end];
+            while let IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS = next_header {
+                let length = (*payload.get(1)? as usize + 1) * 8;
+                next_header = payload[0];
+                payload = payload.get(length..)?;
+            }
+            if next_header == IPV6_FRAGMENT {
+                return None;
+            }
+            (net::IpAddr::from(source), net::IpAddr::from(destination), next_header, payload)
+        },
+        _ => return None
+    };
+    let field = |offset: usize| Some(u16::from_be_bytes([*payload.get(offset)?, *payload.get(offset + 1)?]));
+    let (source, destination) = (net::SocketAddr::new(source, field(0)?), net::SocketAddr::new(destination, field(2)?));
+    match protocol {
+        PROTOCOL_UDP => {
+            let length = (field(4)? as usize).clamp(8, payload.len().max(8));
+            Some(Segment { protocol: Protocol::Udp, source, destination, sequence: 0, flags: 0, payload: payload.get(8..length)? })
+        },
+        PROTOCOL_TCP => {
+            let header_length = (*payload.get(12)? >> 4) as usize * 4;
+            Some(Segment {
+                protocol: Protocol::Tcp,
+                source,
+                destination,
+                sequence: u32::from_be_bytes(payload.get(4..8)?.try_into().ok()?),
+                flags: *payload.get(13)?,
+                payload: payload.get(header_length.max(20)..)?
+            })
+        },
+        _ => None
+    }
+}
+
+/// Decodes a payload of `segment` as a DNS message.
+fn decode(payload: &[u8], timestamp: SystemTime, segment: &Segment) -> Result<CapturedMessage> {
+    let mut tcp_packet = Box::new(TcpPacket::empty());
+    let length = payload.len().min(tcp_packet.buffer.len());
+    tcp_packet.buffer[..length].copy_from_slice(&payload[..length]);
+    // The buffer is padded with zeroes, which must not be read as part of the message.
+    let result = DnsMessage::read_from_udp_packet(&mut tcp_packet).and_then(|message| match tcp_packet.position > length {
+        true => Err(UdpPacketError::OutOfBounds { length, index: tcp_packet.position - 1 }),
+        false => Ok(message)
+    });
+    match result {
+        Ok(message) => Ok(CapturedMessage {
+            timestamp,
+            source: segment.source,
+            destination: segment.destination,
+            protocol: segment.protocol,
+            message
+        }),
+        Err(source) => Err(PcapError::InvalidMessage { from: segment.source, to: segment.destination, source: Box::new(source) })
+    }
+}
+
+/// Statistics of the messages of a capture. Responses are matched with the query sent by their
+/// destination to their source with the same id, to measure response times.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub queries: usize,
+    pub responses: usize,
+    pub invalid: usize,                             // Payloads which are not DNS messages
+    pub answered: usize,                            // Queries matched with a response
+    pub total_response_time: Duration,              // Of the answered queries
+    pub max_response_time: Duration,
+    pub question_types: BTreeMap<String, usize>,    // Of queries
+    pub response_codes: BTreeMap<String, usize>,
+    pub names: BTreeMap<String, usize>,             // The names of the questions of queries
+    outstanding: HashMap<(net::SocketAddr, net::SocketAddr, u16), SystemTime>   // Queries not matched yet
+}
+
+impl Summary {
+    pub fn add(&mut self, captured: &CapturedMessage) {
+        let header = &captured.message.header;
+        match header.response {
+            false => {
+                self.queries += 1;
+                for question in captured.message.questions.iter() {
+                    *self.question_types.entry(question.question_type.to_string()).or_default() += 1;
+                    *self.names.entry(question.name.to_string()).or_default() += 1;
+                }
+                self.outstanding.insert((captured.source, captured.destination, header.id), captured.timestamp);
+            },
+            true => {
+                self.responses += 1;
+                *self.response_codes.entry(header.response_code.to_string()).or_default() += 1;
+                if let Some(sent) = self.outstanding.remove(&(captured.destination, captured.source, header.id)) {
+                    let response_time = captured.timestamp.duration_since(sent).unwrap_or_default();
+                    self.answered += 1;
+                    self.total_response_time += response_time;
+                    self.max_response_time = self.max_response_time.max(response_time);
+                }
+            }
+        }
+    }
+
+    /// Adds the result of PcapReader::next, counting invalid messages and ignoring other errors.
+    pub fn add_result(&mut self, result: &Result<CapturedMessage>) {
+        match result {
+            Ok(captured) => self.add(captured),
+            Err(PcapError::InvalidMessage { .. }) => self.invalid += 1,
+            Err(_) => ()
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = |counts: &mut dyn Iterator<Item = (&String, &usize)>| counts.map(|(key, count)| format!("{} {}", key, count)).collect::<Vec<String>>().join(", ");
+        writeln!(f, ";; Messages: {} ({} queries, {} responses, {} invalid)", self.queries + self.responses + self.invalid, self.queries, self.responses, self.invalid)?;
+        write!(f, ";; Answered: {} of {} queries", self.answered, self.queries)?;
+        if self.answered > 0 {
+            let average = self.total_response_time / self.answered as u32;
+            write!(f, ", average response time {:.1} ms, maximum {:.1} ms", average.as_secs_f64() * 1000.0, self.max_response_time.as_secs_f64() * 1000.0)?;
+        }
+        writeln!(f)?;
+        writeln!(f, ";; Question types: {}", counts(&mut self.question_types.iter()))?;
+        writeln!(f, ";; Response codes: {}", counts(&mut self.response_codes.iter()))?;
+        let mut names: Vec<(&String, &usize)> = self.names.iter().collect();
+        names.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(f, ";; Top names: {}", counts(&mut names.into_iter().take(TOP_NAMES)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pcap::*;
+    use crate::dns_message::{DnsHeader, DnsQuestion, ResponseCode};
+    use crate::udp_packet::DomainName;
+    use std::str::FromStr;
+
+    fn query(id: u16, name: &str) -> DnsMessage {
+        let mut message = DnsMessage {
+            header: DnsHeader { id, recursion_desired: true, ..Default::default() },
+            questions: vec![DnsQuestion { name: DomainName::from_str(name).unwrap(), ..Default::default() }],
+            ..Default::default()
+        };
+        message.update_counts();
+        message
+    }
+
+    fn wire_format(message: &DnsMessage) -> Vec<u8> {
+        let mut tcp_packet = Box::new(TcpPacket::empty());
+        message.write_to_udp_packet(&mut tcp_packet).unwrap();
+        tcp_packet.buffer[..tcp_packet.position].to_vec()
+    }
+
+    /// An Ethernet frame of an IPv4 UDP datagram, checksums left at 0.
+    fn udp_frame(source: ([u8; 4], u16), destination: ([u8; 4], u16), payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&(28 + payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0, 64, PROTOCOL_UDP, 0, 0]);
+        frame.extend_from_slice(&source.0);
+        frame.extend_from_slice(&destination.0);
+        frame.extend_from_slice(&source.1.to_be_bytes());
+        frame.extend_from_slice(&destination.1.to_be_bytes());
+        frame.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// A raw IPv6 packet of a TCP segment, with a hop-by-hop options header.
+    fn tcp_packet(source: u16, destination: u16, sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(8 + 20 + payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[IPV6_HOP_BY_HOP, 64]);
+        packet.extend_from_slice(&net::Ipv6Addr::LOCALHOST.octets());
+        packet.extend_from_slice(&net::Ipv6Addr::LOCALHOST.octets());
+        packet.extend_from_slice(&[PROTOCOL_TCP, 0, 1, 4, 0, 0, 0, 0]);
+        packet.extend_from_slice(&source.to_be_bytes());
+        packet.extend_from_slice(&destination.to_be_bytes());
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn pcap_test() {
+        let query = query(4660, "example.com.");
+        let response = query.reply(ResponseCode::NAMEERROR);
+        let client = ([192, 0, 2, 1], 53000);
+        let server = ([192, 0, 2, 53], DNS_PORT);
+        let frames = [
+            (1, udp_frame(client, server, &wire_format(&query))),
+            (2, udp_frame(client, ([192, 0, 2, 80], 80), b"not DNS")),
+            (3, udp_frame(server, client, b"\x12\x34")),
+            (4, udp_frame(server, client, &wire_format(&response)))
+        ];
+        // A big-endian capture with nanosecond timestamps.
+        let mut capture = Vec::new();
+        capture.extend_from_slice(&PCAP_MAGIC_NANOSECONDS.to_be_bytes());
+        capture.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        capture.extend_from_slice(&LINKTYPE_ETHERNET.to_be_bytes());
+        for (time, frame) in frames.iter() {
+            capture.extend_from_slice(&1700000000u32.to_be_bytes());
+            capture.extend_from_slice(&(*time * 250_000_000u32).to_be_bytes());
+            capture.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            capture.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            capture.extend_from_slice(frame);
+        }
+
+        let results: Vec<Result<CapturedMessage>> = PcapReader::new(capture.as_slice()).unwrap().collect();
+        assert_eq!(results.len(), 3);
+        let first = results[0].as_ref().unwrap();
+        assert_eq!(first.timestamp, UNIX_EPOCH + Duration::new(1700000000, 250_000_000));
+        assert_eq!((first.source, first.destination), (net::SocketAddr::from(client), net::SocketAddr::from(server)));
+        assert_eq!(first.protocol, Protocol::Udp);
+        assert_eq!(first.message, query);
+        assert!(matches!(results[1], Err(PcapError::InvalidMessage { .. })));
+        assert_eq!(results[2].as_ref().unwrap().message, response);
+
+        let mut summary = Summary::default();
+        results.iter().for_each(|result| summary.add_result(result));
+        assert_eq!(summary.to_string(), "\
+;; Messages: 3 (1 queries, 1 responses, 1 invalid)
+;; Answered: 1 of 1 queries, average response time 750.0 ms, maximum 750.0 ms
+;; Question types: A 1
+;; Response codes: NAMEERROR 1
+;; Top names: example.com. 1
+");
+
+        // A capture which ends in the middle of a packet.
+        let results: Vec<Result<CapturedMessage>> = PcapReader::new(&capture[..capture.len() - 1]).unwrap().collect();
+        assert!(matches!(results.last(), Some(Err(PcapError::Malformed { .. }))));
+        assert!(matches!(PcapReader::new(&b"GIF89a"[..]), Err(PcapError::UnknownFormat { magic: 0x47494638 })));
+    }
+
+    #[test]
+    fn pcapng_test() {
+        let block = |block_type: u32, body: &[u8]| {
+            let length = (12 + body.len().next_multiple_of(4)) as u32;
+            let mut block = block_type.to_le_bytes().to_vec();
+            block.extend_from_slice(&length.to_le_bytes());
+            block.extend_from_slice(body);
+            block.resize(length as usize - 4, 0);
+            block.extend_from_slice(&length.to_le_bytes());
+            block
+        };
+        let packet = |time: u64, data: &[u8]| {
+            let mut body = 0u32.to_le_bytes().to_vec();
+            body.extend_from_slice(&((time >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(time as u32).to_le_bytes());
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            block(BLOCK_ENHANCED_PACKET, &body)
+        };
+
+        // Two messages over TCP, the second split across segments and preceded by a retransmission.
+        let mut stream = Vec::new();
+        for message in [query(1, "example.com."), query(2, "example.org.")] {
+            let message = wire_format(&message);
+            stream.extend_from_slice(&(message.len() as u16).to_be_bytes());
+            stream.extend_from_slice(&message);
+        }
+        let split = stream.len() - 10;
+        let mut capture = block(BLOCK_SECTION_HEADER, &[0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        // An interface with millisecond timestamps, given by if_tsresol.
+        capture.extend(block(BLOCK_INTERFACE_DESCRIPTION, &[101, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 3, 0, 0, 0, 0, 0, 0, 0]));
+        capture.extend(packet(1000, &tcp_packet(40000, 5353, 99, TCP_SYN, &[])));
+        capture.extend(packet(2000, &tcp_packet(40000, 5353, 100, 0, &stream[..split])));
+        capture.extend(packet(2500, &tcp_packet(40000, 5353, 100, 0, &stream[..split])));
+        capture.extend(packet(3000, &tcp_packet(40000, 5353, 100 + split as u32, TCP_FIN, &stream[split..])));
+
+        let mut reader = PcapReader::new(capture.as_slice()).unwrap();
+        reader.set_port(5353);
+        let messages: Vec<CapturedMessage> = reader.map(Result::unwrap).collect();
+        let ids: Vec<u16> = messages.iter().map(|captured| captured.message.header.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(messages[0].timestamp, UNIX_EPOCH + Duration::from_secs(2));
+        assert_eq!(messages[1].timestamp, UNIX_EPOCH + Duration::from_secs(3));
+        assert_eq!(messages[1].protocol, Protocol::Tcp);
+        assert_eq!(messages[1].source, net::SocketAddr::from((net::Ipv6Addr::LOCALHOST, 40000)));
+
+        // Nothing is sent to or from the default port.
+        assert_eq!(PcapReader::new(capture.as_slice()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,32 @@
+use std::net;
+
+const MIN_RANDOM_PORT: u16 = 1024;  // Ports below 1024 are privileged and never chosen.
+const MAX_PORT_ATTEMPTS: usize = 16; // The number of random ports tried before letting the OS choose.
+
+/// Fills `bytes` with cryptographically secure random bytes from the operating system.
+pub fn fill(bytes: &mut [u8]) {
+    getrandom::fill(bytes).expect("Failed to obtain random bytes from the operating system.");
+}
+
+pub fn random_u16() -> u16 {
+    let mut bytes = [0; 2];
+    fill(&mut bytes);
+    u16::from_be_bytes(bytes)
+}
+
+/// Binds a UdpSocket to a random port above 1023 on the unspecified address of the same family
+/// as `remote`, which makes responses harder to spoof since the port has to be guessed as well.
+pub fn bind_random_port(remote: net::SocketAddr) -> std::io::Result<net::UdpSocket> {
+    let address: net::IpAddr = match remote {
+        net::SocketAddr::V4(_) => net::Ipv4Addr::UNSPECIFIED.into(),
+        net::SocketAddr::V6(_) => net::Ipv6Addr::UNSPECIFIED.into()
+    };
+    for _ in 0..MAX_PORT_ATTEMPTS {
+        let port = MIN_RANDOM_PORT + random_u16() % (u16::MAX - MIN_RANDOM_PORT + 1);
+        if let Ok(udp_socket) = net::UdpSocket::bind((address, port)) {
+            return Ok(udp_socket);
+        }
+    }
+    // The ephemeral ports chosen by the OS are randomised on most systems as well.
+    net::UdpSocket::bind((address, 0))
+}
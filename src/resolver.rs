@@ -0,0 +1,483 @@
+use crate::dns_message::{
+    CombinedClass, CombinedType, DnsMessage, DnsQuestion, DnsRecord, OperationCode, RecordClass, RecordData, RecordType,
+    ResponseCode
+};
+use crate::system_config::{self, Hosts, SystemConfigError};
+use crate::transport::{Transport, UdpTransport};
+use crate::udp_packet::{DomainName, UdpPacketError};
+use std::net;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+const DEFAULT_NAME_SERVER: (net::Ipv4Addr, u16) = (net::Ipv4Addr::new(8, 8, 8, 8), 53);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);   // Same as the glibc default, see resolv.conf(5).
+const DEFAULT_ATTEMPTS: usize = 2;                          // Same as the glibc default, see resolv.conf(5).
+const DEFAULT_NDOTS: usize = 1;                             // Same as the glibc default, see resolv.conf(5).
+const MAX_NAME_LENGTH: usize = 255;                         // The maximum length of a name in wire format.
+
+/// Error handling type for the stub resolver.
+#[derive(Debug)]
+pub enum ResolverError {
+    /// Every attempt to reach every name server timed out.
+    Timeout {
+        servers: Vec<net::SocketAddr>,  // The name servers which were queried.
+        attempts: usize,                // The number of attempts made per name server.
+        timeout: Duration               // The time waited during each attempt.
+    },
+
+    /// A query could not be encoded, or a network operation failed for a reason other than a timeout.
+    UdpPacket {
+        source: UdpPacketError          // The underlying error.
+    },
+
+    /// The resolver has not been configured with any name servers.
+    NoServers,
+
+    /// The system configuration could not be read.
+    SystemConfig {
+        source: SystemConfigError       // The underlying error.
+    }
+}
+
+impl std::fmt::Display for ResolverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout {
+                servers,
+                attempts,
+                timeout
+            } => write!(f, "no response from {:?} after {} attempt(s) of {:?} each", servers, attempts, timeout),
+            Self::UdpPacket {
+                source
+            } => write!(f, "an error occurred while querying a name server, source: {}", source),
+            Self::NoServers => write!(f, "no name servers configured"),
+            Self::SystemConfig {
+                source
+            } => write!(f, "failed to read the system configuration, source: {}", source)
+        }
+    }
+}
+
+impl std::error::Error for ResolverError {}
+
+impl From<UdpPacketError> for ResolverError {
+    fn from(source: UdpPacketError) -> Self {
+        Self::UdpPacket { source }
+    }
+}
+
+impl From<SystemConfigError> for ResolverError {
+    fn from(source: SystemConfigError) -> Self {
+        Self::SystemConfig { source }
+    }
+}
+
+/// Specialised result type for the stub resolver.
+pub type Result<T> = std::result::Result<T, ResolverError>;
+
+/// Configuration of a Resolver, with defaults mirroring those of the glibc stub resolver.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolverConfig {
+    pub servers: Vec<net::SocketAddr>,  // The recursive name servers to query, in order of preference
+    pub timeout: Duration,              // The time to wait for a response from a single name server
+    pub attempts: usize,                // The number of times every name server is tried before giving up
+    pub rotate: bool,                   // Spread the load by starting each lookup at the next name server
+    pub case_randomisation: bool,       // Randomise the case of query names and require it to be echoed ("DNS 0x20"), UDP only
+    pub search: Vec<DomainName>,        // The domains appended to relative names, from search or domain
+    pub ndots: usize                    // Names with at least this many dots are tried as-is before the search list
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            servers: vec![DEFAULT_NAME_SERVER.into()],
+            timeout: DEFAULT_TIMEOUT,
+            attempts: DEFAULT_ATTEMPTS,
+            rotate: false,
+            case_randomisation: false,
+            search: Vec::new(),
+            ndots: DEFAULT_NDOTS
+        }
+    }
+}
+
+/// The outcome of Resolver::search, i.e. the response along with the candidate name it is for.
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub name: DomainName,               // The candidate which was answered, or the last one tried
+    pub response: DnsMessage
+}
+
+/// A stub resolver, i.e. a resolver which relies on recursive name servers to do the actual resolution.
+/// Address queries for names found in the hosts file are answered without going to the network.
+#[derive(Debug)]
+pub struct Resolver<T = UdpTransport> {
+    config: ResolverConfig,
+    hosts: Hosts,
+    transport: T,
+    next_server: AtomicUsize    // The index of the server to start at, used when rotating
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new(ResolverConfig::default())
+    }
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Self {
+        Self::with_hosts(config, Hosts::default())
+    }
+
+    pub fn with_hosts(config: ResolverConfig, hosts: Hosts) -> Self {
+        let transport = UdpTransport { case_randomisation: config.case_randomisation, ..Default::default() };
+        Self::with_transport(config, hosts, transport)
+    }
+
+    /// Creates a resolver configured by /etc/resolv.conf and /etc/hosts.
+    pub fn from_system_config() -> Result<Self> {
+        Self::from_files(system_config::RESOLV_CONF_PATH, system_config::HOSTS_PATH)
+    }
+
+    /// Creates a resolver configured by the given files, in the formats of resolv.conf(5) and hosts(5).
+    pub fn from_files<P: AsRef<Path>, Q: AsRef<Path>>(resolv_conf_path: P, hosts_path: Q) -> Result<Self> {
+        let config = system_config::read_resolv_conf(resolv_conf_path)?;
+        Ok(Self::with_hosts(config, Hosts::read(hosts_path)?))
+    }
+
+}
+
+impl<T: Transport> Resolver<T> {
+    pub fn with_transport(config: ResolverConfig, hosts: Hosts, transport: T) -> Self {
+        Self { config, hosts, transport, next_server: AtomicUsize::new(0) }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn hosts(&self) -> &Hosts {
+        &self.hosts
+    }
+
+    pub fn config(&self) -> &ResolverConfig {
+        &self.config
+    }
+
+    /// Asks the configured name servers for the records of the given name, type and class.
+    pub fn lookup(&self, name: &DomainName, question_type: CombinedType, question_class: CombinedClass) -> Result<DnsMessage> {
+        let query = DnsMessage {
+            questions: vec![DnsQuestion {
+                name: name.clone(),
+                question_type,
+                question_class
+            }],
+            ..Default::default()
+        };
+        self.query(&query)
+    }
+
+    /// Returns the names tried by Resolver::search for `name`, in order. Like glibc, a name ending in
+    /// a dot is absolute and only tried as-is. Other names are tried as-is before the search list if
+    /// they contain at least `ndots` dots, and after it otherwise.
+    pub fn search_candidates(&self, name: &str) -> Result<Vec<DomainName>> {
+        let as_is = DomainName::from_str(name)?;
+        if name.ends_with('.') {
+            return Ok(vec![as_is]);
+        }
+        let mut candidates = Vec::new();
+        for domain in self.config.search.iter() {
+            let mut labels = as_is.labels();
+            labels.extend(domain.labels());
+            let candidate = DomainName::from_labels(&labels);
+            // Candidates exceeding the maximum length of a name are skipped, just like in glibc.
+            if candidate.bytes.len() <= MAX_NAME_LENGTH {
+                candidates.push(candidate);
+            }
+        }
+        match name.matches('.').count() >= self.config.ndots {
+            true => candidates.insert(0, as_is),
+            false => candidates.push(as_is)
+        }
+        Ok(candidates)
+    }
+
+    /// Resolves a possibly relative name by trying the candidates of Resolver::search_candidates in
+    /// turn, until one of them has an answer. Like glibc, the search carries on past NXDOMAIN,
+    /// NODATA and SERVFAIL responses, but stops at the first error. If no candidate has an answer,
+    /// the first NODATA response is returned, or the response to the last candidate if there is none.
+    pub fn search(&self, name: &str, question_type: CombinedType, question_class: CombinedClass) -> Result<SearchResult> {
+        let query = DnsMessage {
+            questions: vec![DnsQuestion {
+                name: DomainName::root(),
+                question_type,
+                question_class
+            }],
+            ..Default::default()
+        };
+        self.search_query(name, &query)
+    }
+
+    /// Like Resolver::search, but every candidate is sent as the name of the first question of
+    /// `query`, which allows the header flags to be chosen by the caller.
+    pub fn search_query(&self, name: &str, query: &DnsMessage) -> Result<SearchResult> {
+        let mut result: Option<SearchResult> = None;
+        let mut nodata: Option<SearchResult> = None;
+        let mut query = query.clone();
+        for candidate in self.search_candidates(name)? {
+            if let Some(question) = query.questions.first_mut() {
+                question.name = candidate.clone();
+            }
+            let response = self.query(&query)?;
+            let current = SearchResult { name: candidate, response };
+            match current.response.header.response_code {
+                ResponseCode::NOERROR if !current.response.answers.is_empty() => return Ok(current),
+                ResponseCode::NOERROR if nodata.is_none() => nodata = Some(current),
+                ResponseCode::NOERROR | ResponseCode::NAMEERROR | ResponseCode::SERVERFAILURE => result = Some(current),
+                _ => return Ok(current)
+            }
+        }
+        // There is always at least one candidate, namely the name itself.
+        Ok(nodata.or(result).unwrap())
+    }
+
+    /// Sends `query` to the configured name servers, trying each of them in turn, until a response
+    /// is received or the configured number of attempts has been made.
+    pub fn query(&self, query: &DnsMessage) -> Result<DnsMessage> {
+        if let Some(response) = self.answer_from_hosts(query) {
+            log::debug!("answered {} from the hosts file", query.questions[0]);
+            return Ok(response);
+        }
+        let servers = &self.config.servers;
+        if servers.is_empty() {
+            return Err(ResolverError::NoServers);
+        }
+        let first_server = match self.config.rotate {
+            true => self.next_server.fetch_add(1, Ordering::Relaxed) % servers.len(),
+            false => 0
+        };
+        let mut last_error = None;
+        for _ in 0..self.config.attempts {
+            for index in 0..servers.len() {
+                let server = servers[(first_server + index) % servers.len()];
+                match self.transport.exchange(query, server, self.config.timeout) {
+                    Ok(response) => return Ok(response),
+                    Err(error) if error.is_timeout() => log::debug!("timed out waiting for {} after {:?}", server, self.config.timeout),
+                    Err(error) => {
+                        log::debug!("failed to query {}: {}", server, error);
+                        last_error = Some(error);
+                    }
+                }
+            }
+        }
+        match last_error {
+            Some(error) => Err(error.into()),
+            None => Err(ResolverError::Timeout {
+                servers: servers.to_vec(),
+                attempts: self.config.attempts,
+                timeout: self.config.timeout
+            })
+        }
+    }
+
+    /// Answers an A or AAAA query from the hosts file, if it holds addresses of the requested family.
+    fn answer_from_hosts(&self, query: &DnsMessage) -> Option<DnsMessage> {
+        let question = match query.questions.as_slice() {
+            [question] if query.header.operation_code == OperationCode::QUERY => question,
+            _ => return None
+        };
+        match question.question_class {
+            CombinedClass::RecordClass(RecordClass::IN) => (),
+            _ => return None
+        }
+        let answers: Vec<DnsRecord> = self.hosts.lookup(&question.name).iter()
+        .filter_map(|address| {
+            let (record_type, length, data) = match (question.question_type, address) {
+                (CombinedType::RecordType(RecordType::A), net::IpAddr::V4(ipv4_address)) => {
+                    (RecordType::A, 4, RecordData::A { ipv4_address: *ipv4_address })
+                },
+                (CombinedType::RecordType(RecordType::AAAA), net::IpAddr::V6(ipv6_address)) => {
+                    (RecordType::AAAA, 16, RecordData::AAAA { ipv6_address: *ipv6_address })
+                },
+                _ => return None
+            };
+            // Entries of the hosts file never expire, but they must not be cached by anyone else either.
+            Some(DnsRecord {
+                name: question.name.clone(),
+                record_type,
+                record_class: RecordClass::IN,
+                ttl: 0,
+                length,
+                data
+            })
+        })
+        .collect();
+        if answers.is_empty() {
+            return None;
+        }
+        let mut response = query.reply(ResponseCode::NOERROR);
+        response.answers = answers;
+        response.update_counts();
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_message::*;
+    use crate::resolver::*;
+    use crate::server::Server;
+    use crate::transport::{MockAction, MockTransport};
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    fn counting_server(counter: Arc<AtomicUsize>) -> net::SocketAddr {
+        let server = Server::bind((net::Ipv4Addr::LOCALHOST, 0), move |query: &DnsMessage| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            query.reply(ResponseCode::NOERROR)
+        }).unwrap();
+        let address = server.local_addr().unwrap();
+        server.spawn();
+        address
+    }
+
+    fn lookup<T: Transport>(resolver: &Resolver<T>) -> Result<DnsMessage> {
+        resolver.lookup(&DomainName::from_str(TEST_DOMAIN).unwrap(), CombinedType::default(), CombinedClass::default())
+    }
+
+    #[test]
+    fn timeout_test() {
+        let silent = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let resolver = Resolver::new(ResolverConfig {
+            servers: vec![silent.local_addr().unwrap()],
+            timeout: Duration::from_millis(50),
+            attempts: 3,
+            ..Default::default()
+        });
+        match lookup(&resolver) {
+            Err(ResolverError::Timeout { attempts, .. }) => assert_eq!(attempts, 3),
+            result => panic!("Expected a timeout, got {:?}.", result)
+        }
+    }
+
+    #[test]
+    fn failover_and_rotation_test() {
+        let silent = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let first_count = Arc::new(AtomicUsize::new(0));
+        let second_count = Arc::new(AtomicUsize::new(0));
+        let mut config = ResolverConfig {
+            servers: vec![silent.local_addr().unwrap(), counting_server(Arc::clone(&first_count))],
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let response = lookup(&Resolver::new(config.clone())).expect("Failed to fail over.");
+        assert!(response.header.response);
+        assert_eq!(first_count.load(Ordering::SeqCst), 1);
+
+        let third_count = Arc::new(AtomicUsize::new(0));
+        config.servers = vec![counting_server(Arc::clone(&second_count)), counting_server(Arc::clone(&third_count))];
+        config.rotate = true;
+        let resolver = Resolver::new(config);
+        for _ in 0..4 {
+            lookup(&resolver).expect("Failed to resolve.");
+        }
+        assert_eq!(second_count.load(Ordering::SeqCst), 2);
+        assert_eq!(third_count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn hosts_test() {
+        let resolver = Resolver::from_files(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/resolv.conf"),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/hosts")
+        ).expect("Failed to read the fixtures.");
+        assert!(resolver.config().rotate);
+        // The name servers of the fixture are unreachable, so the answer has to come from the hosts file.
+        let name = DomainName::from_str("localhost").unwrap();
+        let response = resolver.lookup(&name, CombinedType::RecordType(RecordType::AAAA), CombinedClass::default())
+        .expect("Failed to resolve.");
+        assert_eq!(response.header.response_code, ResponseCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].data, RecordData::AAAA { ipv6_address: net::Ipv6Addr::LOCALHOST });
+    }
+
+    #[test]
+    fn search_candidates_test() {
+        let resolver = Resolver::new(ResolverConfig {
+            search: vec![DomainName::from_str("corp.example.com").unwrap(), DomainName::from_str("example.com").unwrap()],
+            ..Default::default()
+        });
+        let candidates = |name: &str| -> Vec<String> {
+            resolver.search_candidates(name).unwrap().iter().map(DomainName::to_string).collect()
+        };
+        assert_eq!(candidates("db01"), vec!["db01.corp.example.com.", "db01.example.com.", "db01."]);
+        assert_eq!(candidates("db01.eu"), vec!["db01.eu.", "db01.eu.corp.example.com.", "db01.eu.example.com."]);
+        assert_eq!(candidates("db01.eu."), vec!["db01.eu."]);
+    }
+
+    #[test]
+    fn search_test() {
+        let queried = Arc::new(Mutex::new(Vec::new()));
+        let server_queried = Arc::clone(&queried);
+        let server = Server::bind((net::Ipv4Addr::LOCALHOST, 0), move |query: &DnsMessage| {
+            let name = query.questions[0].name.clone();
+            server_queried.lock().unwrap().push(name.to_string());
+            match name.to_string().as_str() {
+                // The name exists, but not with the requested type.
+                "db01.corp.example.com." => query.reply(ResponseCode::NOERROR),
+                "db01.example.com." => {
+                    let mut response = query.reply(ResponseCode::NOERROR);
+                    response.answers.push(DnsRecord {
+                        name,
+                        record_type: RecordType::A,
+                        record_class: RecordClass::IN,
+                        ttl: 300,
+                        length: 4,
+                        data: RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, 1) }
+                    });
+                    response.update_counts();
+                    response
+                },
+                _ => query.reply(ResponseCode::NAMEERROR)
+            }
+        }).unwrap();
+        let address = server.local_addr().unwrap();
+        server.spawn();
+
+        let resolver = Resolver::new(ResolverConfig {
+            servers: vec![address],
+            search: vec![DomainName::from_str("corp.example.com").unwrap(), DomainName::from_str("example.com").unwrap()],
+            ..Default::default()
+        });
+        let result = resolver.search("db01", CombinedType::default(), CombinedClass::default()).expect("Failed to search.");
+        assert_eq!(result.name.to_string(), "db01.example.com.");
+        assert_eq!(result.response.answers.len(), 1);
+        assert_eq!(*queried.lock().unwrap(), vec!["db01.corp.example.com.", "db01.example.com."]);
+
+        // None of the candidates has an answer, so the NODATA response is preferred over NXDOMAIN.
+        let result = resolver.search("db01.corp", CombinedType::default(), CombinedClass::default()).expect("Failed to search.");
+        assert_eq!(result.name.to_string(), "db01.corp.example.com.");
+        assert!(result.response.answers.is_empty());
+    }
+
+    #[test]
+    fn mock_transport_test() {
+        let servers = vec![net::SocketAddr::from(([192, 0, 2, 1], 53)), net::SocketAddr::from(([192, 0, 2, 2], 53))];
+        let config = ResolverConfig { servers: servers.clone(), ..Default::default() };
+        let transport = MockTransport::new();
+        // Every attempt times out, apart from the fourth, i.e. the second attempt at the second server.
+        transport.script(DnsQuestion::default(), vec![
+            MockAction::Drop,
+            MockAction::Drop,
+            MockAction::Drop,
+            MockAction::Fail(ResponseCode::NAMEERROR)
+        ]);
+        let resolver = Resolver::with_transport(config, Hosts::default(), transport);
+        let response = lookup(&resolver).expect("Failed to resolve.");
+        assert_eq!(response.header.response_code, ResponseCode::NAMEERROR);
+        let queried: Vec<net::SocketAddr> = resolver.transport().queries().iter().map(|(server, _)| *server).collect();
+        assert_eq!(queried, vec![servers[0], servers[1], servers[0], servers[1]]);
+    }
+}
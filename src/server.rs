@@ -0,0 +1,455 @@
+use crate::cache::{Cache, Clock, SystemClock};
+use crate::dns_message::{DnsMessage, ResponseCode};
+use crate::dnstap::{self, DnstapSink, MessageType, SocketProtocol};
+use crate::transport::{Transport, UdpTransport};
+use crate::udp_packet::{self, TcpPacket, UdpPacket, UdpPacketError};
+use std::io::{Read, Write};
+use std::net;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);  // Time to wait for each upstream before trying the next.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);         // Time after which the server closes idle TCP connections.
+pub const MAX_THREADS: usize = 256;                             // Default number of queries and TCP connections handled at once.
+
+/// Anything capable of answering a DNS query on behalf of a Server, e.g. a Forwarder.
+///
+/// The returned message is sent to the client as-is, which means that the implementor is
+/// responsible for setting the id and the header counts of the response.
+pub trait QueryHandler: Send + Sync {
+    fn handle(&self, query: &DnsMessage) -> DnsMessage;
+}
+
+impl<F> QueryHandler for F
+where
+    F: Fn(&DnsMessage) -> DnsMessage + Send + Sync
+{
+    fn handle(&self, query: &DnsMessage) -> DnsMessage {
+        self(query)
+    }
+}
+
+/// A DNS server which answers every query it receives over UDP or TCP using a QueryHandler. Both
+/// use the same port, so that clients can retry truncated responses over TCP.
+/// Each query is handled on a separate thread so that a slow upstream does not block other clients.
+/// When the maximum number of threads is reached, UDP queries are answered with SERVFAIL and TCP
+/// connections are closed, so that a burst of queries cannot exhaust threads or memory.
+pub struct Server<H> {
+    socket: net::UdpSocket,
+    listener: net::TcpListener,
+    handler: Arc<H>,
+    threads: ThreadLimit,
+    dnstap: Option<Arc<DnstapSink>>     // Receives CLIENTQUERY and CLIENTRESPONSE messages, see set_dnstap
+}
+
+impl<H: QueryHandler + 'static> Server<H> {
+    pub fn bind<A: net::ToSocketAddrs>(address: A, handler: H) -> udp_packet::Result<Self> {
+        let socket = net::UdpSocket::bind(address).map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to bind the server socket"),
+            source: error
+        })?;
+        // Binding the address of the UDP socket rather than `address` resolves port 0 to the same port.
+        let listener = socket.local_addr().and_then(net::TcpListener::bind).map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to bind the server TCP socket"),
+            source: error
+        })?;
+        Ok(Self { socket, listener, handler: Arc::new(handler), threads: ThreadLimit::new(MAX_THREADS), dnstap: None })
+    }
+
+    /// Sets the number of queries and TCP connections handled at once, MAX_THREADS by default.
+    pub fn set_max_threads(&mut self, max_threads: usize) {
+        self.threads = ThreadLimit::new(max_threads);
+    }
+
+    /// Logs every query received and response sent to `dnstap`.
+    pub fn set_dnstap(&mut self, dnstap: Arc<DnstapSink>) {
+        self.dnstap = Some(dnstap);
+    }
+
+    pub fn local_addr(&self) -> udp_packet::Result<net::SocketAddr> {
+        self.socket.local_addr().map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to read the address of the server socket"),
+            source: error
+        })
+    }
+
+    /// Serves queries until receiving from the UDP socket fails. TCP connections are accepted on a
+    /// background thread, until accepting one fails.
+    pub fn run(&self) -> udp_packet::Result<()> {
+        let listener = self.listener.try_clone().map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to clone the server TCP socket"),
+            source: error
+        })?;
+        let handler = Arc::clone(&self.handler);
+        let threads = self.threads.clone();
+        let dnstap = self.dnstap.clone();
+        thread::spawn(move || serve_tcp(&listener, handler, &threads, dnstap));
+        loop {
+            let mut udp_packet = UdpPacket::new();
+            let (length, client) = udp_packet.recv_from(&self.socket)?;
+            let query_time = SystemTime::now();
+            let Some(permit) = self.threads.try_acquire() else {
+                log::debug!("too many queries in progress, answering {} with SERVFAIL", client);
+                let response = respond(&|query: &DnsMessage| query.reply(ResponseCode::SERVERFAILURE), &mut udp_packet);
+                if let Err(error) = response.send_to(&self.socket, client) {
+                    log::warn!("failed to respond to {}: {}", client, error);
+                }
+                continue;
+            };
+            let socket = self.socket.try_clone().map_err(|error| UdpPacketError::NetworkIo {
+                description: String::from("failed to clone the server socket"),
+                source: error
+            })?;
+            let handler = Arc::clone(&self.handler);
+            let dnstap = self.dnstap.clone().map(|sink| ClientTap::new(sink, SocketProtocol::UDP, client, socket.local_addr().ok()));
+            thread::spawn(move || {
+                let _permit = permit;
+                let logged = dnstap.as_ref().map(|tap| tap.log_query(query_time, udp_packet.buffer[..length].to_vec()));
+                let response = respond(handler.as_ref(), &mut udp_packet);
+                // A failure to reach a single client should not stop the server.
+                if let Err(error) = response.send_to(&socket, client) {
+                    log::warn!("failed to respond to {}: {}", client, error);
+                    return;
+                }
+                if let Some((tap, message)) = dnstap.as_ref().zip(logged) {
+                    tap.log_response(message, response.buffer[..response.position].to_vec());
+                }
+            });
+        }
+    }
+
+    /// Runs the server on a background thread, mostly useful for tests and embedding.
+    pub fn spawn(self) -> thread::JoinHandle<udp_packet::Result<()>> {
+        thread::spawn(move || self.run())
+    }
+}
+
+/// Serves every connection accepted by `listener` on a separate thread, until accepting one fails.
+/// Connections beyond the limit of `threads` are closed right away.
+fn serve_tcp<H: QueryHandler + 'static>(listener: &net::TcpListener, handler: Arc<H>, threads: &ThreadLimit, dnstap: Option<Arc<DnstapSink>>) {
+    loop {
+        let (mut stream, client) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                log::error!("failed to accept a TCP connection: {}", error);
+                return;
+            }
+        };
+        let Some(permit) = threads.try_acquire() else {
+            log::debug!("too many queries in progress, closing the connection from {}", client);
+            continue;
+        };
+        let handler = Arc::clone(&handler);
+        let dnstap = dnstap.clone().map(|sink| ClientTap::new(sink, SocketProtocol::TCP, client, stream.local_addr().ok()));
+        thread::spawn(move || {
+            let _permit = permit;
+            let _ = stream.set_read_timeout(Some(IDLE_TIMEOUT));
+            let _ = stream.set_write_timeout(Some(IDLE_TIMEOUT));
+            serve_stream(handler.as_ref(), &mut stream, dnstap.as_ref());
+        });
+    }
+}
+
+/// Bounds the number of threads handling queries, shared by the UDP and TCP parts of a Server.
+#[derive(Clone)]
+struct ThreadLimit {
+    running: Arc<AtomicUsize>,
+    max_threads: usize
+}
+
+/// Allows a thread to run until it is dropped, see ThreadLimit::try_acquire.
+struct ThreadPermit(Arc<AtomicUsize>);
+
+impl ThreadLimit {
+    fn new(max_threads: usize) -> Self {
+        Self { running: Arc::new(AtomicUsize::new(0)), max_threads }
+    }
+
+    /// Returns a permit to start a thread, or None if the maximum number of threads is running.
+    fn try_acquire(&self) -> Option<ThreadPermit> {
+        self.running.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+            (running < self.max_threads).then_some(running + 1)
+        }).ok()?;
+        Some(ThreadPermit(Arc::clone(&self.running)))
+    }
+}
+
+impl Drop for ThreadPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Logs the queries received from a client and the responses sent to it as CLIENTQUERY and
+/// CLIENTRESPONSE messages, shared by every kind of server.
+pub(crate) struct ClientTap {
+    sink: Arc<DnstapSink>,
+    message: dnstap::Message        // The protocol and addresses of the client, common to its messages
+}
+
+impl ClientTap {
+    pub(crate) fn new(sink: Arc<DnstapSink>, socket_protocol: SocketProtocol, client: net::SocketAddr, local_address: Option<net::SocketAddr>) -> Self {
+        let message = dnstap::Message {
+            socket_protocol: Some(socket_protocol),
+            query_address: Some(client),
+            response_address: local_address,
+            ..dnstap::Message::new(MessageType::CLIENTQUERY)
+        };
+        Self { sink, message }
+    }
+
+    /// Logs a query in wire format, returning the message to pass to ClientTap::log_response.
+    pub(crate) fn log_query(&self, query_time: SystemTime, query: Vec<u8>) -> dnstap::Message {
+        let message = dnstap::Message { query_time: Some(query_time), query_message: Some(query), ..self.message.clone() };
+        self.sink.log(message.clone());
+        message
+    }
+
+    pub(crate) fn log_response(&self, query: dnstap::Message, response: Vec<u8>) {
+        self.sink.log(dnstap::Message {
+            message_type: MessageType::CLIENTRESPONSE,
+            response_time: Some(SystemTime::now()),
+            response_message: Some(response),
+            ..query
+        });
+    }
+}
+
+/// Answers every query read from `stream`, using the framing of DNS over TCP, until the peer closes
+/// the connection or an error occurs. Used by stream based servers, e.g. the DNS over TLS server.
+pub(crate) fn serve_stream<H: QueryHandler + ?Sized, S: Read + Write>(handler: &H, stream: &mut S, dnstap: Option<&ClientTap>) {
+    loop {
+        let mut tcp_packet = Box::new(TcpPacket::empty());
+        let Ok(length) = tcp_packet.recv_framed(stream) else {
+            return;
+        };
+        let logged = dnstap.map(|tap| tap.log_query(SystemTime::now(), tcp_packet.buffer[..length].to_vec()));
+        let response = respond(handler, &mut tcp_packet);
+        if response.send_framed(stream).is_err() {
+            return;
+        }
+        if let Some((tap, message)) = dnstap.zip(logged) {
+            tap.log_response(message, response.buffer[..response.position].to_vec());
+        }
+    }
+}
+
+/// Decodes a query from `udp_packet` and encodes the handler's response to it into a new packet of
+/// the same size.
+fn respond<H: QueryHandler + ?Sized, const N: usize>(handler: &H, udp_packet: &mut UdpPacket<N>) -> Box<UdpPacket<N>> {
+    let response = match DnsMessage::read_from_udp_packet(udp_packet) {
+        Ok(query) => {
+            log::debug!("received query {} for {}", query.header.id, query.questions.iter().map(ToString::to_string).collect::<Vec<String>>().join(", "));
+            handler.handle(&query)
+        },
+        Err(error) => {
+            log::debug!("received a malformed query: {}", error);
+            // The id is the only part of a malformed query which can be echoed with any certainty.
+            let mut query = DnsMessage { questions: Vec::new(), ..Default::default() };
+            query.header.id = u16::from_be_bytes([udp_packet.buffer[0], udp_packet.buffer[1]]);
+            query.reply(ResponseCode::FORMATERROR)
+        }
+    };
+    let mut response_packet = Box::new(UdpPacket::empty());
+    if response.write_to_udp_packet(&mut response_packet).is_err() {
+        // The response does not fit in a UDP packet, so only the header and questions are sent
+        // with the TC flag set.
+        let mut truncated = response.reply(response.header.response_code);
+        truncated.header.truncated = true;
+        truncated.header.recursion_available = response.header.recursion_available;
+        response_packet = Box::new(UdpPacket::empty());
+        if truncated.write_to_udp_packet(&mut response_packet).is_err() {
+            response_packet = Box::new(UdpPacket::empty());
+        }
+    }
+    response_packet
+}
+
+/// A QueryHandler which forwards queries to a list of recursive upstream servers and caches
+/// their answers, including negative ones, for as long as their TTL:s allow.
+pub struct Forwarder<C = SystemClock, T = UdpTransport> {
+    upstreams: Vec<net::SocketAddr>,
+    timeout: Duration,
+    cache: Mutex<Cache<C>>,
+    transport: T            // Used to reach the upstreams, e.g. to forward over TLS
+}
+
+impl Forwarder {
+    pub fn new(upstreams: Vec<net::SocketAddr>) -> Self {
+        Self::with_timeout(upstreams, UPSTREAM_TIMEOUT)
+    }
+
+    pub fn with_timeout(upstreams: Vec<net::SocketAddr>, timeout: Duration) -> Self {
+        Self::with_cache(upstreams, timeout, Cache::default())
+    }
+}
+
+impl<C: Clock> Forwarder<C> {
+    pub fn with_cache(upstreams: Vec<net::SocketAddr>, timeout: Duration, cache: Cache<C>) -> Self {
+        Self::with_transport(upstreams, timeout, cache, UdpTransport::default())
+    }
+}
+
+impl<C: Clock, T: Transport> Forwarder<C, T> {
+    pub fn with_transport(upstreams: Vec<net::SocketAddr>, timeout: Duration, cache: Cache<C>, transport: T) -> Self {
+        Self { upstreams, timeout, cache: Mutex::new(cache), transport }
+    }
+}
+
+impl<C: Clock, T: Transport> QueryHandler for Forwarder<C, T> {
+    fn handle(&self, query: &DnsMessage) -> DnsMessage {
+        if let Some(mut response) = self.cache.lock().unwrap().answer(query) {
+            response.header.recursion_available = true;
+            return response;
+        }
+        for upstream in self.upstreams.iter() {
+            if let Ok(mut response) = self.transport.exchange(query, *upstream, self.timeout) {
+                self.cache.lock().unwrap().insert_message(&response);
+                response.header.id = query.header.id;
+                return response;
+            }
+        }
+        let mut response = query.reply(ResponseCode::SERVERFAILURE);
+        response.header.recursion_available = true;
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_message::*;
+    use crate::server::*;
+    use crate::transport::TcpTransport;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn exchange(server: net::SocketAddr, query: &DnsMessage) -> DnsMessage {
+        let socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).expect("Failed to bind socket.");
+        socket.set_read_timeout(Some(Duration::from_secs(5))).expect("Failed to set timeout.");
+        socket.connect(server).expect("Failed to connect to server.");
+        let mut udp_packet = UdpPacket::new();
+        query.write_to_udp_packet(&mut udp_packet).expect("Failed to write query.");
+        udp_packet.send(&socket).expect("Failed to send query.");
+        let mut response_packet = UdpPacket::new();
+        response_packet.recv(&socket).expect("Failed to receive response.");
+        DnsMessage::read_from_udp_packet(&mut response_packet).expect("Failed to decode response.")
+    }
+
+    fn query(id: u16) -> DnsMessage {
+        let mut query = DnsMessage::default();
+        query.header.id = id;
+        query
+    }
+
+    #[test]
+    fn forwarder_cache_test() {
+        let num_queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&num_queries);
+        let upstream = Server::bind((net::Ipv4Addr::LOCALHOST, 0), move |query: &DnsMessage| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut response = query.reply(ResponseCode::NOERROR);
+            response.answers.push(DnsRecord {
+                name: udp_packet::DomainName::from_str(TEST_DOMAIN).unwrap(),
+                record_type: RecordType::A,
+                record_class: RecordClass::IN,
+                ttl: 300,
+                length: 4,
+                data: RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, 1) }
+            });
+            response.update_counts();
+            response
+        }).expect("Failed to bind upstream.");
+        let upstream_address = upstream.local_addr().unwrap();
+        upstream.spawn();
+
+        let server = Server::bind((net::Ipv4Addr::LOCALHOST, 0), Forwarder::new(vec![upstream_address]))
+        .expect("Failed to bind server.");
+        let server_address = server.local_addr().unwrap();
+        server.spawn();
+
+        let first = exchange(server_address, &query(1));
+        assert_eq!(first.header.id, 1);
+        assert_eq!(first.answers.len(), 1);
+        let second = exchange(server_address, &query(2));
+        assert_eq!(second.header.id, 2);
+        assert_eq!(second.answers, first.answers);
+        assert_eq!(num_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn tcp_test() {
+        // Too many answers for a UDP packet, so the client has to retry over TCP.
+        let mut server = Server::bind((net::Ipv4Addr::LOCALHOST, 0), |query: &DnsMessage| {
+            let mut response = query.reply(ResponseCode::NOERROR);
+            for index in 0..64 {
+                response.answers.push(DnsRecord {
+                    name: udp_packet::DomainName::from_str(TEST_DOMAIN).unwrap(),
+                    record_type: RecordType::A,
+                    record_class: RecordClass::IN,
+                    ttl: 300,
+                    length: 4,
+                    data: RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, index) }
+                });
+            }
+            response.update_counts();
+            response
+        }).expect("Failed to bind server.");
+        let (sink, messages) = dnstap::memory_sink();
+        server.set_dnstap(sink);
+        let server_address = server.local_addr().unwrap();
+        server.spawn();
+
+        let truncated = exchange(server_address, &query(1));
+        assert!(truncated.header.truncated);
+        assert!(truncated.answers.is_empty());
+        let response = TcpTransport.exchange(&query(2), server_address, Duration::from_secs(5))
+        .expect("Failed to exchange over TCP.");
+        assert_eq!(response.header.id, 2);
+        assert!(!response.header.truncated);
+        assert_eq!(response.answers.len(), 64);
+
+        // The UDP and TCP messages are logged by different threads, so only their order within a
+        // protocol is known.
+        let messages = messages(4);
+        let types = |socket_protocol: SocketProtocol| messages.iter()
+        .filter(|message| message.socket_protocol == Some(socket_protocol))
+        .map(|message| message.message_type)
+        .collect::<Vec<MessageType>>();
+        assert_eq!(types(SocketProtocol::UDP), vec![MessageType::CLIENTQUERY, MessageType::CLIENTRESPONSE]);
+        assert_eq!(types(SocketProtocol::TCP), vec![MessageType::CLIENTQUERY, MessageType::CLIENTRESPONSE]);
+        assert!(messages.iter().all(|message| message.response_address == Some(server_address)));
+    }
+
+    #[test]
+    fn thread_limit_test() {
+        let mut server = Server::bind((net::Ipv4Addr::LOCALHOST, 0), |query: &DnsMessage| {
+            if query.header.id == 1 {
+                thread::sleep(Duration::from_millis(500));
+            }
+            query.reply(ResponseCode::NOERROR)
+        }).expect("Failed to bind server.");
+        server.set_max_threads(1);
+        let server_address = server.local_addr().unwrap();
+        server.spawn();
+
+        let slow = thread::spawn(move || exchange(server_address, &query(1)));
+        thread::sleep(Duration::from_millis(100));
+        let refused = exchange(server_address, &query(2));
+        assert_eq!(refused.header.id, 2);
+        assert_eq!(refused.header.response_code, ResponseCode::SERVERFAILURE);
+        assert_eq!(slow.join().unwrap().header.response_code, ResponseCode::NOERROR);
+        assert_eq!(exchange(server_address, &query(3)).header.response_code, ResponseCode::NOERROR);
+    }
+
+    #[test]
+    fn forwarder_server_failure_test() {
+        // Bound but never read from, so every forwarded query times out.
+        let silent = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let forwarder = Forwarder::with_timeout(vec![silent.local_addr().unwrap()], Duration::from_millis(100));
+        let response = forwarder.handle(&query(7));
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.response_code, ResponseCode::SERVERFAILURE);
+    }
+}
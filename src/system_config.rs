@@ -0,0 +1,195 @@
+use crate::resolver::ResolverConfig;
+use crate::udp_packet::DomainName;
+use std::collections::HashMap;
+use std::net;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+pub const HOSTS_PATH: &str = "/etc/hosts";
+
+const NAME_SERVER_PORT: u16 = 53;
+const MAX_NAME_SERVERS: usize = 3;                          // MAXNS, later name servers are ignored by glibc.
+const MAX_NDOTS: usize = 15;                                // The upper bound of options ndots, see resolv.conf(5).
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);      // The upper bound of options timeout, see resolv.conf(5).
+const MAX_ATTEMPTS: usize = 5;                              // The upper bound of options attempts, see resolv.conf(5).
+
+/// Error handling type for reading the system configuration.
+#[derive(Debug)]
+pub enum SystemConfigError {
+    /// A configuration file exists but could not be read.
+    Io {
+        path: PathBuf,                  // The file which was read.
+        source: std::io::Error          // The underlying error.
+    }
+}
+
+impl std::fmt::Display for SystemConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io {
+                path,
+                source
+            } => write!(f, "failed to read {}, source: {}", path.display(), source)
+        }
+    }
+}
+
+impl std::error::Error for SystemConfigError {}
+
+/// Specialised result type for reading the system configuration.
+pub type Result<T> = std::result::Result<T, SystemConfigError>;
+
+/// Reads a file, treating a missing file as an empty one, which is how glibc treats both
+/// resolv.conf and the hosts file.
+fn read_to_string(path: &Path) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(error) => Err(SystemConfigError::Io { path: path.to_path_buf(), source: error })
+    }
+}
+
+/// Reads a resolver configuration in the format of resolv.conf(5) from `path`.
+pub fn read_resolv_conf<P: AsRef<Path>>(path: P) -> Result<ResolverConfig> {
+    Ok(parse_resolv_conf(&read_to_string(path.as_ref())?))
+}
+
+/// Parses a resolver configuration in the format of resolv.conf(5). Like glibc, unknown and
+/// malformed lines are ignored, the last of the search and domain keywords wins and the local
+/// name server is used if none is given.
+pub fn parse_resolv_conf(contents: &str) -> ResolverConfig {
+    let mut config = ResolverConfig { servers: Vec::new(), ..Default::default() };
+    for line in contents.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("nameserver") => {
+                // Link-local IPv6 addresses may carry a zone index, which is not supported.
+                let address = words.next().and_then(|address| net::IpAddr::from_str(address).ok());
+                if let Some(address) = address {
+                    if config.servers.len() < MAX_NAME_SERVERS {
+                        config.servers.push(net::SocketAddr::new(address, NAME_SERVER_PORT));
+                    }
+                }
+            },
+            Some("domain") => {
+                config.search = words.next().and_then(|domain| DomainName::from_str(domain).ok()).into_iter().collect();
+            },
+            Some("search") => {
+                config.search = words.filter_map(|domain| DomainName::from_str(domain).ok()).collect();
+            },
+            Some("options") => {
+                for option in words {
+                    let (name, value) = match option.split_once(':') {
+                        Some((name, value)) => (name, value.parse::<usize>().ok()),
+                        None => (option, None)
+                    };
+                    match (name, value) {
+                        ("ndots", Some(ndots)) => config.ndots = ndots.min(MAX_NDOTS),
+                        ("timeout", Some(timeout)) => {
+                            config.timeout = Duration::from_secs(timeout as u64).clamp(Duration::from_secs(1), MAX_TIMEOUT);
+                        },
+                        ("attempts", Some(attempts)) => config.attempts = attempts.clamp(1, MAX_ATTEMPTS),
+                        ("rotate", None) => config.rotate = true,
+                        _ => ()
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+    if config.servers.is_empty() {
+        config.servers.push(net::SocketAddr::new(net::Ipv4Addr::LOCALHOST.into(), NAME_SERVER_PORT));
+    }
+    config
+}
+
+/// The static host name to address mappings of a hosts(5) file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hosts {
+    entries: HashMap<DomainName, Vec<net::IpAddr>>
+}
+
+impl Hosts {
+    /// Reads a file in the format of hosts(5) from `path`.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::parse(&read_to_string(path.as_ref())?))
+    }
+
+    /// Parses a file in the format of hosts(5), i.e. lines holding an address followed by the
+    /// canonical name and aliases of the host. Malformed lines are ignored.
+    pub fn parse(contents: &str) -> Self {
+        let mut entries: HashMap<DomainName, Vec<net::IpAddr>> = HashMap::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let address = match words.next().and_then(|address| net::IpAddr::from_str(address).ok()) {
+                Some(address) => address,
+                None => continue
+            };
+            for name in words.filter_map(|name| DomainName::from_str(name).ok()) {
+                let addresses = entries.entry(name).or_default();
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+        }
+        Self { entries }
+    }
+
+    /// Returns the addresses of `name`, in the order in which they appear in the file.
+    pub fn lookup(&self, name: &DomainName) -> &[net::IpAddr] {
+        self.entries.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::system_config::*;
+
+    const RESOLV_CONF_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/resolv.conf");
+    const HOSTS_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/hosts");
+
+    fn name(name: &str) -> DomainName {
+        DomainName::from_str(name).unwrap()
+    }
+
+    #[test]
+    fn resolv_conf_test() {
+        let config = read_resolv_conf(RESOLV_CONF_FIXTURE).expect("Failed to read resolv.conf.");
+        assert_eq!(config.servers, vec![
+            net::SocketAddr::from(([192, 0, 2, 53], 53)),
+            net::SocketAddr::from((net::Ipv6Addr::from_str("2001:db8::53").unwrap(), 53)),
+            net::SocketAddr::from(([192, 0, 2, 54], 53))
+        ]);
+        assert_eq!(config.search, vec![name("corp.example.com"), name("example.com")]);
+        assert_eq!(config.ndots, 2);
+        assert_eq!(config.timeout, Duration::from_secs(3));
+        assert_eq!(config.attempts, MAX_ATTEMPTS);
+        assert!(config.rotate);
+    }
+
+    #[test]
+    fn resolv_conf_defaults_test() {
+        let config = parse_resolv_conf("search example.com\ndomain example.net\n");
+        assert_eq!(config.servers, vec![net::SocketAddr::from(([127, 0, 0, 1], 53))]);
+        assert_eq!(config.search, vec![name("example.net")]);
+        let missing = read_resolv_conf(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/missing"));
+        assert_eq!(missing.expect("Failed to handle a missing file."), parse_resolv_conf(""));
+    }
+
+    #[test]
+    fn hosts_test() {
+        let hosts = Hosts::read(HOSTS_FIXTURE).expect("Failed to read hosts.");
+        let localhost: Vec<net::IpAddr> = vec![net::Ipv4Addr::LOCALHOST.into(), net::Ipv6Addr::LOCALHOST.into()];
+        assert_eq!(hosts.lookup(&name("localhost")), localhost.as_slice());
+        assert_eq!(hosts.lookup(&name("DB.example.com")), &[net::IpAddr::from([192, 0, 2, 10])]);
+        assert_eq!(hosts.lookup(&name("db")), &[net::IpAddr::from([192, 0, 2, 10])]);
+        assert!(hosts.lookup(&name("commented.example.com")).is_empty());
+    }
+}
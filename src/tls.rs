@@ -0,0 +1,518 @@
+use crate::dns_message::DnsMessage;
+use crate::dnstap::{DnstapSink, SocketProtocol};
+use crate::server::{self, ClientTap, QueryHandler};
+use crate::transport::{self, Transport};
+use crate::udp_packet::{self, UdpPacketError};
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use std::net;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const DOT_PORT: u16 = 853;
+
+const ALPN_PROTOCOL: &[u8] = b"dot";                    // The ALPN protocol id of DNS over TLS.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10); // Time after which the server closes idle connections.
+const SHA256_LENGTH_BYTES: usize = 32;
+
+/// Error handling type for DNS over TLS.
+#[derive(Debug)]
+pub enum TlsError {
+    /// rustls rejected the configuration, e.g. because of an unsupported private key.
+    Tls {
+        source: rustls::Error           // The underlying error.
+    },
+
+    /// A certificate or private key could not be read.
+    Pem {
+        path: String,                   // The file which was read.
+        description: String             // A description of the problem.
+    },
+
+    /// A certificate could not be used, e.g. as a trust anchor.
+    InvalidCertificate {
+        description: String             // A description of the problem.
+    },
+
+    /// An SPKI pin was not the base64 encoding of a SHA-256 digest.
+    InvalidPin {
+        pin: String                     // The offending pin.
+    }
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tls {
+                source
+            } => write!(f, "invalid TLS configuration, source: {}", source),
+            Self::Pem {
+                path,
+                description
+            } => write!(f, "failed to read {}: {}", path, description),
+            Self::InvalidCertificate {
+                description
+            } => write!(f, "invalid certificate: {}", description),
+            Self::InvalidPin {
+                pin
+            } => write!(f, "invalid SPKI pin {}, expected a base64 encoded SHA-256 digest", pin)
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<rustls::Error> for TlsError {
+    fn from(source: rustls::Error) -> Self {
+        Self::Tls { source }
+    }
+}
+
+/// Specialised result type for DNS over TLS.
+pub type Result<T> = std::result::Result<T, TlsError>;
+
+/// The SHA-256 digest of the SubjectPublicKeyInfo of a certificate, as used by the out-of-band
+/// key-pinned privacy profile of RFC 7858. Written as base64, like the pin-sha256 of RFC 7469.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpkiPin(pub [u8; SHA256_LENGTH_BYTES]);
+
+impl SpkiPin {
+    /// Computes the pin of a DER encoded certificate, or returns None if it cannot be decoded.
+    pub fn of_certificate(certificate: &[u8]) -> Option<Self> {
+        let certificate = CertificateDer::from(certificate);
+        let certificate = webpki::EndEntityCert::try_from(&certificate).ok()?;
+        Self::of_parsed_certificate(&certificate)
+    }
+
+    /// Computes the pin of a certificate parsed by webpki, e.g. an intermediate of a verified chain.
+    fn of_parsed_certificate(certificate: &webpki::Cert<'_>) -> Option<Self> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, certificate.subject_public_key_info().as_ref());
+        Some(Self(digest.as_ref().try_into().ok()?))
+    }
+}
+
+impl FromStr for SpkiPin {
+    type Err = TlsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || TlsError::InvalidPin { pin: String::from(s) };
+        let bytes = base64::engine::general_purpose::STANDARD.decode(s).map_err(|_| invalid())?;
+        Ok(Self(bytes.try_into().map_err(|_| invalid())?))
+    }
+}
+
+impl std::fmt::Display for SpkiPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", base64::engine::general_purpose::STANDARD.encode(self.0))
+    }
+}
+
+/// Configuration of a TlsTransport.
+#[derive(Clone, Debug, Default)]
+pub struct TlsClientConfig {
+    pub server_name: Option<String>,    // Sent as SNI and verified, the address of the server is verified if None
+    pub root_certificates: Vec<CertificateDer<'static>>,   // Trusted in addition to the Mozilla root certificates
+    pub spki_pins: Vec<SpkiPin>,        // If any, a certificate of the verified chain must match one of them, roots only if given above
+    pub pins_only: bool                 // Trust a pinned server certificate without verifying the chain and name
+}
+
+/// Verifies certificates like rustls does by default, and additionally checks the SPKI pins.
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Arc<rustls::client::WebPkiServerVerifier>,
+    roots: Arc<rustls::RootCertStore>,
+    spki_pins: Vec<SpkiPin>,
+    pinned_anchors: Vec<Vec<u8>>,       // The SubjectPublicKeyInfo contents of the pinned trust anchors
+    pins_only: bool
+}
+
+impl PinningVerifier {
+    /// Returns whether a certificate of the chain from `end_entity` to a trust anchor matches a
+    /// pin. Only the certificates which webpki used to build the chain count, as the server can
+    /// send any certificate as an intermediate.
+    fn verified_chain_pinned(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime
+    ) -> std::result::Result<bool, rustls::Error> {
+        let certificate = webpki::EndEntityCert::try_from(end_entity)
+        .map_err(|_| rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding))?;
+        let algorithms = crypto_provider().signature_verification_algorithms.all;
+        let path = certificate.verify_for_usage(
+            algorithms, &self.roots.roots, intermediates, now, webpki::KeyUsage::server_auth(), None, None
+        ).map_err(|_| rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer))?;
+        let anchor = path.anchor().subject_public_key_info.as_ref();
+        let pinned = std::iter::once(SpkiPin::of_parsed_certificate(&certificate))
+        .chain(path.intermediate_certificates().map(SpkiPin::of_parsed_certificate))
+        .flatten()
+        .any(|pin| self.spki_pins.contains(&pin));
+        Ok(pinned || self.pinned_anchors.iter().any(|pinned_anchor| pinned_anchor.as_slice() == anchor))
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let pinned = match self.pins_only && !self.spki_pins.is_empty() {
+            // The server only proves possession of the key of its own certificate, by signing the handshake.
+            true => SpkiPin::of_certificate(end_entity).is_some_and(|pin| self.spki_pins.contains(&pin)),
+            false => {
+                self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+                self.spki_pins.is_empty() || self.verified_chain_pinned(end_entity, intermediates, now)?
+            }
+        };
+        match pinned {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &rustls::DigitallySignedStruct
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, certificate, signature)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &rustls::DigitallySignedStruct
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, certificate, signature)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Builds a rustls client configuration verifying servers according to `config`. Also used by
+/// other TLS based transports, e.g. DNS over HTTPS.
+pub(crate) fn client_config(config: &TlsClientConfig, alpn_protocols: Vec<Vec<u8>>) -> Result<Arc<rustls::ClientConfig>> {
+    let mut root_store = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    for certificate in config.root_certificates.iter() {
+        root_store.add(certificate.clone())?;
+    }
+    let roots = Arc::new(root_store);
+    let inner = rustls::client::WebPkiServerVerifier::builder_with_provider(Arc::clone(&roots), crypto_provider())
+    .build()
+    .map_err(|error| TlsError::InvalidCertificate { description: error.to_string() })?;
+    // Trust anchors only keep the contents of their SubjectPublicKeyInfo, which cannot be pinned, so
+    // the pinned ones are found through their certificates instead.
+    let pinned_anchors = config.root_certificates.iter()
+    .filter(|certificate| SpkiPin::of_certificate(certificate).is_some_and(|pin| config.spki_pins.contains(&pin)))
+    .filter_map(|certificate| webpki::anchor_from_trusted_cert(certificate).ok())
+    .map(|anchor| anchor.subject_public_key_info.to_vec())
+    .collect();
+    let verifier = PinningVerifier {
+        inner,
+        roots,
+        spki_pins: config.spki_pins.clone(),
+        pinned_anchors,
+        pins_only: config.pins_only
+    };
+    let mut client_config = rustls::ClientConfig::builder_with_provider(crypto_provider())
+    .with_safe_default_protocol_versions()?
+    .dangerous()
+    .with_custom_certificate_verifier(Arc::new(verifier))
+    .with_no_client_auth();
+    client_config.alpn_protocols = alpn_protocols;
+    Ok(Arc::new(client_config))
+}
+
+/// Builds a rustls server configuration presenting the given certificate chain.
+pub fn server_config(certificates: Vec<CertificateDer<'static>>, private_key: PrivateKeyDer<'static>) -> Result<Arc<rustls::ServerConfig>> {
+    let mut server_config = rustls::ServerConfig::builder_with_provider(crypto_provider())
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certificates, private_key)?;
+    server_config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+/// Reads every certificate of a PEM file.
+pub fn read_certificates<P: AsRef<Path>>(path: P) -> Result<Vec<CertificateDer<'static>>> {
+    let pem_error = |error: rustls::pki_types::pem::Error| TlsError::Pem {
+        path: path.as_ref().display().to_string(),
+        description: error.to_string()
+    };
+    CertificateDer::pem_file_iter(path.as_ref()).map_err(pem_error)?
+    .collect::<std::result::Result<Vec<_>, _>>()
+    .map_err(pem_error)
+}
+
+/// Reads the first private key of a PEM file.
+pub fn read_private_key<P: AsRef<Path>>(path: P) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path.as_ref()).map_err(|error| TlsError::Pem {
+        path: path.as_ref().display().to_string(),
+        description: error.to_string()
+    })
+}
+
+/// DNS over TLS (RFC 7858), using a new connection for every query.
+#[derive(Clone, Debug)]
+pub struct TlsTransport {
+    config: Arc<rustls::ClientConfig>,
+    server_name: Option<ServerName<'static>>
+}
+
+impl TlsTransport {
+    pub fn new(config: TlsClientConfig) -> Result<Self> {
+        let server_name = match &config.server_name {
+            Some(server_name) => Some(ServerName::try_from(server_name.clone()).map_err(|error| {
+                TlsError::InvalidCertificate { description: error.to_string() }
+            })?),
+            None => None
+        };
+        Ok(Self { config: client_config(&config, vec![ALPN_PROTOCOL.to_vec()])?, server_name })
+    }
+}
+
+impl Transport for TlsTransport {
+    fn exchange(&self, query: &DnsMessage, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<DnsMessage> {
+        let server_name = self.server_name.clone().unwrap_or(ServerName::IpAddress(server.ip().into()));
+        let connection = rustls::ClientConnection::new(Arc::clone(&self.config), server_name)
+        .map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to start a TLS session"),
+            source: std::io::Error::other(error)
+        })?;
+        let mut stream = rustls::StreamOwned::new(connection, transport::connect(server, timeout)?);
+        transport::exchange_over_stream(query, &mut stream)
+    }
+}
+
+/// A DNS over TLS server which answers every query it receives using a QueryHandler. Each
+/// connection is served on a separate thread, and may carry any number of queries.
+pub struct TlsServer<H> {
+    listener: net::TcpListener,
+    config: Arc<rustls::ServerConfig>,
+    handler: Arc<H>,
+    dnstap: Option<Arc<DnstapSink>>     // Receives CLIENTQUERY and CLIENTRESPONSE messages, see set_dnstap
+}
+
+impl<H: QueryHandler + 'static> TlsServer<H> {
+    pub fn bind<A: net::ToSocketAddrs>(address: A, config: Arc<rustls::ServerConfig>, handler: H) -> udp_packet::Result<Self> {
+        let listener = net::TcpListener::bind(address).map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to bind the server socket"),
+            source: error
+        })?;
+        Ok(Self { listener, config, handler: Arc::new(handler), dnstap: None })
+    }
+
+    /// Logs every query received and response sent to `dnstap`.
+    pub fn set_dnstap(&mut self, dnstap: Arc<DnstapSink>) {
+        self.dnstap = Some(dnstap);
+    }
+
+    pub fn local_addr(&self) -> udp_packet::Result<net::SocketAddr> {
+        self.listener.local_addr().map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to read the address of the server socket"),
+            source: error
+        })
+    }
+
+    /// Serves connections until accepting one fails.
+    pub fn run(&self) -> udp_packet::Result<()> {
+        loop {
+            let (stream, client) = self.listener.accept().map_err(|error| UdpPacketError::NetworkIo {
+                description: String::from("failed to accept a connection"),
+                source: error
+            })?;
+            let config = Arc::clone(&self.config);
+            let handler = Arc::clone(&self.handler);
+            let dnstap = self.dnstap.clone().map(|sink| ClientTap::new(sink, SocketProtocol::DOT, client, stream.local_addr().ok()));
+            thread::spawn(move || {
+                // A failure to serve a single client should not stop the server.
+                let _ = stream.set_read_timeout(Some(IDLE_TIMEOUT));
+                let _ = stream.set_write_timeout(Some(IDLE_TIMEOUT));
+                if let Ok(connection) = rustls::ServerConnection::new(config) {
+                    let mut stream = rustls::StreamOwned::new(connection, stream);
+                    server::serve_stream(handler.as_ref(), &mut stream, dnstap.as_ref());
+                    stream.conn.send_close_notify();
+                    let _ = std::io::Write::flush(&mut stream);
+                }
+            });
+        }
+    }
+
+    /// Runs the server on a background thread, mostly useful for tests and embedding.
+    pub fn spawn(self) -> thread::JoinHandle<udp_packet::Result<()>> {
+        thread::spawn(move || self.run())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_message::*;
+    use crate::dnstap::{self, MessageType};
+    use crate::tls::*;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    const SERVER_NAME: &str = "dns.example.test";
+
+    struct Pki {
+        ca: CertificateDer<'static>,
+        certificate: CertificateDer<'static>
+    }
+
+    /// Issues a certificate for SERVER_NAME by a new CA, returning the CA, the certificate and its key.
+    fn issue() -> (rcgen::Certificate, rcgen::Certificate, rcgen::KeyPair) {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = rcgen::KeyPair::generate().unwrap();
+        let certificate = rcgen::CertificateParams::new(vec![String::from(SERVER_NAME)]).unwrap()
+        .signed_by(&key, &ca, &ca_key).unwrap();
+        (ca, certificate, key)
+    }
+
+    /// Starts a DNS over TLS server presenting the given certificate chain.
+    fn serve(certificates: Vec<CertificateDer<'static>>, key: &rcgen::KeyPair) -> net::SocketAddr {
+        serve_with_dnstap(certificates, key, None)
+    }
+
+    fn serve_with_dnstap(certificates: Vec<CertificateDer<'static>>, key: &rcgen::KeyPair, dnstap: Option<Arc<DnstapSink>>) -> net::SocketAddr {
+        let private_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let config = server_config(certificates, private_key).unwrap();
+        let mut server = TlsServer::bind((net::Ipv4Addr::LOCALHOST, 0), config, |query: &DnsMessage| {
+            query.reply(ResponseCode::NOERROR)
+        }).unwrap();
+        if let Some(sink) = dnstap {
+            server.set_dnstap(sink);
+        }
+        let address = server.local_addr().unwrap();
+        server.spawn();
+        address
+    }
+
+    /// Starts a DNS over TLS server whose certificate for SERVER_NAME is issued by a new CA.
+    fn start_server() -> (net::SocketAddr, Pki) {
+        let (ca, certificate, key) = issue();
+        let address = serve(vec![certificate.der().clone()], &key);
+        (address, Pki { ca: ca.der().clone(), certificate: certificate.der().clone() })
+    }
+
+    fn exchange(address: net::SocketAddr, config: TlsClientConfig) -> udp_packet::Result<DnsMessage> {
+        let mut query = DnsMessage::default();
+        query.header.id = 853;
+        TlsTransport::new(config).unwrap().exchange(&query, address, Duration::from_secs(5))
+    }
+
+    #[test]
+    fn verification_test() {
+        let (address, pki) = start_server();
+        let response = exchange(address, TlsClientConfig {
+            server_name: Some(String::from(SERVER_NAME)),
+            root_certificates: vec![pki.ca.clone()],
+            ..Default::default()
+        }).expect("Failed to exchange.");
+        assert_eq!(response.header.id, 853);
+        assert_eq!(response.header.response_code, ResponseCode::NOERROR);
+
+        let untrusted = exchange(address, TlsClientConfig {
+            server_name: Some(String::from(SERVER_NAME)),
+            ..Default::default()
+        });
+        assert!(untrusted.is_err());
+        let wrong_name = exchange(address, TlsClientConfig {
+            server_name: Some(String::from("other.example.test")),
+            root_certificates: vec![pki.ca],
+            ..Default::default()
+        });
+        assert!(wrong_name.is_err());
+    }
+
+    #[test]
+    fn dnstap_test() {
+        let (ca, certificate, key) = issue();
+        let (sink, messages) = dnstap::memory_sink();
+        let address = serve_with_dnstap(vec![certificate.der().clone()], &key, Some(sink));
+        let response = exchange(address, TlsClientConfig {
+            server_name: Some(String::from(SERVER_NAME)),
+            root_certificates: vec![ca.der().clone()],
+            ..Default::default()
+        }).expect("Failed to exchange.");
+        let messages = messages(2);
+        let types: Vec<MessageType> = messages.iter().map(|message| message.message_type).collect();
+        assert_eq!(types, vec![MessageType::CLIENTQUERY, MessageType::CLIENTRESPONSE]);
+        assert!(messages.iter().all(|message| message.socket_protocol == Some(SocketProtocol::DOT)));
+        assert_eq!(response.header.response_code, ResponseCode::NOERROR);
+        assert_eq!(messages[1].query_message, messages[0].query_message);
+        assert!(messages[1].response_message.is_some());
+    }
+
+    #[test]
+    fn pinning_test() {
+        let (address, pki) = start_server();
+        let pin = SpkiPin::of_certificate(&pki.certificate).expect("Failed to compute the pin.");
+        assert_eq!(SpkiPin::from_str(&pin.to_string()).unwrap(), pin);
+        let response = exchange(address, TlsClientConfig {
+            server_name: Some(String::from(SERVER_NAME)),
+            spki_pins: vec![pin],
+            pins_only: true,
+            ..Default::default()
+        });
+        assert!(response.is_ok());
+
+        let wrong_pin = exchange(address, TlsClientConfig {
+            server_name: Some(String::from(SERVER_NAME)),
+            root_certificates: vec![pki.ca],
+            spki_pins: vec![SpkiPin([0; SHA256_LENGTH_BYTES])],
+            ..Default::default()
+        });
+        assert!(wrong_pin.is_err());
+    }
+
+    #[test]
+    fn forged_chain_pinning_test() {
+        let (_, pki) = start_server();
+        let pin = SpkiPin::of_certificate(&pki.certificate).unwrap();
+        // An attacker presenting their own certificate, followed by the pinned one as an intermediate.
+        let (forged_ca, forged, forged_key) = issue();
+        let address = serve(vec![forged.der().clone(), pki.certificate.clone()], &forged_key);
+        let pins_only = exchange(address, TlsClientConfig {
+            server_name: Some(String::from(SERVER_NAME)),
+            spki_pins: vec![pin],
+            pins_only: true,
+            ..Default::default()
+        });
+        assert!(pins_only.is_err());
+
+        // Even if the attacker's CA is trusted, the pinned certificate is not part of the chain.
+        let trusted = exchange(address, TlsClientConfig {
+            server_name: Some(String::from(SERVER_NAME)),
+            root_certificates: vec![forged_ca.der().clone(), pki.ca.clone()],
+            spki_pins: vec![pin],
+            ..Default::default()
+        });
+        assert!(trusted.is_err());
+        let forged_ca_pinned = exchange(address, TlsClientConfig {
+            server_name: Some(String::from(SERVER_NAME)),
+            root_certificates: vec![forged_ca.der().clone()],
+            spki_pins: vec![SpkiPin::of_certificate(forged_ca.der()).unwrap()],
+            ..Default::default()
+        });
+        assert!(forged_ca_pinned.is_ok());
+    }
+}
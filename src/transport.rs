@@ -0,0 +1,314 @@
+use crate::dns_message::{DnsMessage, DnsQuestion, ResponseCode};
+use crate::random;
+use crate::udp_packet::{self, TcpPacket, UdpPacketError};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A way of sending a query to a name server and receiving its response. Implementors are
+/// responsible for choosing the id of the query on the wire, but the returned response must carry
+/// the id of `query`. A timeout is reported as a UdpPacketError for which is_timeout() is true.
+pub trait Transport: Send + Sync {
+    fn exchange(&self, query: &DnsMessage, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<DnsMessage>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn exchange(&self, query: &DnsMessage, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<DnsMessage> {
+        self.as_ref().exchange(query, server, timeout)
+    }
+}
+
+/// The transport used by default, i.e. DNS over UDP (see DnsMessage::exchange), optionally
+/// retrying truncated responses over TCP.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UdpTransport {
+    pub case_randomisation: bool,   // Randomise the case of query names and require it to be echoed ("DNS 0x20")
+    pub tcp_fallback: bool          // Repeat the query over TCP if the response is truncated
+}
+
+impl Default for UdpTransport {
+    fn default() -> Self {
+        Self { case_randomisation: false, tcp_fallback: true }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn exchange(&self, query: &DnsMessage, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<DnsMessage> {
+        let start = Instant::now();
+        let response = match self.case_randomisation {
+            true => query.exchange_with_case_randomisation(server, timeout)?,
+            false => query.exchange(server, timeout)?
+        };
+        match response.header.truncated && self.tcp_fallback {
+            true => {
+                log::debug!("truncated response from {}, retrying over TCP", server);
+                TcpTransport.exchange(query, server, timeout.saturating_sub(start.elapsed()))
+            },
+            false => Ok(response)
+        }
+    }
+}
+
+/// DNS over TCP, using a new connection for every query (RFC 7766).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn exchange(&self, query: &DnsMessage, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<DnsMessage> {
+        exchange_over_stream(query, &mut connect(server, timeout)?)
+    }
+}
+
+/// Connects to `server` and limits every read and write on the stream to the part of `timeout`
+/// which remains after connecting.
+pub(crate) fn connect(server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<net::TcpStream> {
+    let io_error = |description: &str| {
+        let description = String::from(description);
+        move |error| UdpPacketError::NetworkIo { description, source: error }
+    };
+    let timed_out = || UdpPacketError::NetworkIo {
+        description: String::from("timed out while waiting for a response"),
+        source: std::io::Error::from(std::io::ErrorKind::TimedOut)
+    };
+    if timeout.is_zero() {
+        return Err(timed_out());
+    }
+    let deadline = Instant::now() + timeout;
+    let stream = net::TcpStream::connect_timeout(&server, timeout).map_err(io_error("failed to connect"))?;
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(timed_out());
+    }
+    stream.set_write_timeout(Some(remaining)).map_err(io_error("failed to set the write timeout"))?;
+    stream.set_read_timeout(Some(remaining)).map_err(io_error("failed to set the read timeout"))?;
+    Ok(stream)
+}
+
+/// Sends `query` with a random id over a connected stream, using the framing of DNS over TCP, and
+/// reads its response. Shared by all stream based transports.
+pub(crate) fn exchange_over_stream<S: Read + Write>(query: &DnsMessage, stream: &mut S) -> udp_packet::Result<DnsMessage> {
+    let mut sent = query.clone();
+    sent.header.id = random::random_u16();
+    let mut tcp_packet = Box::new(TcpPacket::empty());
+    sent.write_to_udp_packet(&mut tcp_packet)?;
+    tcp_packet.send_framed(stream)?;
+
+    let mut response_packet = Box::new(TcpPacket::empty());
+    response_packet.recv_framed(stream)?;
+    let mut response = DnsMessage::read_from_udp_packet(&mut response_packet)?;
+    // A connection is only used for one query, so anything else is a broken server.
+    if !response.is_response_to(&sent) {
+        return Err(UdpPacketError::NetworkIo {
+            description: String::from("received a response which does not match the query"),
+            source: std::io::Error::from(std::io::ErrorKind::InvalidData)
+        });
+    }
+    response.header.id = query.header.id;
+    Ok(response)
+}
+
+/// What a MockTransport does when it receives a query.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockAction {
+    /// Responds with the given message, whose id, questions and QR flag are taken from the query.
+    Respond(DnsMessage),
+
+    /// Responds with an empty message with the given response code.
+    Fail(ResponseCode),
+
+    /// Responds with the header and questions of the given message only, with the TC flag set.
+    Truncate(DnsMessage),
+
+    /// Never responds, i.e. the exchange times out.
+    Drop,
+
+    /// Carries out the inner action after a delay, or times out if the delay is not shorter than
+    /// the timeout of the exchange.
+    Delay(Duration, Box<MockAction>)
+}
+
+/// An in-memory Transport for tests, which answers every question according to a script and
+/// records the queries it receives. Timeouts are reported immediately instead of being waited for.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    script: Mutex<Vec<(DnsQuestion, VecDeque<MockAction>)>>,
+    queries: Mutex<Vec<(net::SocketAddr, DnsMessage)>>
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds actions for queries whose first question is `question`. The actions are carried out in
+    /// order, one per query, and the last one is repeated once the others have been used up.
+    /// Questions without a script are answered with REFUSED.
+    pub fn script(&self, question: DnsQuestion, actions: Vec<MockAction>) {
+        let mut script = self.script.lock().unwrap();
+        match script.iter_mut().find(|(scripted, _)| *scripted == question) {
+            Some((_, scripted_actions)) => scripted_actions.extend(actions),
+            None => script.push((question, actions.into()))
+        }
+    }
+
+    /// Returns the queries received so far, along with the servers they were sent to.
+    pub fn queries(&self) -> Vec<(net::SocketAddr, DnsMessage)> {
+        self.queries.lock().unwrap().clone()
+    }
+
+    fn next_action(&self, query: &DnsMessage) -> MockAction {
+        let mut script = self.script.lock().unwrap();
+        let actions = query.questions.first()
+        .and_then(|question| script.iter_mut().find(|(scripted, _)| scripted == question))
+        .map(|(_, actions)| actions);
+        match actions {
+            Some(actions) if actions.len() > 1 => actions.pop_front().unwrap(),
+            Some(actions) if !actions.is_empty() => actions[0].clone(),
+            _ => MockAction::Fail(ResponseCode::REFUSED)
+        }
+    }
+}
+
+impl Transport for MockTransport {
+    fn exchange(&self, query: &DnsMessage, server: net::SocketAddr, timeout: Duration) -> udp_packet::Result<DnsMessage> {
+        self.queries.lock().unwrap().push((server, query.clone()));
+        let mut action = self.next_action(query);
+        let mut elapsed = Duration::ZERO;
+        loop {
+            let response = match action {
+                MockAction::Respond(mut response) => {
+                    response.header.id = query.header.id;
+                    response.header.response = true;
+                    response.questions = query.questions.clone();
+                    response.update_counts();
+                    response
+                },
+                MockAction::Fail(response_code) => query.reply(response_code),
+                MockAction::Truncate(response) => {
+                    let mut truncated = query.reply(response.header.response_code);
+                    truncated.header.truncated = true;
+                    truncated
+                },
+                MockAction::Drop => {
+                    return Err(UdpPacketError::NetworkIo {
+                        description: String::from("timed out while waiting for a response"),
+                        source: std::io::Error::from(std::io::ErrorKind::TimedOut)
+                    });
+                },
+                MockAction::Delay(delay, inner) => {
+                    elapsed += delay;
+                    action = match elapsed < timeout {
+                        true => {
+                            std::thread::sleep(delay);
+                            *inner
+                        },
+                        false => MockAction::Drop
+                    };
+                    continue;
+                }
+            };
+            return Ok(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_message::*;
+    use crate::transport::*;
+    use crate::udp_packet::{DomainName, UdpPacket};
+    use std::str::FromStr;
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion { name: DomainName::from_str(name).unwrap(), ..Default::default() }
+    }
+
+    fn query(name: &str) -> DnsMessage {
+        DnsMessage { questions: vec![question(name)], ..Default::default() }
+    }
+
+    /// Returns a response to `query` with enough A records to not fit in a UDP packet.
+    fn large_response(query: &DnsMessage) -> DnsMessage {
+        let mut response = query.reply(ResponseCode::NOERROR);
+        for host in 0..64 {
+            response.answers.push(DnsRecord {
+                name: query.questions[0].name.clone(),
+                record_type: RecordType::A,
+                record_class: RecordClass::IN,
+                ttl: 300,
+                length: 4,
+                data: RecordData::A { ipv4_address: net::Ipv4Addr::new(192, 0, 2, host) }
+            });
+        }
+        response.update_counts();
+        response
+    }
+
+    /// Starts a UDP server which truncates every response and a TCP server which does not, both on
+    /// the same port.
+    fn start_truncating_server() -> net::SocketAddr {
+        let listener = net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let udp_socket = net::UdpSocket::bind(address).unwrap();
+        std::thread::spawn(move || {
+            let mut udp_packet = UdpPacket::new();
+            let (_, client) = udp_packet.recv_from(&udp_socket).unwrap();
+            let mut response = DnsMessage::read_from_udp_packet(&mut udp_packet).unwrap().reply(ResponseCode::NOERROR);
+            response.header.truncated = true;
+            let mut udp_packet = UdpPacket::new();
+            response.write_to_udp_packet(&mut udp_packet).unwrap();
+            udp_packet.send_to(&udp_socket, client).unwrap();
+        });
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut tcp_packet = Box::new(TcpPacket::empty());
+            tcp_packet.recv_framed(&mut stream).unwrap();
+            let response = large_response(&DnsMessage::read_from_udp_packet(&mut tcp_packet).unwrap());
+            let mut tcp_packet = Box::new(TcpPacket::empty());
+            response.write_to_udp_packet(&mut tcp_packet).unwrap();
+            tcp_packet.send_framed(&mut stream).unwrap();
+        });
+        address
+    }
+
+    #[test]
+    fn tcp_fallback_test() {
+        let server = start_truncating_server();
+        let mut query = query(TEST_DOMAIN);
+        query.header.id = 42;
+        let response = UdpTransport::default().exchange(&query, server, Duration::from_secs(5))
+        .expect("Failed to exchange.");
+        assert_eq!(response.header.id, 42);
+        assert!(!response.header.truncated);
+        assert_eq!(response.answers.len(), 64);
+    }
+
+    #[test]
+    fn mock_script_test() {
+        let server = net::SocketAddr::from(([192, 0, 2, 53], 53));
+        let timeout = Duration::from_millis(100);
+        let mock = MockTransport::new();
+        let answer = large_response(&query("www.example.com"));
+        mock.script(question("www.example.com"), vec![
+            MockAction::Drop,
+            MockAction::Delay(Duration::from_secs(1), Box::new(MockAction::Respond(answer.clone()))),
+            MockAction::Truncate(answer.clone()),
+            MockAction::Delay(Duration::from_millis(10), Box::new(MockAction::Respond(answer.clone())))
+        ]);
+
+        let query = query("WWW.example.com");
+        assert!(mock.exchange(&query, server, timeout).unwrap_err().is_timeout());
+        assert!(mock.exchange(&query, server, timeout).unwrap_err().is_timeout());
+        assert!(mock.exchange(&query, server, timeout).unwrap().header.truncated);
+        for _ in 0..2 {
+            let response = mock.exchange(&query, server, timeout).unwrap();
+            assert!(response.is_response_to(&query));
+            assert_eq!(response.answers, answer.answers);
+        }
+        let unscripted = mock.exchange(&self::query("example.net"), server, timeout).unwrap();
+        assert_eq!(unscripted.header.response_code, ResponseCode::REFUSED);
+        assert_eq!(mock.queries().len(), 6);
+    }
+}
@@ -1,9 +1,11 @@
+use crate::random;
 use std::fmt::Display;
 use std::str::FromStr;
 use std::net;
 use std::result;
 
 pub const UDP_PACKET_MAX_SIZE_BYTES: usize = 512;
+pub const TCP_MESSAGE_MAX_SIZE_BYTES: usize = 65535;   // The largest length which fits in the two byte length prefix.
 const NAME_MAX_LENGTH_BYTES: usize = 255;
 const LABEL_MAX_LENGTH_BYTES: usize = 63;
 const MAX_JUMPS: usize = 10;
@@ -62,6 +64,12 @@ pub enum UdpPacketError {
     FromUtf8 {
         bytes: Vec<u8>,                     // The erroneous bytes.
         source: std::string::FromUtf8Error  // The underlying error.
+    },
+
+    /// A field of a packet contained a value which is unknown or unsupported, e.g. an unimplemented record type.
+    InvalidValue {
+        field: String,          // The name of the field.
+        value: u16              // The erroneous value.
     }
 }
 
@@ -85,14 +93,31 @@ impl std::fmt::Display for UdpPacketError {
             UdpPacketError::FromUtf8 { 
                 bytes, 
                 source, 
-            } => write!(f, "Failed to convert byte sequence {:?} to a utf-8 string, source: {}", bytes, source)
+            } => write!(f, "Failed to convert byte sequence {:?} to a utf-8 string, source: {}", bytes, source),
+            UdpPacketError::InvalidValue { 
+                field, 
+                value, 
+            } => write!(f, "encountered invalid or unsupported value {} in field {}", value, field)
         }
     }
 }
 
 impl std::error::Error for UdpPacketError {}
 
-#[derive(Debug, Default, PartialEq)]
+impl UdpPacketError {
+    /// Returns true if the error was caused by a network operation timing out.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Self::NetworkIo { source, .. } => matches!(
+                source.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ),
+            _ => false
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct CharacterString {
     pub length: usize,
     pub bytes: Vec<u8>
@@ -121,11 +146,30 @@ impl FromStr for CharacterString {
     }
 }
 
-#[derive(Debug, Default, PartialEq)]
+#[cfg(feature = "serde")]
+crate::serde_as_str!(CharacterString);
+
+/// A domain name in wire format. The original bytes are kept as-is, but names are compared and
+/// hashed case-insensitively, as required by RFC 4343.
+#[derive(Clone, Debug, Default)]
 pub struct DomainName {
     pub bytes: Vec<u8>
 }
 
+impl PartialEq for DomainName {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes.eq_ignore_ascii_case(&other.bytes)
+    }
+}
+
+impl Eq for DomainName {}
+
+impl std::hash::Hash for DomainName {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.bytes.to_ascii_lowercase().hash(state)
+    }
+}
+
 impl Display for DomainName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut labels: Vec<&[u8]> = Vec::new();
@@ -136,6 +180,9 @@ impl Display for DomainName {
             labels.push(b".");
             position += length + 1;
         }
+        if labels.is_empty() {
+            return write!(f, ".");
+        }
         let string = String::from_utf8(labels.concat())
         .map_err(|error| UdpPacketError::FromUtf8 { 
             bytes: self.bytes.to_vec(), 
@@ -154,6 +201,9 @@ impl FromStr for DomainName {
         if s.ends_with(".") {
             s.pop();
         }
+        if s.is_empty() {
+            return Ok(Self::root());
+        }
         if s.len() + 2 > NAME_MAX_LENGTH_BYTES {
             return Err(UdpPacketError::MalformedDomainName {
                 domain_name: s.to_string(),
@@ -177,24 +227,148 @@ impl FromStr for DomainName {
     }
 }
 
+#[cfg(feature = "serde")]
+crate::serde_as_str!(DomainName);
+
+impl DomainName {
+    /// The root domain name, i.e. the name consisting only of the zero byte.
+    pub fn root() -> Self {
+        Self { bytes: vec![0] }
+    }
+
+    /// Returns the labels of the name, excluding the empty root label.
+    pub fn labels(&self) -> Vec<&[u8]> {
+        let mut labels: Vec<&[u8]> = Vec::new();
+        let mut position = 0;
+        while position < self.bytes.len() && self.bytes[position] != 0x00 {
+            let length = self.bytes[position] as usize;
+            labels.push(&self.bytes[(position + 1)..(position + 1 + length)]);
+            position += length + 1;
+        }
+        labels
+    }
+
+    /// Constructs a name from labels, which are assumed to be at most 63 bytes long.
+    pub fn from_labels(labels: &[&[u8]]) -> Self {
+        let mut bytes = Vec::new();
+        for label in labels {
+            bytes.push(label.len() as u8);
+            bytes.extend_from_slice(label);
+        }
+        bytes.push(0);
+        Self { bytes }
+    }
+
+    /// The name used to look up the PTR record of an address, i.e. the octets of an IPv4 address
+    /// below in-addr.arpa, or the nibbles of an IPv6 address below ip6.arpa, in reverse order.
+    pub fn reverse(address: net::IpAddr) -> Self {
+        let labels: Vec<String> = match address {
+            net::IpAddr::V4(address) => address.octets().iter().rev()
+            .map(u8::to_string)
+            .chain(["in-addr", "arpa"].map(String::from))
+            .collect(),
+            net::IpAddr::V6(address) => address.octets().iter().rev()
+            .flat_map(|octet| [octet & 0x0f, octet >> 4])
+            .map(|nibble| format!("{:x}", nibble))
+            .chain(["ip6", "arpa"].map(String::from))
+            .collect()
+        };
+        Self::from_labels(&labels.iter().map(String::as_bytes).collect::<Vec<_>>())
+    }
+
+    /// Returns true if the name is equal to, or below, `ancestor`. Labels are compared case-insensitively.
+    pub fn is_subdomain_of(&self, ancestor: &DomainName) -> bool {
+        let labels = self.labels();
+        let ancestor_labels = ancestor.labels();
+        labels.len() >= ancestor_labels.len()
+        && labels[(labels.len() - ancestor_labels.len())..].iter()
+        .zip(ancestor_labels.iter())
+        .all(|(label, ancestor_label)| label.eq_ignore_ascii_case(ancestor_label))
+    }
+
+    /// Compares two names byte by byte, i.e. including the case of their letters.
+    pub fn eq_case_sensitive(&self, other: &DomainName) -> bool {
+        self.bytes == other.bytes
+    }
+
+    /// Returns a copy of the name where the case of every letter has been chosen at random, which is
+    /// used to make spoofed responses harder to forge ("DNS 0x20", draft-vixie-dnsext-dns0x20).
+    pub fn randomise_case(&self) -> DomainName {
+        let mut random_bytes = vec![0; self.bytes.len()];
+        random::fill(&mut random_bytes);
+        let mut name = self.clone();
+        let mut position = 0;
+        while position < name.bytes.len() && name.bytes[position] != 0x00 {
+            let length = name.bytes[position] as usize;
+            let label = (position + 1)..(position + 1 + length);
+            for (byte, random_byte) in name.bytes[label.clone()].iter_mut().zip(random_bytes[label].iter()) {
+                if byte.is_ascii_alphabetic() {
+                    *byte = match random_byte & 1 {
+                        0 => byte.to_ascii_lowercase(),
+                        _ => byte.to_ascii_uppercase()
+                    };
+                }
+            }
+            position += length + 1;
+        }
+        name
+    }
+
+    /// Replaces `suffix` at the end of the name with `replacement`, which is how a CNAME is
+    /// synthesised from a DNAME record (RFC 6672). Returns None if the name is not strictly below
+    /// `suffix`, or if the resulting name would be too long.
+    pub fn replace_suffix(&self, suffix: &DomainName, replacement: &DomainName) -> Option<DomainName> {
+        let labels = self.labels();
+        let num_suffix_labels = suffix.labels().len();
+        if labels.len() <= num_suffix_labels || !self.is_subdomain_of(suffix) {
+            return None;
+        }
+        let mut new_labels = labels[..(labels.len() - num_suffix_labels)].to_vec();
+        new_labels.extend(replacement.labels());
+        let name = Self::from_labels(&new_labels);
+        match name.bytes.len() > NAME_MAX_LENGTH_BYTES {
+            true => None,
+            false => Some(name)
+        }
+    }
+}
+
+/// A buffer holding a single DNS message. The size defaults to that of a UDP packet, but larger
+/// buffers are used for transports which allow larger messages, see TcpPacket.
 #[derive(Debug, PartialEq)]
-pub struct UdpPacket {
-    pub buffer: [u8; UDP_PACKET_MAX_SIZE_BYTES],
+pub struct UdpPacket<const N: usize = UDP_PACKET_MAX_SIZE_BYTES> {
+    pub buffer: [u8; N],
     pub position: usize
 }
 
+/// A buffer large enough for any DNS message sent over a stream, e.g. TCP or TLS.
+pub type TcpPacket = UdpPacket<TCP_MESSAGE_MAX_SIZE_BYTES>;
+
+impl Default for UdpPacket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UdpPacket {
     pub fn new() -> Self {
+        Self::empty()
+    }
+}
+
+impl<const N: usize> UdpPacket<N> {
+    /// Creates an empty packet of any size, unlike UdpPacket::new, which is restricted to UDP.
+    pub fn empty() -> Self {
         UdpPacket {
-            buffer: [0; UDP_PACKET_MAX_SIZE_BYTES],
+            buffer: [0; N],
             position: 0
         }
     }
 
     pub fn read_u16(&mut self) -> Result<u16> {
-        if self.position + 1 >= UDP_PACKET_MAX_SIZE_BYTES {
+        if self.position + 1 >= N {
             return Err(UdpPacketError::OutOfBounds { 
-                length: UDP_PACKET_MAX_SIZE_BYTES, 
+                length: N, 
                 index: self.position + 1 
             })
         }
@@ -204,9 +378,9 @@ impl UdpPacket {
     }
 
     pub fn read_u32(&mut self) -> Result<u32> {
-        if self.position + 3 >= UDP_PACKET_MAX_SIZE_BYTES {
+        if self.position + 3 >= N {
             return Err(UdpPacketError::OutOfBounds { 
-                length: UDP_PACKET_MAX_SIZE_BYTES, 
+                length: N, 
                 index: self.position + 3 
             })
         }
@@ -215,9 +389,9 @@ impl UdpPacket {
     }
 
     pub fn read_u64(&mut self) -> Result<u64> {
-        if self.position + 7 >= UDP_PACKET_MAX_SIZE_BYTES {
+        if self.position + 7 >= N {
             return Err(UdpPacketError::OutOfBounds { 
-                length: UDP_PACKET_MAX_SIZE_BYTES, 
+                length: N, 
                 index: self.position + 7 
             })
         }
@@ -226,9 +400,9 @@ impl UdpPacket {
     }
 
     pub fn read_u128(&mut self) -> Result<u128> {
-        if self.position + 15 >= UDP_PACKET_MAX_SIZE_BYTES {
+        if self.position + 15 >= N {
             return Err(UdpPacketError::OutOfBounds { 
-                length: UDP_PACKET_MAX_SIZE_BYTES, 
+                length: N, 
                 index: self.position + 15 
             })
         }
@@ -237,8 +411,11 @@ impl UdpPacket {
     }
 
     pub fn send(&self, udp_socket: &net::UdpSocket) -> Result<usize> {
-        match udp_socket.send(&self.buffer) {
-            Ok(num_bytes_read) => Ok(num_bytes_read),
+        match udp_socket.send(&self.buffer[..self.position]) {
+            Ok(num_bytes_read) => {
+                log::trace!("sent {} bytes over UDP:\n{}", num_bytes_read, hex_dump(&self.buffer[..num_bytes_read]));
+                Ok(num_bytes_read)
+            },
             Err(error) => Err(UdpPacketError::NetworkIo { 
                 description: String::from("failed to send a packet"), 
                 source: error
@@ -246,9 +423,12 @@ impl UdpPacket {
         }
     }
 
-    pub fn send_to<A: net::ToSocketAddrs>(&self, udp_socket: &net::UdpSocket, addr: A) -> Result<usize> {
-        match udp_socket.send_to(&self.buffer, addr) {
-            Ok(num_bytes_read) => Ok(num_bytes_read),
+    pub fn send_to<A: net::ToSocketAddrs + std::fmt::Debug>(&self, udp_socket: &net::UdpSocket, addr: A) -> Result<usize> {
+        match udp_socket.send_to(&self.buffer[..self.position], &addr) {
+            Ok(num_bytes_read) => {
+                log::trace!("sent {} bytes over UDP to {:?}:\n{}", num_bytes_read, addr, hex_dump(&self.buffer[..num_bytes_read]));
+                Ok(num_bytes_read)
+            },
             Err(error) => Err(UdpPacketError::NetworkIo { 
                 description: String::from("failed to send a packet"), 
                 source: error
@@ -258,7 +438,10 @@ impl UdpPacket {
 
     pub fn recv(&mut self, udp_socket: &net::UdpSocket) -> Result<usize> {
         match udp_socket.recv(&mut self.buffer) {
-            Ok(num_bytes_read) => Ok(num_bytes_read),
+            Ok(num_bytes_read) => {
+                log::trace!("received {} bytes over UDP:\n{}", num_bytes_read, hex_dump(&self.buffer[..num_bytes_read]));
+                Ok(num_bytes_read)
+            },
             Err(error) => Err(UdpPacketError::NetworkIo { 
                 description: String::from("failed to receive a packet"), 
                 source: error
@@ -268,7 +451,10 @@ impl UdpPacket {
 
     pub fn recv_from(&mut self, udp_socket: &net::UdpSocket) -> Result<(usize, net::SocketAddr)> {
         match udp_socket.recv_from(&mut self.buffer) {
-            Ok(num_bytes_addr) => Ok(num_bytes_addr),
+            Ok((num_bytes_read, addr)) => {
+                log::trace!("received {} bytes over UDP from {}:\n{}", num_bytes_read, addr, hex_dump(&self.buffer[..num_bytes_read]));
+                Ok((num_bytes_read, addr))
+            },
             Err(error) => Err(UdpPacketError::NetworkIo { 
                 description: String::from("failed to receive a packet"), 
                 source: error
@@ -276,14 +462,42 @@ impl UdpPacket {
         }
     }
 
-    pub fn write_from_slice(&mut self, slice: &[u8], margin: Option<usize>) -> Result<()> {
-        let margin = match margin {
-            Some(value) => value,
-            None => 0
+    /// Writes the packet to a stream, preceded by its length as required for DNS over TCP
+    /// (RFC 1035, section 4.2.2).
+    pub fn send_framed<W: std::io::Write>(&self, stream: &mut W) -> Result<()> {
+        let length = (self.position as u16).to_be_bytes();
+        stream.write_all(&[&length, &self.buffer[..self.position]].concat())
+        .and_then(|_| stream.flush())
+        .map_err(|error| UdpPacketError::NetworkIo {
+            description: String::from("failed to send a message"),
+            source: error
+        })?;
+        log::trace!("sent a message of {} bytes over a stream:\n{}", self.position, hex_dump(&self.buffer[..self.position]));
+        Ok(())
+    }
+
+    /// Reads a length-prefixed message from a stream into the packet, see UdpPacket::send_framed.
+    pub fn recv_framed<R: std::io::Read>(&mut self, stream: &mut R) -> Result<usize> {
+        let io_error = |error| UdpPacketError::NetworkIo {
+            description: String::from("failed to receive a message"),
+            source: error
         };
-        if self.position + slice.len() + margin >= UDP_PACKET_MAX_SIZE_BYTES {
+        let mut length = [0; 2];
+        stream.read_exact(&mut length).map_err(io_error)?;
+        let length = u16::from_be_bytes(length) as usize;
+        if length > N {
+            return Err(UdpPacketError::OutOfBounds { length: N, index: length });
+        }
+        stream.read_exact(&mut self.buffer[..length]).map_err(io_error)?;
+        log::trace!("received a message of {} bytes over a stream:\n{}", length, hex_dump(&self.buffer[..length]));
+        Ok(length)
+    }
+
+    pub fn write_from_slice(&mut self, slice: &[u8], margin: Option<usize>) -> Result<()> {
+        let margin = margin.unwrap_or_default();
+        if self.position + slice.len() + margin >= N {
             return Err(UdpPacketError::OutOfBounds { 
-                length: UDP_PACKET_MAX_SIZE_BYTES, 
+                length: N, 
                 index: self.position + slice.len()
             })
         }
@@ -295,15 +509,22 @@ impl UdpPacket {
     }
 
     pub fn read_to_slice(&self, start: usize, length: usize) -> Result<&[u8]> {
-        if start + length >= UDP_PACKET_MAX_SIZE_BYTES {
+        if start + length >= N {
             return Err(UdpPacketError::OutOfBounds { 
-                length: UDP_PACKET_MAX_SIZE_BYTES, 
+                length: N, 
                 index: start + length
             })
         }
         Ok(&self.buffer[start..(start + length)])
     }
 
+    /// Reads `length` bytes, e.g. RDATA which is not interpreted.
+    pub fn read_bytes(&mut self, length: usize) -> Result<Vec<u8>> {
+        let bytes = self.read_to_slice(self.position, length)?.to_vec();
+        self.position += length;
+        Ok(bytes)
+    }
+
     pub fn write_domain_name(&mut self, domain_name: &DomainName, margin: Option<usize>) -> Result<()> {
         self.write_from_slice(&domain_name.bytes, margin)?;
         Ok(())
@@ -315,7 +536,17 @@ impl UdpPacket {
         let mut has_jumped = false;
         let mut position = self.position;
         let mut num_bytes_read_before_jump = 0;
-        while self.buffer[position] != 0x00 {
+        loop {
+            // Also covers the second byte of a pointer, and thereby any malicious pointer offset.
+            if position + 1 >= N {
+                return Err(UdpPacketError::OutOfBounds { 
+                    length: N, 
+                    index: position + 1 
+                })
+            }
+            if self.buffer[position] == 0x00 {
+                break;
+            }
             if num_jumps > MAX_JUMPS {
                 return Err(UdpPacketError::MaxJumpsExceeded)
             } else if self.buffer[position] & 0xc0 == 0xc0 {
@@ -328,7 +559,7 @@ impl UdpPacket {
                 if length > LABEL_MAX_LENGTH_BYTES {
                     return Err(UdpPacketError::MalformedDomainName { 
                         domain_name: String::from("a domain name"), 
-                        description: format!("the length of a label exceeds 63 bytes"), 
+                        description: String::from("the length of a label exceeds 63 bytes"), 
                         source: Malformation::LabelTooLong
                     })
                 }
@@ -350,7 +581,7 @@ impl UdpPacket {
                         source: error 
                     })
                 },
-                description: format!("domain name length exceeds 255 bytes"), 
+                description: String::from("domain name length exceeds 255 bytes"), 
                 source: Malformation::NameTooLong
             })
         }
@@ -362,7 +593,7 @@ impl UdpPacket {
     }
 
     pub fn read_character_string(&mut self) -> Result<CharacterString> {
-        let length = self.buffer[self.position] as usize;
+        let length = self.read_to_slice(self.position, 1)?[0] as usize;
         let bytes = self.read_to_slice(self.position, length + 1)?.to_vec();
         Ok(CharacterString {
             length,
@@ -371,6 +602,22 @@ impl UdpPacket {
     }
 }
 
+/// Formats bytes as a hex dump with 16 bytes per line, each line preceded by the offset of its
+/// first byte and followed by the bytes as ASCII, with . for unprintable ones. Used for logging
+/// packets at trace level.
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut lines = Vec::new();
+    for (index, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = chunk.iter().map(|&byte| match byte.is_ascii_graphic() || byte == b' ' {
+            true => byte as char,
+            false => '.'
+        }).collect();
+        lines.push(format!("{:04x}  {:<47}  {}", index * 16, hex.join(" "), ascii));
+    }
+    lines.join("\n")
+}
+
 #[cfg(test)]
 mod tests {
     use crate::dns_message;
@@ -415,7 +662,7 @@ mod tests {
         let mut udp_packet = UdpPacket::new();
         udp_packet.write_from_slice(&slice, None).expect("Failed to write to packet.");
         assert_eq!(udp_packet, UdpPacket {
-            buffer: buffer,
+            buffer,
             position: 12
         });
         let buffer = [
@@ -454,7 +701,7 @@ mod tests {
         ];
         udp_packet.write_from_slice(&slice, None).expect("Failed to write to packet.");
         assert_eq!(udp_packet, UdpPacket {
-            buffer: buffer,
+            buffer,
             position: 24
         });
     }
@@ -501,4 +748,34 @@ mod tests {
             position: 13
         })
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn domain_name_case_test() {
+        use std::collections::HashSet;
+        let name = DomainName::from_str("www.Example.com").unwrap();
+        let randomised = name.randomise_case();
+        assert_eq!(name, DomainName::from_str("WWW.EXAMPLE.COM").unwrap());
+        assert_eq!(name, randomised);
+        assert!(!name.eq_case_sensitive(&DomainName::from_str("www.example.com").unwrap()));
+        assert_eq!(randomised.bytes.to_ascii_lowercase(), name.bytes.to_ascii_lowercase());
+        let names: HashSet<DomainName> = [name, randomised].into_iter().collect();
+        assert_eq!(names.len(), 1);
+    }
+
+    #[test]
+    fn reverse_test() {
+        let name = DomainName::reverse(net::IpAddr::from([192, 0, 2, 1]));
+        assert_eq!(name.to_string(), "1.2.0.192.in-addr.arpa.");
+        let name = DomainName::reverse(net::IpAddr::from_str("2001:db8::1").unwrap());
+        assert_eq!(name.to_string(), "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.");
+    }
+
+    #[test]
+    fn hex_dump_test() {
+        let bytes: Vec<u8> = (0x30..0x42).chain([0x00, 0xff]).collect();
+        assert_eq!(hex_dump(&bytes), "\
+0000  30 31 32 33 34 35 36 37 38 39 3a 3b 3c 3d 3e 3f  0123456789:;<=>?
+0010  40 41 00 ff                                      @A..");
+        assert_eq!(hex_dump(&[]), "");
+    }
+}